@@ -2,7 +2,12 @@
 
 use crate::archive_human_output_file::*;
 use OutputFormat;
-use image::DynamicImage;
+use image::{DynamicImage, RgbImage};
+use std::fs::File;
+use std::io::BufReader;
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::ColorType;
+use crate::color_multiplexer::cmyk_to_rgb;
 
 pub struct ArchiveHumanInputFile<'a> {
     in_file: &'a str,
@@ -28,6 +33,26 @@ impl<'a> ArchiveHumanInputFile<'a> {
     }
 
     pub fn read_page(&self) -> Option<DynamicImage> {
-        return Some(image::open(self.in_file).unwrap());
+        match image::open(self.in_file) {
+            Ok(img) => Some(img),
+            // The image crate can't read CMYK TIFFs like the ones we write for printing, so we handle those ourselves.
+            Err(e) => Some(read_cmyk_tiff(self.in_file).unwrap_or_else(|| panic!("{}", e)))
+        }
+    }
+}
+
+// Reads a CMYK TIFF, showing it the way it would look printed.  Returns None if it isn't one.
+fn read_cmyk_tiff(filename: &str) -> Option<DynamicImage> {
+    let mut decoder = Decoder::new(BufReader::new(File::open(filename).ok()?)).ok()?;
+    if decoder.colortype().ok()? != ColorType::CMYK(8) {
+        return None;
+    }
+    let (width, height) = decoder.dimensions().ok()?;
+    match decoder.read_image().ok()? {
+        DecodingResult::U8(data) => {
+            let rgb = data.chunks_exact(4).flat_map(|inks| cmyk_to_rgb([inks[0], inks[1], inks[2], inks[3]]).0).collect();
+            Some(DynamicImage::ImageRgb8(RgbImage::from_raw(width, height, rgb)?))
+        },
+        _ => None
     }
 }
\ No newline at end of file
@@ -5,11 +5,19 @@ use image::imageops;
 use hsl::HSL;
 use imageproc::rect::Rect;
 use imageproc::drawing::*;
-use rusttype::{Scale, Font};
+use rusttype::{point, Scale, Font};
+use std::fs::File;
+use std::io::BufWriter;
+use tiff::encoder::{TiffEncoder, colortype, compression::Lzw};
+use crate::color_multiplexer::rgb_to_cmyk;
+use crate::palette_swatch::SwatchLabel;
 
 #[derive(Copy, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum OutputFormat {
-    PNG
+    PNG,
+    // CMYK, ready for printing with process inks.
+    TIFF
 }
 
 #[derive(Copy, Clone)]
@@ -31,7 +39,9 @@ pub struct ArchiveHumanOutputFile<'a> {
     text_height: f32,
     dpi: u16,
     margins: OutputMargins,
-    colors: Vec<Rgb<u8>>
+    colors: Vec<Rgb<u8>>,
+    // Ink coverage for each palette color, if it should be printed with specific inks rather than converted.
+    inks: Option<Vec<[u8; 4]>>
 }
 
 impl<'a> ArchiveHumanOutputFile<'a> {
@@ -63,7 +73,8 @@ impl<'a> ArchiveHumanOutputFile<'a> {
                 bottom: 0.5,
                 left: 0.25
             },
-            colors: colors_hsl.iter().map(|h| { let c = h.to_rgb(); Rgb([c.0, c.1, c.2]) }).collect()
+            colors: colors_hsl.iter().map(|h| { let c = h.to_rgb(); Rgb([c.0, c.1, c.2]) }).collect(),
+            inks: None
         }
     }
 
@@ -83,6 +94,11 @@ impl<'a> ArchiveHumanOutputFile<'a> {
         self
     }
 
+    pub fn inks(mut self, inks: Option<Vec<[u8; 4]>>) -> Self {
+        self.inks = inks;
+        self
+    }
+
     pub fn document_header(mut self, header: &'a str) -> Self {
         self.document_header = header;
         self
@@ -118,7 +134,8 @@ impl<'a> ArchiveHumanOutputFile<'a> {
             text_height: self.text_height,
             dpi: self.dpi,
             margins: self.margins,
-            colors: self.colors
+            colors: self.colors,
+            inks: self.inks
         }
     }
 
@@ -141,6 +158,61 @@ impl<'a> ArchiveHumanOutputFile<'a> {
 
     pub fn write_page(&self, code_image: &RgbImage, page_num: u16) {
         // Format the barcode image into the bounds on the page where it should be, and add metadata.
+        let mut out_image = self.build_page(page_num);
+
+        // Copy the barcode to within the margins.
+        let dpi_float = self.dpi as f32;
+        imageops::overlay(&mut out_image, code_image, (self.margins.left * dpi_float) as i64, ((self.margins.top + self.text_height) * dpi_float) as i64);
+
+        self.save_page(&out_image, page_num);
+    }
+
+    // Writes a page of plain text in the area where barcodes would normally go, shrinking the text until it all fits.
+    // Used for the bootstrap page describing the format.
+    pub fn write_text_page(&self, paragraphs: &[String], page_num: u16) {
+        let mut out_image = self.build_page(page_num);
+        let dpi_float = self.dpi as f32;
+        let font_data: &[u8] = include_bytes!("Seshat-Regular.ttf");
+        let font = Font::try_from_bytes(font_data).unwrap();
+        let (area_width, area_height) = self.get_barcode_image_size();
+        let left = (self.margins.left * dpi_float) as i32;
+        let top = ((self.margins.top + self.text_height) * dpi_float) as i32;
+
+        // Start at a bit under the header size and work our way down.
+        let mut line_height = self.text_height * dpi_float * 0.5;
+        let mut lines: Vec<String>;
+        loop {
+            lines = vec![];
+            let scale = Scale::uniform(line_height);
+            for p in paragraphs {
+                let mut line = String::new();
+                for word in p.split(' ') {
+                    let candidate = if line.is_empty() { word.to_string() } else { format!("{} {}", line, word) };
+                    if text_width(&font, scale, &candidate) > area_width as f32 && !line.is_empty() {
+                        lines.push(line);
+                        line = word.to_string();
+                    }
+                    else {
+                        line = candidate;
+                    }
+                }
+                lines.push(line);
+            }
+            if (lines.len() as f32 * line_height) <= area_height as f32 || line_height <= 4.0 {
+                break;
+            }
+            line_height *= 0.9;
+        }
+
+        for (i, l) in lines.iter().enumerate() {
+            draw_text_mut(&mut out_image, Rgb([0, 0, 0]), left, top + (i as f32 * line_height) as i32, Scale::uniform(line_height), &font, l);
+        }
+
+        self.save_page(&out_image, page_num);
+    }
+
+    // Builds a blank page with the header, footer, and palette on it.
+    pub fn build_page(&self, page_num: u16) -> RgbImage {
         // Build a blank full page.
         let dpi_float = self.dpi as f32;
         let page_width_pixels = (self.width * dpi_float).round() as u32;
@@ -148,9 +220,6 @@ impl<'a> ArchiveHumanOutputFile<'a> {
         let mut out_image = RgbImage::new(page_width_pixels, page_height_pixels);
         draw_filled_rect_mut(&mut out_image, Rect::at(0, 0).of_size(page_width_pixels, page_height_pixels), Rgb([255, 255, 255]));
 
-        // Copy the barcode to within the margins.
-        imageops::overlay(&mut out_image, code_image, (self.margins.left * dpi_float) as i64, ((self.margins.top + self.text_height) * dpi_float) as i64);
-
         let num_colors = self.colors.len();
         //let num_bits_colors = (num_colors as f64).log(2.0) as u8;
 
@@ -174,47 +243,84 @@ impl<'a> ArchiveHumanOutputFile<'a> {
         draw_text_mut(&mut out_image, Rgb([0, 0, 0]), (self.margins.left * dpi_float) as i32, footer_top as i32, Scale::uniform(self.text_height * dpi_float), &font, &footer_substituted);
 
         // Add the color palette, but only if we're actually using colors.
+        // It goes on the right of both the header and the footer, and in the middle of them where the text leaves room, so decoders can correct for lighting which changes across the page.
         if self.colors.len() > 2 {
             let max_palette_width = (page_width_pixels - ((self.margins.left * dpi_float) as u32) - ((self.margins.right * dpi_float) as u32)) / 2;
             let colors_except_bw = self.colors.len() as u32 - 2;
+            let palette_top = footer_top;
+            let line_height = (self.text_height * dpi_float) as u32;
+
+            // Use as few rows of patches as fit, so they're as big as they can be.
+            // Each swatch has a label saying which colors it holds, so decoders can find it wherever it ends up on a scan.
             let mut rows = 0;
-            let mut colors_per_row;
-            let mut palette_top;
-            let mut palette_height;
-            let mut swatch_size;
-            let mut palette_width;
-            let palette_left;
-            let palette_border;
-            loop {
-                rows = rows + 1;
-                colors_per_row = (colors_except_bw + rows - 1) / rows; // colors_except_bw / rows, rounding up
-                palette_top = footer_top;
-                palette_height = (self.text_height * dpi_float) as u32;
-                swatch_size = palette_height / rows;
-                palette_width = colors_per_row * swatch_size;
+            let (label, palette_width, palette_height) = loop {
+                rows += 1;
+                let label = SwatchLabel { first_index: 1, count: colors_except_bw as u8, columns: colors_except_bw.div_ceil(rows) };
+                let (width, height) = label.size_at_height(line_height);
                 //println!("Rows: {}", rows);
-                //println!("Colors per row: {}", colors_per_row);
-                //println!("Page width: {}", page_width_pixels);
-                //println!("Palette width: {}", palette_width);
+                //println!("Palette width: {}", width);
                 // Exit the loop if we've packed it correctly.
-                if palette_width <= max_palette_width {
-                    // Only calculate these if we're reasonably sure we won't overflow.
-                    palette_left = page_width_pixels - ((self.margins.right * dpi_float) as u32) - palette_width;
-                    palette_border = swatch_size / 4;
-                    break;
+                if width <= max_palette_width || rows >= colors_except_bw {
+                    break (label, width, height);
                 }
+            };
+            let palette_left = page_width_pixels - ((self.margins.right * dpi_float) as u32) - palette_width;
+            let header_top = (self.margins.top * dpi_float) as u32;
+            let mut palette_positions = vec![(palette_left, palette_top), (palette_left, header_top)];
+            let center_left = (page_width_pixels - palette_width) / 2;
+            let text_scale = Scale::uniform(self.text_height * dpi_float);
+            let text_left = self.margins.left * dpi_float;
+            let gap = palette_height as f32;
+            if text_left + text_width(&font, text_scale, &header_substituted) + gap < center_left as f32 && center_left + palette_width + (gap as u32) < palette_left {
+                palette_positions.push((center_left, header_top));
+            }
+            if text_left + text_width(&font, text_scale, &footer_substituted) + gap < center_left as f32 && center_left + palette_width + (gap as u32) < palette_left {
+                palette_positions.push((center_left, palette_top));
             }
-            draw_filled_rect_mut(&mut out_image, Rect::at(palette_left as i32, palette_top as i32).of_size(palette_width, palette_height), Rgb([0, 0, 0]));
-            for c in 0..(self.colors.len() - 2) {
-                let x = (palette_left + ((c as u32 % colors_per_row) * swatch_size) + palette_border) as i32;
-                let y = (palette_top + (c as u32 / colors_per_row) * swatch_size + palette_border) as i32;
-                draw_filled_rect_mut(&mut out_image, Rect::at(x, y).of_size(swatch_size - palette_border * 2, swatch_size - palette_border * 2), self.colors[c + 1]);
+            for (left, top) in palette_positions {
+                label.draw(&mut out_image, left, top, line_height, &self.colors);
             }
         }
 
-        // Save it out.
-        let numbered_filename = format!("{}{}.png", self.out_file, page_num);
-        println!("Writing to {}", numbered_filename);
-        out_image.save(numbered_filename).unwrap();
+        out_image
     }
+
+    // Where the given page gets written.
+    pub fn page_filename(&self, page_num: u16) -> String {
+        match self.format {
+            OutputFormat::PNG => format!("{}{}.png", self.out_file, page_num),
+            OutputFormat::TIFF => format!("{}{}.tif", self.out_file, page_num)
+        }
+    }
+
+    fn save_page(&self, out_image: &RgbImage, page_num: u16) {
+        let numbered_filename = self.page_filename(page_num);
+        match self.format {
+            OutputFormat::PNG => {
+                println!("Writing to {}", numbered_filename);
+                out_image.save(numbered_filename).unwrap();
+            },
+            OutputFormat::TIFF => {
+                println!("Writing to {}", numbered_filename);
+                let mut cmyk: Vec<u8> = Vec::with_capacity(out_image.len() / 3 * 4);
+                for pixel in out_image.pixels() {
+                    // Palette colors go on exactly the inks they stand for, so the decoder can unmix them again.
+                    let inks = match &self.inks {
+                        Some(inks) => self.colors.iter().position(|c| c == pixel).map(|i| inks[i]).unwrap_or_else(|| rgb_to_cmyk(pixel)),
+                        None => rgb_to_cmyk(pixel)
+                    };
+                    cmyk.extend_from_slice(&inks);
+                }
+                let mut encoder = TiffEncoder::new(BufWriter::new(File::create(numbered_filename).unwrap())).unwrap();
+                encoder.write_image_with_compression::<colortype::CMYK8, _>(out_image.width(), out_image.height(), Lzw, &cmyk).unwrap();
+            }
+        }
+    }
+}
+
+fn text_width(font: &Font, scale: Scale, text: &str) -> f32 {
+    font.layout(text, scale, point(0.0, 0.0))
+        .last()
+        .map(|g| g.position().x + g.unpositioned().h_metrics().advance_width)
+        .unwrap_or(0.0)
 }
\ No newline at end of file
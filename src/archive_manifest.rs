@@ -0,0 +1,317 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0+ OR Zlib
+
+use crate::color_multiplexer::MultiplexMode;
+use crate::color_profile::parse_hex_color;
+use crate::payload_encoding::PayloadEncoding;
+use image::Rgb;
+use qrencode::types::EcLevel;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+
+// Where one barcode sits on every page of the archive, and how it was encoded.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ManifestBarcode {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub version: i16,
+    pub ec_level: EcLevel,
+    pub is_metadata: bool
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ManifestPage {
+    pub number: u16,
+    // Just the name, so the archive can be moved.
+    pub file_name: String,
+    pub sha256: String
+}
+
+// Everything about an archive as it was encoded, written alongside its pages so it can be checked later without scanning anything.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ArchiveManifest {
+    // As printed in the page header.
+    pub title: String,
+    pub input_length: u64,
+    // All 32 bits of the file hash.  Barcodes only have room for the low 24.
+    pub file_hash: u32,
+    pub format_version: u8,
+    pub payload_encoding: PayloadEncoding,
+    // Bytes of the file on each page.
+    pub page_bytes: u32,
+    pub data_pages: u16,
+    pub parity_pages: u8,
+    // In inches.
+    pub page_width: f32,
+    pub page_height: f32,
+    pub dpi: u16,
+    pub multiplex_mode: MultiplexMode,
+    pub palette: Vec<Rgb<u8>>,
+    pub layout_id: u8,
+    pub barcodes: Vec<ManifestBarcode>,
+    pub pages: Vec<ManifestPage>
+}
+
+fn hex_color(c: &Rgb<u8>) -> String {
+    format!("{:02x}{:02x}{:02x}", c[0], c[1], c[2])
+}
+
+fn ec_level_name(l: EcLevel) -> &'static str {
+    match l {
+        EcLevel::L => "L",
+        EcLevel::M => "M",
+        EcLevel::Q => "Q",
+        EcLevel::H => "H"
+    }
+}
+
+// Titles can hold anything the command line can, so line breaks are escaped to keep each field on its own line.
+fn escape_title(title: &str) -> String {
+    title.replace('\\', "\\\\").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+fn unescape_title(escaped: &str) -> Result<String, &'static str> {
+    let mut title = String::new();
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            title.push(c);
+            continue;
+        }
+        title.push(match chars.next() {
+            Some('\\') => '\\',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            _ => return Err("Unknown escape in manifest title")
+        });
+    }
+    Ok(title)
+}
+
+// Works for any kind of number, so every field can share it.
+fn bad_number<E>(_e: E) -> &'static str {
+    "Manifest has a value which isn't a number"
+}
+
+// Where the manifest for pages written to the given output goes, next to the pages themselves.
+pub fn manifest_filename(out_file: &str) -> String {
+    format!("{}manifest.txt", out_file)
+}
+
+// SHA-256 of a page file, as hex.
+pub fn hash_page_file(filename: &str) -> Option<String> {
+    let contents = fs::read(filename).ok()?;
+    Some(Sha256::digest(contents).iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+impl ArchiveManifest {
+    // Records the hash of each page file which was written, including page 0 if there is one.
+    pub fn add_pages(&mut self, page_filename: impl Fn(u16) -> String) {
+        self.pages.clear();
+        for number in 0..=(self.data_pages + self.parity_pages as u16) {
+            let filename = page_filename(number);
+            if let Some(sha256) = hash_page_file(&filename) {
+                let file_name = Path::new(&filename).file_name().unwrap().to_string_lossy().to_string();
+                self.pages.push(ManifestPage {
+                    number,
+                    file_name,
+                    sha256
+                });
+            }
+        }
+    }
+
+    pub fn page(&self, number: u16) -> Option<&ManifestPage> {
+        self.pages.iter().find(|p| p.number == number)
+    }
+
+    // Names of everything besides the pages themselves which differs between two manifests.
+    pub fn differences(&self, other: &ArchiveManifest) -> Vec<&'static str> {
+        let mut different = vec![];
+        let mut check = |same: bool, name: &'static str| if !same { different.push(name) };
+        check(self.title == other.title, "title");
+        check(self.input_length == other.input_length, "input length");
+        check(self.file_hash == other.file_hash, "file hash");
+        check(self.format_version == other.format_version, "format version");
+        check(self.payload_encoding == other.payload_encoding, "encoding");
+        check(self.page_bytes == other.page_bytes, "bytes per page");
+        check(self.data_pages == other.data_pages && self.parity_pages == other.parity_pages, "page counts");
+        check(self.page_width == other.page_width && self.page_height == other.page_height && self.dpi == other.dpi, "page size");
+        check(self.multiplex_mode == other.multiplex_mode && self.palette == other.palette, "palette");
+        check(self.layout_id == other.layout_id && self.barcodes == other.barcodes, "barcode layout");
+        different
+    }
+
+    pub fn save(&self, filename: &str) -> std::io::Result<()> {
+        let mut out = String::from("# Real World Archive manifest\n");
+        out += &format!("title {}\n", escape_title(&self.title));
+        out += &format!("input_length {}\n", self.input_length);
+        out += &format!("file_hash {:08x}\n", self.file_hash);
+        out += &format!("format_version {}\n", self.format_version);
+        out += &format!("encoding {}\n", match self.payload_encoding {
+            PayloadEncoding::Base45 => "base45",
+            PayloadEncoding::Byte => "byte"
+        });
+        out += &format!("page_bytes {}\n", self.page_bytes);
+        out += &format!("data_pages {}\n", self.data_pages);
+        out += &format!("parity_pages {}\n", self.parity_pages);
+        out += &format!("page_size {} {}\n", self.page_width, self.page_height);
+        out += &format!("dpi {}\n", self.dpi);
+        out += &format!("multiplex {}\n", match self.multiplex_mode {
+            MultiplexMode::Palette => "palette",
+            MultiplexMode::Cmyk => "cmyk",
+            MultiplexMode::Grayscale => "grayscale"
+        });
+        out += &format!("palette {}\n", self.palette.iter().map(hex_color).collect::<Vec<String>>().join(" "));
+        out += &format!("layout {}\n", self.layout_id);
+        out += "# barcode <x> <y> <width> <height> <QR version> <EC level> <data or metadata>, in pixels from the top left of the barcode area\n";
+        for b in &self.barcodes {
+            out += &format!("barcode {} {} {} {} {} {} {}\n", b.x, b.y, b.width, b.height, b.version, ec_level_name(b.ec_level), if b.is_metadata { "metadata" } else { "data" });
+        }
+        out += "# page <number> <SHA-256> <file name>\n";
+        for p in &self.pages {
+            out += &format!("page {} {} {}\n", p.number, p.sha256, p.file_name);
+        }
+        fs::write(filename, out)
+    }
+
+    pub fn load(filename: &str) -> Result<ArchiveManifest, &'static str> {
+        let text = fs::read_to_string(filename).map_err(|_e| "Could not read manifest")?;
+        let mut manifest = ArchiveManifest {
+            title: String::new(),
+            input_length: 0,
+            file_hash: 0,
+            format_version: 0,
+            payload_encoding: PayloadEncoding::Base45,
+            page_bytes: 0,
+            data_pages: 0,
+            parity_pages: 0,
+            page_width: 0.0,
+            page_height: 0.0,
+            dpi: 0,
+            multiplex_mode: MultiplexMode::Palette,
+            palette: vec![],
+            layout_id: 0,
+            barcodes: vec![],
+            pages: vec![]
+        };
+        for line in text.lines() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            let fields: Vec<&str> = value.split_whitespace().collect();
+            match key {
+                // Titles can have spaces of their own.
+                "title" => manifest.title = unescape_title(value)?,
+                "input_length" => manifest.input_length = value.parse().map_err(bad_number)?,
+                "file_hash" => manifest.file_hash = u32::from_str_radix(value, 16).map_err(|_e| "Manifest file hash must be 8 hex digits")?,
+                "format_version" => manifest.format_version = value.parse().map_err(bad_number)?,
+                "encoding" => manifest.payload_encoding = match value {
+                    "base45" => PayloadEncoding::Base45,
+                    "byte" => PayloadEncoding::Byte,
+                    _ => return Err("Unknown encoding in manifest")
+                },
+                "page_bytes" => manifest.page_bytes = value.parse().map_err(bad_number)?,
+                "data_pages" => manifest.data_pages = value.parse().map_err(bad_number)?,
+                "parity_pages" => manifest.parity_pages = value.parse().map_err(bad_number)?,
+                "page_size" => {
+                    if fields.len() != 2 {
+                        return Err("Manifest page size needs a width and height");
+                    }
+                    manifest.page_width = fields[0].parse().map_err(bad_number)?;
+                    manifest.page_height = fields[1].parse().map_err(bad_number)?;
+                },
+                "dpi" => manifest.dpi = value.parse().map_err(bad_number)?,
+                "multiplex" => manifest.multiplex_mode = match value {
+                    "palette" => MultiplexMode::Palette,
+                    "cmyk" => MultiplexMode::Cmyk,
+                    "grayscale" => MultiplexMode::Grayscale,
+                    _ => return Err("Unknown multiplexing mode in manifest")
+                },
+                "palette" => manifest.palette = fields.iter().map(|c| parse_hex_color(c)).collect::<Result<Vec<Rgb<u8>>, &'static str>>()?,
+                "layout" => manifest.layout_id = value.parse().map_err(bad_number)?,
+                "barcode" => {
+                    if fields.len() != 7 {
+                        return Err("Manifest barcode lines need 7 fields");
+                    }
+                    let number = |i: usize| fields[i].parse::<u32>().map_err(bad_number);
+                    manifest.barcodes.push(ManifestBarcode {
+                        x: number(0)?,
+                        y: number(1)?,
+                        width: number(2)?,
+                        height: number(3)?,
+                        version: fields[4].parse().map_err(bad_number)?,
+                        ec_level: match fields[5] {
+                            "L" => EcLevel::L,
+                            "M" => EcLevel::M,
+                            "Q" => EcLevel::Q,
+                            "H" => EcLevel::H,
+                            _ => return Err("Unknown error correction level in manifest")
+                        },
+                        is_metadata: fields[6] == "metadata"
+                    });
+                },
+                "page" => {
+                    // The file name comes last, since the output prefix it's made from can have spaces in it.
+                    let fields: Vec<&str> = value.splitn(3, ' ').collect();
+                    if fields.len() != 3 {
+                        return Err("Manifest page lines need 3 fields");
+                    }
+                    manifest.pages.push(ManifestPage {
+                        number: fields[0].parse().map_err(bad_number)?,
+                        sha256: fields[1].to_string(),
+                        file_name: fields[2].to_string()
+                    });
+                },
+                _ => return Err("Unknown line in manifest")
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_load() {
+        let manifest = ArchiveManifest {
+            title: "Tax records 2024\ndpi 600 \\n\r".to_string(),
+            input_length: 123456,
+            file_hash: 0x0badf00d,
+            format_version: 2,
+            payload_encoding: PayloadEncoding::Byte,
+            page_bytes: 4096,
+            data_pages: 31,
+            parity_pages: 2,
+            page_width: 8.5,
+            page_height: 11.0,
+            dpi: 300,
+            multiplex_mode: MultiplexMode::Palette,
+            palette: vec![Rgb([0, 0, 0]), Rgb([0x1f, 0x4e, 0x9a]), Rgb([0xb0, 0x3a, 0x2e]), Rgb([255, 255, 255])],
+            layout_id: 0x81,
+            barcodes: vec![
+                ManifestBarcode { x: 0, y: 0, width: 77, height: 77, version: 15, ec_level: EcLevel::H, is_metadata: true },
+                ManifestBarcode { x: 85, y: 0, width: 77, height: 77, version: 15, ec_level: EcLevel::Q, is_metadata: false }
+            ],
+            pages: vec![
+                ManifestPage { number: 1, file_name: "tax records  1.png".to_string(), sha256: "ab".repeat(32) }
+            ]
+        };
+        let filename = std::env::temp_dir().join(format!("realworldarchive-manifest-test-{}.txt", std::process::id()));
+        let filename = filename.to_str().unwrap();
+        manifest.save(filename).unwrap();
+        let loaded = ArchiveManifest::load(filename);
+        fs::remove_file(filename).unwrap();
+        assert_eq!(loaded, Ok(manifest.clone()));
+
+        let mut other = manifest.clone();
+        other.barcodes[1].ec_level = EcLevel::M;
+        other.pages.clear();
+        assert_eq!(manifest.differences(&other), vec!["barcode layout"]);
+    }
+}
@@ -0,0 +1,181 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0+ OR Zlib
+
+use crate::archive_human_output_file::*;
+use crate::archive_human_input_file::*;
+use crate::color_multiplexer::{ColorMultiplexer, rgb_to_oklab};
+use image::{DynamicImage, GenericImageView, RgbImage, Rgb};
+use imageproc::rect::Rect;
+use imageproc::drawing::draw_filled_rect_mut;
+
+// Patches across the sheet.  As many rows are added as fit, with square cells.
+const CALIBRATION_COLUMNS: u32 = 16;
+
+// Each row starts this many colors further along the palette than the one above it, so every color ends up spread all over the page rather than in a few columns.
+const CALIBRATION_ROW_SHIFT: u32 = 3;
+
+// Pixels darker than this in every channel count as part of the frame around the patches.
+const FRAME_DARKNESS: u8 = 96;
+
+// Prints a page covered in patches of every palette color inside a black frame, and reads a scan of it back to measure what the printer and scanner do to each color.
+pub struct CalibrationSheet {
+}
+
+impl CalibrationSheet {
+    pub fn new() -> CalibrationSheet {
+        CalibrationSheet {
+        }
+    }
+
+    pub fn finalize(self) -> CalibrationSheet {
+        CalibrationSheet {
+        }
+    }
+
+    fn color_index(column: u32, row: u32, num_colors: u32) -> usize {
+        ((column + row * CALIBRATION_ROW_SHIFT) % num_colors) as usize
+    }
+
+    pub fn encode(&self, writer: &ArchiveHumanOutputFile, multiplexer: &ColorMultiplexer) {
+        let (w, h) = writer.get_barcode_image_size();
+        let mut out_image = RgbImage::new(w, h);
+        draw_filled_rect_mut(&mut out_image, Rect::at(0, 0).of_size(w, h), Rgb([255, 255, 255]));
+
+        // The frame is a quarter of a cell thick, and patches fill the middle three quarters of their cells so they stay solid even if the scan is a little out of line.
+        let cell = w * 4 / (CALIBRATION_COLUMNS * 4 + 2);
+        let frame = cell / 4;
+        let rows = (h - frame * 2) / cell;
+        let colors = multiplexer.get_rgb();
+        println!("Generating calibration sheet with {} patches of {} colors", CALIBRATION_COLUMNS * rows, colors.len());
+        draw_filled_rect_mut(&mut out_image, Rect::at(0, 0).of_size(cell * CALIBRATION_COLUMNS + frame * 2, cell * rows + frame * 2), Rgb([0, 0, 0]));
+        draw_filled_rect_mut(&mut out_image, Rect::at(frame as i32, frame as i32).of_size(cell * CALIBRATION_COLUMNS, cell * rows), Rgb([255, 255, 255]));
+        for row in 0..rows {
+            for column in 0..CALIBRATION_COLUMNS {
+                let x = frame + column * cell + cell / 8;
+                let y = frame + row * cell + cell / 8;
+                let color = colors[CalibrationSheet::color_index(column, row, colors.len() as u32)];
+                draw_filled_rect_mut(&mut out_image, Rect::at(x as i32, y as i32).of_size(cell - cell / 4, cell - cell / 4), color);
+            }
+        }
+
+        writer.write_page(&out_image, 0);
+    }
+
+    // Finds the frame on a scanned sheet, returning its left, top, right, and bottom edges and how thick it is.
+    // The frame is the only thing on the page with unbroken dark lines across more than half its width or height.
+    fn find_frame(img: &DynamicImage) -> Option<(u32, u32, u32, u32, u32)> {
+        let (w, h) = (img.width(), img.height());
+        let dark = |x: u32, y: u32| img.get_pixel(x, y).0[0..3].iter().all(|c| *c < FRAME_DARKNESS);
+        let longest_run = |pixels: &mut dyn Iterator<Item = bool>| {
+            let (mut longest, mut run) = (0, 0);
+            for is_dark in pixels {
+                run = if is_dark { run + 1 } else { 0 };
+                longest = longest.max(run);
+            }
+            longest
+        };
+        let dark_rows: Vec<u32> = (0..h).filter(|y| longest_run(&mut (0..w).map(|x| dark(x, *y))) * 2 > w).collect();
+        let (top, bottom) = (*dark_rows.first()?, *dark_rows.last()?);
+        let dark_columns: Vec<u32> = (0..w).filter(|x| longest_run(&mut (top..=bottom).map(|y| dark(*x, y))) * 2 > bottom - top).collect();
+        let (left, right) = (*dark_columns.first()?, *dark_columns.last()?);
+        let thickness = dark_rows.iter().zip(top..).take_while(|(y, expected)| **y == *expected).count() as u32;
+        if right <= left + thickness * 2 || bottom <= top + thickness * 2 {
+            return None;
+        }
+        Some((left, top, right, bottom, thickness))
+    }
+
+    // Reads a scanned calibration sheet, returning every pixel found for each palette color.
+    pub fn decode(&self, reader: &ArchiveHumanInputFile, multiplexer: &ColorMultiplexer) -> Result<Vec<Vec<Rgb<u8>>>, &'static str> {
+        println!("Reading image");
+        let image = reader.read_page().unwrap();
+        let (left, top, right, bottom, thickness) = CalibrationSheet::find_frame(&image).ok_or("Could not find the frame around the calibration patches")?;
+        let inner_left = (left + thickness) as f32;
+        let inner_top = (top + thickness) as f32;
+        let cell = (right - thickness + 1) as f32 - inner_left;
+        let cell = cell / CALIBRATION_COLUMNS as f32;
+        let rows = (((bottom - thickness + 1) as f32 - inner_top) / cell).round() as u32;
+        println!("Found {} rows of patches", rows);
+
+        // Only sample the middle of each patch, well clear of the white gaps around it.
+        let num_colors = multiplexer.num_colors() as u32;
+        let mut samples = vec![vec![]; num_colors as usize];
+        for row in 0..rows {
+            for column in 0..CALIBRATION_COLUMNS {
+                let x0 = (inner_left + (column as f32 + 0.25) * cell).round() as u32;
+                let y0 = (inner_top + (row as f32 + 0.25) * cell).round() as u32;
+                let x1 = (inner_left + (column as f32 + 0.75) * cell).round() as u32;
+                let y1 = (inner_top + (row as f32 + 0.75) * cell).round() as u32;
+                let index = CalibrationSheet::color_index(column, row, num_colors);
+                for y in y0..y1.min(image.height()) {
+                    for x in x0..x1.min(image.width()) {
+                        let p = image.get_pixel(x, y);
+                        samples[index].push(Rgb([p[0], p[1], p[2]]));
+                    }
+                }
+            }
+        }
+
+        // Report how each color came out.
+        for (c, s) in samples.iter().enumerate() {
+            if s.is_empty() {
+                return Err("Not every color was found on the calibration sheet");
+            }
+            let mean = Rgb([0, 1, 2].map(|i| (s.iter().map(|p| p[i] as u32).sum::<u32>() / s.len() as u32) as u8));
+            let printed = multiplexer.get_rgb()[c];
+            let lab = rgb_to_oklab(&mean);
+            let spread = (s.iter().map(|p| {
+                let l = rgb_to_oklab(p);
+                (0..3).map(|i| (l[i] - lab[i]).powi(2)).sum::<f32>()
+            }).sum::<f32>() / s.len() as f32).sqrt();
+            println!("- Color {}: printed #{:02x}{:02x}{:02x}, scanned as #{:02x}{:02x}{:02x}, varying by {:.3}", c, printed[0], printed[1], printed[2], mean[0], mean[1], mean[2], spread);
+        }
+        Ok(samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Roughly what printing and scanning does to colors: ink never gets as dark as black or paper as white as white, and the scanner adds a warm cast.
+    fn print_and_scan(c: &Rgb<u8>) -> Rgb<u8> {
+        let cast = [10.0, 0.0, -12.0];
+        Rgb([0, 1, 2].map(|i| (24.0 + c[i] as f32 * 0.8 + cast[i]).round().clamp(0.0, 255.0) as u8))
+    }
+
+    #[test]
+    fn reads_back_shifted_colors() {
+        let multiplexer = ColorMultiplexer::new(8).finalize();
+        let out_file = std::env::temp_dir().join(format!("realworldarchive-calibration-test-{}-", std::process::id()));
+        let writer = ArchiveHumanOutputFile::new(out_file.to_str().unwrap(), OutputFormat::PNG)
+            .size(4.0, 4.0)
+            .dpi(100)
+            .total_pages(1)
+            .colors(multiplexer.get_rgb())
+            .finalize();
+        let sheet = CalibrationSheet::new().finalize();
+        sheet.encode(&writer, &multiplexer);
+        let filename = writer.page_filename(0);
+        let mut page = image::open(&filename).unwrap().to_rgb8();
+        for p in page.pixels_mut() {
+            *p = print_and_scan(p);
+        }
+        page.save(&filename).unwrap();
+
+        let scanned = DynamicImage::ImageRgb8(page);
+        let (left, top, right, bottom, thickness) = CalibrationSheet::find_frame(&scanned).unwrap();
+        assert!(left < right && top < bottom && thickness > 0);
+
+        let reader = ArchiveHumanInputFile::new(&filename, OutputFormat::PNG)
+            .finalize();
+        let samples = sheet.decode(&reader, &multiplexer);
+        std::fs::remove_file(&filename).unwrap();
+        let samples = samples.unwrap();
+        assert_eq!(samples.len(), 8);
+        for (printed, s) in multiplexer.get_rgb().iter().zip(samples.iter()) {
+            // Patches are sampled well inside their edges, so every sample should be exactly the shifted color.
+            let expected = print_and_scan(printed);
+            assert!(s.iter().all(|p| *p == expected), "{:?} scanned as {:?}, not {:?}", printed, s[0], expected);
+        }
+    }
+}
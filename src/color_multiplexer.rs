@@ -3,17 +3,245 @@
 use image::Rgb;
 use image::Rgba;
 use image::RgbImage;
+use image::GrayImage;
+use image::Luma;
 use image::DynamicImage;
 use image::GenericImage;
 use image::GenericImageView;
+use image::Pixel;
 use gray_codes::GrayCode8;
 use hsl::HSL;
-use palette::Srgb;
+use palette::{Srgb, Oklab, IntoColor};
+use std::collections::HashMap;
 use kmeans_colors::{get_kmeans_hamerly, Kmeans};
+use crate::color_profile::ColorProfile;
+use crate::palette_swatch::FoundSwatch;
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum MultiplexMode {
+    // Colors spaced around the hue circle, in Gray code order.
+    Palette,
+    // One plane on each of the cyan, magenta, yellow, and black inks.
+    Cmyk,
+    // Two planes in four shades of gray, for laser printers and engravers which can't do color.
+    Grayscale
+}
 
 pub struct ColorMultiplexer {
+    mode: MultiplexMode,
+    colors_rgb: Vec<Rgb<u8>>,
+    // The same palette in OKLab, for classifying scanned pixels.
+    colors_lab: Vec<[f32; 3]>,
+    // Palettes measured from swatches at different places on the page, if we've found any.
+    local_palettes: Vec<LocalPalette>,
+    // What the printer and scanner were measured to do to each color, if we've been given a profile.
+    profile: Option<ColorProfile>,
+    // Inverted OKLab covariance of each palette color from the profile, for telling colors apart by how much they're known to vary.
+    // Empty without a profile, in which case distances are plain straight-line ones.
+    inverse_covariance: Vec<[[f32; 3]; 3]>,
+    // Whether the palette was picked by hand, so it isn't in hue order.
+    custom_palette: bool
+}
+
+// A palette as it appeared at one spot on a scanned page.
+struct LocalPalette {
+    // Position of the swatch it was measured from, as a fraction of the page width and height.
+    x: f32,
+    y: f32,
     colors_rgb: Vec<Rgb<u8>>,
-    colors_hsl: Vec<HSL>
+    colors_lab: Vec<[f32; 3]>
+}
+
+// Bands across the page where write_page puts palette swatches, as fractions of the page height: the header and the footer.
+// How many swatches are in each band depends on how much room the text leaves, so we find them rather than assuming where they are.
+const SWATCH_BANDS: [[f32; 2]; 2] = [[0.0, 0.2], [0.75, 1.0]];
+
+// How far apart a pixel's brightest and darkest channels need to be for it to count as colorful rather than black, white, or gray.
+// Going by this rather than HSL saturation means palette colors still count when the lighting is dim.
+const MIN_SWATCH_CHROMA: u8 = 40;
+
+// Size of the square areas which each get their own interpolated palette when demultiplexing.
+const LOCAL_PALETTE_TILE_SIZE: u32 = 64;
+
+// How much red, green, and blue light each ink lets through at full coverage, in cyan, magenta, yellow, black order.
+// Roughly what process inks look like on white paper.  Only used until we can measure the real thing from a swatch.
+// No real ink blocks a color completely, which is what lets black still be seen on top of the other three.
+const INK_TRANSMITTANCE: [[f32; 3]; 4] = [
+    [0.06, 0.68, 0.94],
+    [0.93, 0.06, 0.55],
+    [1.0, 0.95, 0.06],
+    [0.06, 0.06, 0.06]
+];
+
+// Black ink is only laid down at this coverage in barcodes, so the other inks under it can still be seen.
+// Full black would hide them, leaving nothing to unmix.
+pub const CMYK_BLACK_TINT: u8 = 128;
+
+// Optical densities beyond this are lost in scanner noise, so we don't try to tell them apart.
+const MAX_INK_DENSITY: f32 = 4.5;
+
+// Palettes which aren't a power of two can't carry a whole number of bits in each pixel, so they spread their planes over this many neighboring barcodes instead.
+// Every pixel in the group then holds one digit of a number made up of the planes' bits, and the group holds as many planes as fit in all of those digits together.
+// Pairs of pixels are enough to get an extra plane out of palettes like 3, 6, or 12 colors.
+const PIXELS_PER_GROUP: u32 = 2;
+
+// Added to the variance of every profiled color in OKLab, so colors which scanned perfectly evenly on the calibration sheet don't become impossibly picky.
+// Roughly the smallest color difference anyone can see.
+const MIN_PROFILE_VARIANCE: f32 = 0.02 * 0.02;
+
+// Shades for grayscale multiplexing, in palette order.
+// Black and white have to be all zeroes and all ones so finder patterns show up in both planes, which leaves 01 and 10 for the grays - so a misread between the two grays flips both planes, while every other neighboring pair only flips one.
+// That makes the middle of this order deliberately not a Gray code, since no order of four levels with 00 and 11 at the ends can be one.
+// The grays are spaced evenly in OKLab lightness, except that the gap between them is widened to make that misread the least likely one.
+const GRAY_LEVELS: [u8; 4] = [0, 46, 158, 255];
+
+// Most rounds of refining the gray levels a page was printed with, which they almost always settle well before.
+const GRAY_LEVEL_ITERATIONS: usize = 32;
+
+// Works out which shade each gray level came out as on a scanned page, starting from the given guesses.
+// Each pixel goes with the closest level, with the thresholds halfway between neighboring levels, and each level then moves to the average of its pixels, until nothing changes (Lloyd's algorithm, over the page's histogram).
+pub fn learn_gray_levels(img: &DynamicImage, initial: &[u8]) -> Vec<u8> {
+    let mut histogram = [0u64; 256];
+    for (_x, _y, pixel) in img.pixels() {
+        histogram[pixel.to_luma()[0] as usize] += 1;
+    }
+    // Stretch the guesses over the range of shades the page actually has first, since washed out black could otherwise be closer to the dark gray guess and leave the black level with nothing to learn from.
+    let darkest = histogram.iter().position(|c| *c > 0).unwrap_or(0) as i32;
+    let lightest = histogram.iter().rposition(|c| *c > 0).unwrap_or(255) as i32;
+    let mut levels: Vec<u8> = initial.iter().map(|l| (darkest + (*l as i32 * (lightest - darkest) + 127) / 255) as u8).collect();
+    for _i in 0..GRAY_LEVEL_ITERATIONS {
+        let mut sums = vec![0u64; levels.len()];
+        let mut counts = vec![0u64; levels.len()];
+        for (value, count) in histogram.iter().enumerate() {
+            let nearest = (0..levels.len()).min_by_key(|l| (levels[*l] as i32 - value as i32).abs()).unwrap();
+            sums[nearest] += value as u64 * count;
+            counts[nearest] += count;
+        }
+        // A level nothing was closest to stays where it was.
+        let refined: Vec<u8> = levels.iter().enumerate().map(|(l, level)| (sums[l] + counts[l] / 2).checked_div(counts[l]).map_or(*level, |v| v as u8)).collect();
+        if refined == levels {
+            break;
+        }
+        levels = refined;
+    }
+    levels
+}
+
+// Ink coverage for each palette index in CMYK mode.  A set bit is a light module, so it means that ink is left off.
+fn cmyk_palette_inks() -> Vec<[u8; 4]> {
+    (0..16u8).map(|i| [0, 1, 2, 3].map(|ink| {
+        if (i >> ink) & 1 == 1 { 0 } else if ink == 3 { CMYK_BLACK_TINT } else { 255 }
+    })).collect()
+}
+
+// What the given ink coverage looks like on white paper, with each ink filtering the light the others let through.
+pub fn cmyk_to_rgb(inks: [u8; 4]) -> Rgb<u8> {
+    Rgb([0, 1, 2].map(|channel| {
+        let transmitted: f32 = (0..4).map(|ink| INK_TRANSMITTANCE[ink][channel].powf(inks[ink] as f32 / 255.0)).product();
+        (transmitted * 255.0).round() as u8
+    }))
+}
+
+// Plain conversion for anything which isn't a palette color, like lettering, putting all the gray on the black ink.
+pub fn rgb_to_cmyk(c: &Rgb<u8>) -> [u8; 4] {
+    let k = 255 - *c.0.iter().max().unwrap();
+    if k == 255 {
+        return [0, 0, 0, 255];
+    }
+    let ink = |v: u8| ((255 - v - k) as u32 * 255 / (255 - k) as u32) as u8;
+    [ink(c[0]), ink(c[1]), ink(c[2]), k]
+}
+
+// Optical density of each channel of a color, relative to the paper.
+fn ink_density(c: &Rgb<u8>, paper: &Rgb<u8>) -> [f32; 3] {
+    [0, 1, 2].map(|i| (-((c[i].max(1) as f32) / (paper[i].max(1) as f32)).ln()).clamp(0.0, MAX_INK_DENSITY))
+}
+
+// Converts to OKLab (Ottosson, 2020), where straight-line distance roughly matches how different two colors look.
+// Hue is an angle around the lightness axis, so it wraps around on its own.
+pub fn rgb_to_oklab(c: &Rgb<u8>) -> [f32; 3] {
+    let lab: Oklab = Srgb::new(c[0], c[1], c[2]).into_format::<f32>().into_color();
+    [lab.l, lab.a, lab.b]
+}
+
+fn lab_distance_squared(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    (a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)
+}
+
+// How far the given color looks from each palette color, squared.
+// Given a profile's inverted covariances, this is how unlikely the color is to be each palette color instead, in the same units: the squared Mahalanobis distance, plus a penalty for colors which vary a lot so they don't claim everything nearby.
+fn palette_distances(palette_lab: &[[f32; 3]], inverse_covariance: &[[[f32; 3]; 3]], pixel: &Rgb<u8>) -> Vec<f32> {
+    let lab = rgb_to_oklab(pixel);
+    if inverse_covariance.is_empty() {
+        return palette_lab.iter().map(|palette_color| lab_distance_squared(&lab, palette_color)).collect();
+    }
+    // The inverse's determinant is one over the covariance's, so this is the log of how spread out each color is, relative to the tightest one.
+    let spreads: Vec<f32> = inverse_covariance.iter().map(|m| -determinant(m).ln()).collect();
+    let tightest = spreads.iter().cloned().fold(f32::MAX, f32::min);
+    palette_lab.iter().zip(inverse_covariance.iter()).zip(spreads.iter()).map(|((palette_color, m), spread)| {
+        let d = [0, 1, 2].map(|i| lab[i] - palette_color[i]);
+        let mahalanobis: f32 = (0..3).map(|i| (0..3).map(|j| d[i] * m[i][j] * d[j]).sum::<f32>()).sum();
+        mahalanobis.max(0.0) + spread - tightest
+    }).collect()
+}
+
+fn determinant(m: &[[f32; 3]; 3]) -> f32 {
+    m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1]) - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0]) + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+}
+
+// Inverts a covariance matrix, after widening it by MIN_PROFILE_VARIANCE so it can always be inverted.
+fn inverse_covariance(covariance: &[[f32; 3]; 3]) -> [[f32; 3]; 3] {
+    let mut m = *covariance;
+    for (i, row) in m.iter_mut().enumerate() {
+        row[i] += MIN_PROFILE_VARIANCE;
+    }
+    let det = determinant(&m);
+    // Adjugate over determinant, with the cofactor signs taken care of by going around the indices cyclically.
+    [0, 1, 2].map(|i| [0, 1, 2].map(|j| {
+        (m[(j + 1) % 3][(i + 1) % 3] * m[(j + 2) % 3][(i + 2) % 3] - m[(j + 1) % 3][(i + 2) % 3] * m[(j + 2) % 3][(i + 1) % 3]) / det
+    }))
+}
+
+// Picks the closest palette entry from squared distances to each, along with how sure we are of each plane's bit.
+// A plane's confidence compares the best entry with the closest one that has the other bit in that plane: 255 when the pixel sits right on the best entry, 0 when it's halfway between.
+// Codes are the plane bits of each palette entry.
+fn nearest_with_confidence(distances: &[f32], codes: &[usize], num_planes: usize) -> (usize, Vec<u8>) {
+    let mut closest_index = 0;
+    for (c, distance) in distances.iter().enumerate() {
+        if *distance < distances[closest_index] {
+            closest_index = c;
+        }
+    }
+    let best = distances[closest_index].sqrt();
+    let confidence = (0..num_planes).map(|p| {
+        let alternative = distances.iter().enumerate()
+            .filter(|(c, _)| (codes[*c] ^ codes[closest_index]) >> p & 1 != 0)
+            .map(|(_, d)| d.sqrt())
+            .fold(f32::MAX, f32::min);
+        if alternative + best <= 0.0 { 0 } else { ((alternative - best) / (alternative + best) * 255.0).round() as u8 }
+    }).collect();
+    (closest_index, confidence)
+}
+
+// Averages a small square of the image, returning None if it runs off the edge or isn't all one color.
+pub fn sample_solid_color(img: &DynamicImage, x: f32, y: f32, radius: f32) -> Option<Rgb<u8>> {
+    let (x0, y0) = ((x - radius).round(), (y - radius).round());
+    let (x1, y1) = ((x + radius).round(), (y + radius).round());
+    if x0 < 0.0 || y0 < 0.0 || x1 >= img.width() as f32 || y1 >= img.height() as f32 {
+        return None;
+    }
+    let mut pixels = vec![];
+    for sy in (y0 as u32)..=(y1 as u32) {
+        for sx in (x0 as u32)..=(x1 as u32) {
+            let p = img.get_pixel(sx, sy);
+            pixels.push([p[0] as f32, p[1] as f32, p[2] as f32]);
+        }
+    }
+    let mean = [0, 1, 2].map(|i| pixels.iter().map(|p| p[i]).sum::<f32>() / pixels.len() as f32);
+    if pixels.iter().any(|p| (0..3).map(|i| (p[i] - mean[i]).powi(2)).sum::<f32>() > 40.0 * 40.0) {
+        return None;
+    }
+    Some(Rgb(mean.map(|c| c.round() as u8)))
 }
 
 fn reorder_by_gray_code(num_colors: u8, colors_rgb: Vec<Rgb<u8>>, colors_hsl: Vec<HSL>) -> (Vec<Rgb<u8>>, Vec<HSL>) {
@@ -47,12 +275,75 @@ fn reorder_by_gray_code(num_colors: u8, colors_rgb: Vec<Rgb<u8>>, colors_hsl: Ve
     return (gray_code_order_rgb, gray_code_order_hsl);
 }
 
+// Puts a palette someone picked by hand into the order we multiplex with: the darkest color first as black and the lightest last as white, so finder patterns stay dark and light.
+// For power of two palettes, the colors in between are shuffled so that colors which look alike end up one bit apart, the way Gray code order does for colors spaced around the hue circle.
+// We can't rely on hue for arbitrary inks and materials, so this goes by how far apart they measure in OKLab instead: closer pairs count for more, and colors get swapped around for as long as that makes the bits that differ between them cheaper.
+// Grouped palettes don't carry bits in each color, so they're left in the order given.
+pub fn order_custom_palette(colors: &[Rgb<u8>]) -> Vec<Rgb<u8>> {
+    let mut ordered = colors.to_vec();
+    let lightness = |c: &Rgb<u8>| rgb_to_oklab(c)[0];
+    let darkest = (0..ordered.len()).min_by(|a, b| lightness(&ordered[*a]).total_cmp(&lightness(&ordered[*b]))).unwrap();
+    let black = ordered.remove(darkest);
+    ordered.insert(0, black);
+    let lightest = (1..ordered.len()).max_by(|a, b| lightness(&ordered[*a]).total_cmp(&lightness(&ordered[*b]))).unwrap();
+    let white = ordered.remove(lightest);
+    ordered.push(white);
+    if ordered.len() <= 4 || !ordered.len().is_power_of_two() {
+        // With 4 colors, the two in between are one bit from black and white whichever way round they go, so there's nothing to gain.
+        return ordered;
+    }
+
+    // White's index gets set to all ones when multiplexing, so the codes are just the indexes.
+    let lab: Vec<[f32; 3]> = ordered.iter().map(rgb_to_oklab).collect();
+    let n = ordered.len();
+    let mut colors_at: Vec<usize> = (0..n).collect();
+    let weight = |a: usize, b: usize| 1.0 / (lab_distance_squared(&lab[a], &lab[b]) + 1e-6);
+    let cost_at = |colors_at: &[usize], position: usize, color: usize, skip: usize| -> f32 {
+        (0..n).filter(|q| *q != position && *q != skip).map(|q| weight(color, colors_at[q]) * ((position ^ q).count_ones() - 1) as f32).sum()
+    };
+    let mut improved = true;
+    while improved {
+        improved = false;
+        for a in 1..(n - 1) {
+            for b in (a + 1)..(n - 1) {
+                let before = cost_at(&colors_at, a, colors_at[a], b) + cost_at(&colors_at, b, colors_at[b], a);
+                let after = cost_at(&colors_at, a, colors_at[b], b) + cost_at(&colors_at, b, colors_at[a], a);
+                if after < before * 0.9999 {
+                    colors_at.swap(a, b);
+                    improved = true;
+                }
+            }
+        }
+    }
+    colors_at.iter().map(|c| ordered[*c]).collect()
+}
+
+// How many planes' worth of bits fit in a group of pixels with the given number of colors: the largest power of two no bigger than every combination of their colors.
+fn bits_per_group(num_colors: usize, pixels: u32) -> u32 {
+    (num_colors as u64).pow(pixels).ilog2()
+}
+
+// Largest palette size up to the given one which doesn't waste any colors.
+// Powers of two always work, and sizes in between only help if grouping pixels gets an extra plane out of them - 5 colors can't carry any more than 4.
+pub fn usable_num_colors(num_colors: u8) -> u8 {
+    if num_colors <= 2 || num_colors.is_power_of_two() {
+        return num_colors.max(2);
+    }
+    let rounded = 2_u8.pow(num_colors.ilog2());
+    if bits_per_group(num_colors as usize, PIXELS_PER_GROUP) > bits_per_group(rounded as usize, PIXELS_PER_GROUP) {
+        num_colors
+    }
+    else {
+        rounded
+    }
+}
+
 fn generate_palette(num_colors_unrounded: u8) -> (Vec<Rgb<u8>>, Vec<HSL>) {
     if num_colors_unrounded == 2 {
         return (vec![Rgb([0, 0, 0]), Rgb([255, 255, 255])], vec![HSL { h: 0.0, s: 0.0, l: 0.0 }, HSL { h: 0.0, s: 0.0, l: 1.0 }]);
     }
     //println!("Number of colors: {}", num_colors_unrounded);
-    let num_colors = (2 as u8).pow(num_colors_unrounded.ilog2());
+    let num_colors = usable_num_colors(num_colors_unrounded);
     //println!("Number of colors rounded: {}", num_colors);
     let mut colors_rgb = vec![Rgb([0, 0, 0])];
     let mut colors_hsl = vec![HSL { h: 0.0, s: 0.0, l: 0.0 }];
@@ -75,27 +366,328 @@ fn generate_palette(num_colors_unrounded: u8) -> (Vec<Rgb<u8>>, Vec<HSL>) {
     colors_rgb.push(Rgb([255, 255, 255]));
     colors_hsl.push(HSL { h: 0.0, s: 0.0, l: 1.0 });
 
+    // Each pixel of a grouped palette is a digit rather than a set of bits, so there's no Gray code to put them in, and they stay in hue order.
+    if !num_colors.is_power_of_two() {
+        return (colors_rgb, colors_hsl);
+    }
     reorder_by_gray_code(num_colors, colors_rgb, colors_hsl)
 }
 
+// Estimates how many colors a page uses from its palette swatch, rounded down to a palette size we can generate.
+// Black and white aren't in the swatch, so they're added on afterward.  Returns 2 if there's no swatch at all.
+// Takes the labeled swatches found on the page, so the page only has to be searched for them once.
+pub fn estimate_num_colors(img: &DynamicImage, swatches: &[FoundSwatch]) -> u8 {
+    // A labeled swatch says exactly how many colors it holds.
+    if let Some(swatch) = swatches.iter().find(|s| s.label.first_index == 1) {
+        return usable_num_colors((swatch.label.count as u32 + 2).min(128) as u8);
+    }
+
+    // Otherwise count the colors in the header and footer bands, where write_page puts the swatches.
+    let mut clusters: Vec<([f32; 3], u32)> = vec![];
+    let h = img.height() as f32;
+    for (_px, _py, pixel) in img.pixels().filter(|(_, y, _)| SWATCH_BANDS.iter().any(|band| (*y as f32) >= band[0] * h && (*y as f32) < band[1] * h)) {
+        let hsl = HSL::from_rgb(&[pixel[0], pixel[1], pixel[2]]);
+        if hsl.s < 0.5 || hsl.l < 0.15 || hsl.l > 0.9 {
+            // Black, white, gray, or antialiased lettering.
+            continue;
+        }
+        let rgb = [pixel[0] as f32, pixel[1] as f32, pixel[2] as f32];
+        let mut found = false;
+        for (center, count) in clusters.iter_mut() {
+            let distance = ((center[0] - rgb[0]).powi(2) + (center[1] - rgb[1]).powi(2) + (center[2] - rgb[2]).powi(2)).sqrt();
+            if distance < 48.0 {
+                *count += 1;
+                found = true;
+                break;
+            }
+        }
+        if !found {
+            clusters.push((rgb, 1));
+        }
+    }
+
+    // Ignore stray pixels from antialiasing and scanner noise - every real swatch patch is the same size.
+    let largest = clusters.iter().map(|(_, c)| *c).max().unwrap_or(0);
+    let swatch_colors = clusters.iter().filter(|(_, c)| *c * 4 >= largest).count() as u32;
+    if swatch_colors == 0 {
+        return 2;
+    }
+    usable_num_colors((swatch_colors + 2).min(128) as u8)
+}
+
 impl<'a> ColorMultiplexer {
     pub fn new(num_colors: u8) -> ColorMultiplexer {
-        let (rgb, hsl) = generate_palette(num_colors);
+        let (rgb, _hsl) = generate_palette(num_colors);
         ColorMultiplexer {
+            mode: MultiplexMode::Palette,
+            colors_lab: rgb.iter().map(rgb_to_oklab).collect(),
             colors_rgb: rgb,
-            colors_hsl: hsl
+            local_palettes: vec![],
+            profile: None,
+            inverse_covariance: vec![],
+            custom_palette: false
         }
     }
 
+    // Multiplexes with a palette of our choosing, like a set of specialty inks, rather than colors spaced around the hue circle.
+    // This sets the number of colors along with it, and puts the colors in the order given by order_custom_palette.
+    pub fn colors(mut self, colors: &[Rgb<u8>]) -> Self {
+        self.set_palette(order_custom_palette(colors));
+        self.custom_palette = true;
+        self
+    }
+
+    // Switching to CMYK or grayscale replaces the palette, since each has its own fixed set of planes.
+    pub fn mode(mut self, mode: MultiplexMode) -> Self {
+        self.mode = mode;
+        match mode {
+            MultiplexMode::Palette => {},
+            MultiplexMode::Cmyk => self.set_palette(cmyk_palette_inks().into_iter().map(cmyk_to_rgb).collect()),
+            MultiplexMode::Grayscale => self.set_palette(GRAY_LEVELS.iter().map(|l| Rgb([*l, *l, *l])).collect())
+        }
+        self
+    }
+
+    // Uses a measured printer and scanner profile, which sets the number of colors and the multiplexing mode along with it.
+    // Encoding prints with the profile's colors, predistorted if it has them, and decoding starts from the colors the scanner was measured to see.
+    pub fn profile(mut self, profile: &ColorProfile) -> Self {
+        self = self.mode(profile.mode);
+        self.set_palette(profile.encoding_colors().clone());
+        if profile.mode == MultiplexMode::Palette {
+            self.inverse_covariance = profile.covariance.iter().map(inverse_covariance).collect();
+        }
+        self.profile = Some(profile.clone());
+        self
+    }
+
     pub fn finalize(self) -> ColorMultiplexer {
         ColorMultiplexer {
+            mode: self.mode,
             colors_rgb: self.colors_rgb,
-            colors_hsl: self.colors_hsl
+            colors_lab: self.colors_lab,
+            local_palettes: self.local_palettes,
+            profile: self.profile,
+            inverse_covariance: self.inverse_covariance,
+            custom_palette: self.custom_palette
+        }
+    }
+
+    fn set_palette(&mut self, rgb: Vec<Rgb<u8>>) {
+        self.colors_lab = rgb.iter().map(rgb_to_oklab).collect();
+        self.colors_rgb = rgb;
+        self.local_palettes = vec![];
+    }
+
+    // Blends the local palettes for a spot on the page, weighting the closest swatches most heavily.
+    // Falls back to the page-wide palette if we don't have any.
+    fn palette_at(&self, x: f32, y: f32) -> Vec<[f32; 3]> {
+        if self.local_palettes.is_empty() {
+            return self.colors_lab.clone();
+        }
+        let mut blended = vec![[0.0; 3]; self.colors_lab.len()];
+        let mut total_weight = 0.0;
+        for local in &self.local_palettes {
+            let weight = 1.0 / ((local.x - x).powi(2) + (local.y - y).powi(2) + 0.001);
+            total_weight += weight;
+            for (b, c) in blended.iter_mut().zip(local.colors_lab.iter()) {
+                for (b_channel, c_channel) in b.iter_mut().zip(c.iter()) {
+                    *b_channel += c_channel * weight;
+                }
+            }
+        }
+        for b in blended.iter_mut() {
+            for b_channel in b.iter_mut() {
+                *b_channel /= total_weight;
+            }
+        }
+        blended
+    }
+
+    // Reads the palette from each labeled swatch on the page which holds every color but black and white.
+    // The label says which color each patch is, so these can be found anywhere on the page and need no guessing at which color is which.
+    fn find_labeled_palettes(&self, img: &DynamicImage, swatches: &[FoundSwatch]) -> Vec<LocalPalette> {
+        let colors_except_bw = self.colors_rgb.len() - 2;
+        swatches.iter().filter(|s| s.label.first_index == 1 && s.label.count as usize == colors_except_bw).map(|s| {
+            let mut colors_rgb = vec![s.black];
+            colors_rgb.extend(&s.patches);
+            colors_rgb.push(s.white);
+            LocalPalette {
+                x: s.x / img.width() as f32,
+                y: s.y / img.height() as f32,
+                colors_lab: colors_rgb.iter().map(rgb_to_oklab).collect(),
+                colors_rgb
+            }
+        }).collect()
+    }
+
+    // Measures the palette from each swatch on a page printed without swatch labels, so lighting which changes from one part of the page to another can be accounted for.
+    // Swatch patches are solid blocks of color, where barcode modules and antialiased lettering are not, which is how we find them.
+    fn find_local_palettes(&self, img: &DynamicImage) -> Vec<LocalPalette> {
+        let (w, h) = (img.width(), img.height());
+        let mut local_palettes = vec![];
+        if w < 5 || h < 5 {
+            return local_palettes;
+        }
+        for band in SWATCH_BANDS {
+            let top = ((band[0] * h as f32) as u32).max(2);
+            let bottom = ((band[1] * h as f32) as u32).min(h - 2);
+
+            // Colorful pixels which match their neighbors are probably part of a swatch patch.
+            let mut swatch_pixels: Vec<(u32, u32, Rgb<u8>)> = vec![];
+            for y in top..bottom {
+                for x in 2..(w - 2) {
+                    let pixel = img.get_pixel(x, y);
+                    let c = Rgb([pixel[0], pixel[1], pixel[2]]);
+                    if c.0.iter().max().unwrap() - c.0.iter().min().unwrap() < MIN_SWATCH_CHROMA {
+                        continue;
+                    }
+                    let uniform = [(x - 2, y - 2), (x, y - 2), (x + 2, y - 2), (x - 2, y), (x + 2, y), (x - 2, y + 2), (x, y + 2), (x + 2, y + 2)].iter().all(|(nx, ny)| {
+                        let n = img.get_pixel(*nx, *ny);
+                        (0..3).map(|i| (n[i] as i32 - c[i] as i32).pow(2)).sum::<i32>() < 40 * 40
+                    });
+                    if uniform {
+                        swatch_pixels.push((x, y, c));
+                    }
+                }
+            }
+
+            // Swatch rows are full of these, where the odd run of same-colored barcode modules only adds a few, so drop the sparse rows.
+            let mut row_counts = vec![0; h as usize];
+            for p in &swatch_pixels {
+                row_counts[p.1 as usize] += 1;
+            }
+            let max_row_count = row_counts.iter().copied().max().unwrap_or(0);
+            swatch_pixels.retain(|p| row_counts[p.1 as usize] * 4 >= max_row_count);
+            if swatch_pixels.is_empty() {
+                continue;
+            }
+
+            // Patches are half a cell wide with half a cell between them, and swatches are at least a cell apart, so measure the patches to tell the two kinds of gap apart.
+            // The neighbor check trims a couple of pixels off each side of every patch.
+            swatch_pixels.sort_by_key(|p| (p.1, p.0));
+            let mut runs = vec![];
+            let mut run = 1;
+            for (a, b) in swatch_pixels.iter().zip(swatch_pixels.iter().skip(1)) {
+                if b.1 == a.1 && b.0 == a.0 + 1 {
+                    run += 1;
+                }
+                else {
+                    runs.push(run);
+                    run = 1;
+                }
+            }
+            runs.push(run);
+            runs.sort();
+            let max_gap = (runs[runs.len() / 2] + 4) * 2;
+
+            // Split them into separate swatches wherever there's a gap that wide.
+            swatch_pixels.sort_by_key(|p| p.0);
+            let mut groups: Vec<Vec<(u32, u32, Rgb<u8>)>> = vec![vec![]];
+            for p in swatch_pixels {
+                let current = groups.last_mut().unwrap();
+                if let Some(last) = current.last() {
+                    if p.0 > last.0 + max_gap {
+                        groups.push(vec![]);
+                    }
+                }
+                groups.last_mut().unwrap().push(p);
+            }
+
+            for group in groups {
+                if let Some(local) = self.read_swatch(img, &group) {
+                    local_palettes.push(local);
+                }
+            }
+        }
+        local_palettes
+    }
+
+    // Reads the colors of one swatch, given the solid pixels we found in it.
+    // Patches are laid out left to right then top to bottom in palette order, the same way write_page draws them, so where a patch is tells us which color it is.
+    fn read_swatch(&self, img: &DynamicImage, pixels: &[(u32, u32, Rgb<u8>)]) -> Option<LocalPalette> {
+        let num_colors = self.colors_rgb.len();
+        let colors_except_bw = num_colors as u32 - 2;
+
+        // The neighbor check trims a couple of pixels off every edge, so put them back.
+        let left = pixels.iter().map(|p| p.0).min()? as f32 - 2.0;
+        let right = pixels.iter().map(|p| p.0).max()? as f32 + 2.0;
+        let top = pixels.iter().map(|p| p.1).min()? as f32 - 2.0;
+        let bottom = pixels.iter().map(|p| p.1).max()? as f32 + 2.0;
+        let (width, height) = (right - left + 1.0, bottom - top + 1.0);
+
+        // Each patch takes up the middle half of a square cell, so a swatch with this many rows and columns is (columns - 0.5) by (rows - 0.5) cells.
+        let mut layout: Option<(u32, f32)> = None;
+        for rows in 1..=colors_except_bw {
+            let columns = colors_except_bw.div_ceil(rows);
+            let expected = (columns as f32 - 0.5) / (rows as f32 - 0.5);
+            let error = ((width / height) / expected).ln().abs();
+            if layout.is_none_or(|(_, e)| error < e) {
+                layout = Some((rows, error));
+            }
+        }
+        let (rows, error) = layout?;
+        if error > 0.25 {
+            // Not shaped like a swatch.
+            return None;
+        }
+        let columns = colors_except_bw.div_ceil(rows);
+        let cell = width / (columns as f32 - 0.5);
+        let radius = (cell / 8.0).max(1.0);
+
+        let mut colors_rgb = vec![self.colors_rgb[0]];
+        for c in 0..colors_except_bw {
+            let x = left + (c % columns) as f32 * cell + cell / 4.0;
+            let y = top + (c / columns) as f32 * cell + cell / 4.0;
+            colors_rgb.push(sample_solid_color(img, x, y, radius)?);
+        }
+        if columns > 1 {
+            // The black border shows between patches, except on labeled swatches, which we're only reading this way if the label was unreadable.
+            let border = sample_solid_color(img, left + cell * 0.75, top + cell / 4.0, radius)?;
+            if colors_rgb[1..].iter().all(|c| c.to_luma()[0] > border.to_luma()[0]) {
+                colors_rgb[0] = border;
+            }
         }
+
+        // Take whichever side of the swatch is paper rather than barcode.
+        let above = sample_solid_color(img, left + width / 2.0, top - cell * 0.75, radius);
+        let below = sample_solid_color(img, left + width / 2.0, bottom + cell * 0.75, radius);
+        let white = [above, below].into_iter().flatten().max_by_key(|c| c[0] as u32 + c[1] as u32 + c[2] as u32)?;
+        colors_rgb.push(white);
+
+        Some(LocalPalette {
+            x: (left + width / 2.0) / img.width() as f32,
+            y: (top + height / 2.0) / img.height() as f32,
+            colors_lab: colors_rgb.iter().map(rgb_to_oklab).collect(),
+            colors_rgb
+        })
+    }
+
+    // How many neighboring barcodes each set of planes is spread over.
+    // A power of two palette carries a whole number of bits in every pixel, so each barcode square stands on its own.
+    pub fn pixels_per_group(&self) -> u32 {
+        if self.colors_rgb.len().is_power_of_two() { 1 } else { PIXELS_PER_GROUP }
     }
 
+    // Number of barcode planes multiplexed together, which for grouped palettes are spread over the whole group.
     pub fn num_planes(&self) -> u8 {
-        self.colors_rgb.len().ilog2() as u8
+        bits_per_group(self.colors_rgb.len(), self.pixels_per_group()) as u8
+    }
+
+    // Number of planes demultiplex_image splits a page into.
+    // Grouped palettes can't be split into barcode planes a pixel at a time, so these hold the bits of each pixel's palette index instead, for ungroup_modules to put back together.
+    pub fn num_demultiplexed_planes(&self) -> u8 {
+        if self.pixels_per_group() == 1 {
+            self.num_planes()
+        }
+        else {
+            ((self.colors_rgb.len() - 1).ilog2() + 1) as u8
+        }
+    }
+
+    // Bits each palette color sets in the demultiplexed planes: its index, except for white, which has to be all ones so finder patterns show up in every plane.
+    fn plane_codes(&self) -> Vec<usize> {
+        let all_ones = (1 << self.num_demultiplexed_planes()) - 1;
+        (0..self.colors_rgb.len()).map(|c| if c == self.colors_rgb.len() - 1 { all_ones } else { c }).collect()
     }
 
     pub fn num_colors(&self) -> u8 {
@@ -106,15 +698,106 @@ impl<'a> ColorMultiplexer {
         &self.colors_rgb
     }
 
-    pub fn palettize_from_image(&mut self, img: &DynamicImage) {
+    // Ink coverage to print each palette color with, if we're multiplexing onto inks rather than colors.
+    pub fn get_inks(&self) -> Option<Vec<[u8; 4]>> {
+        match self.mode {
+            MultiplexMode::Palette | MultiplexMode::Grayscale => None,
+            MultiplexMode::Cmyk => Some(cmyk_palette_inks())
+        }
+    }
+
+    // How far the given color is from each ink combination, squared, comparing optical densities so that overprinted inks add up.
+    fn ink_combination_distances(&self, densities: &[[f32; 3]; 4], paper: &Rgb<u8>, pixel: &Rgb<u8>) -> Vec<f32> {
+        let measured = ink_density(pixel, paper);
+        (0..self.colors_rgb.len()).map(|index| {
+            let mut distance = 0.0;
+            for channel in 0..3 {
+                let expected: f32 = (0..4).filter(|ink| (index >> ink) & 1 == 0).map(|ink| densities[ink][channel]).sum();
+                distance += (expected.min(MAX_INK_DENSITY) - measured[channel]).powi(2);
+            }
+            distance
+        }).collect()
+    }
+
+    // Density of each ink as printed, measured from the palette colors where it's the only ink.
+    fn ink_densities(&self) -> [[f32; 3]; 4] {
+        let white = self.colors_rgb.len() - 1;
+        [0, 1, 2, 3].map(|ink| ink_density(&self.colors_rgb[white ^ (1 << ink)], &self.colors_rgb[white]))
+    }
+
+    // Takes the labeled swatches found on the page, which the caller finds once however many palettes it tries.
+    pub fn palettize_from_image(&mut self, img: &DynamicImage, swatches: &[FoundSwatch]) {
         //println!("Repalettizing from {:?}", self.colors_rgb);
 
+        // Don't carry over anything measured from a previous page.
+        self.local_palettes = vec![];
+
+        // A profile tells us how this printer and scanner shift every color, so there's no need to guess at the page-wide palette.
+        // Its covariance describes how each of its colors scatters around the one it measured, so it doesn't fit colors measured on this page instead - keep the profile's.
+        if let Some(profile) = &self.profile {
+            self.set_palette(profile.decoding_colors());
+            if !self.inverse_covariance.is_empty() {
+                return;
+            }
+        }
+
         let num_colors = self.num_colors();
         if num_colors <= 2 {
             // It's monochrome - there's no need to repalettize.
             return;
         }
 
+        // Labeled swatches tell us exactly what each color looks like on this page, so there's no need to guess from clusters.
+        let labeled = self.find_labeled_palettes(img, swatches);
+        let average = |palettes: &[LocalPalette]| -> Vec<Rgb<u8>> {
+            (0..num_colors as usize).map(|c| {
+                Rgb([0, 1, 2].map(|i| (palettes.iter().map(|l| l.colors_rgb[c][i] as u32).sum::<u32>() / palettes.len() as u32) as u8))
+            }).collect()
+        };
+
+        if self.mode == MultiplexMode::Grayscale {
+            // Barcode modules far outnumber swatch patches, so learn the shades from the whole page, starting from what the swatches show if we found any.
+            let current: Vec<u8> = if labeled.is_empty() { self.colors_rgb.clone() } else { average(&labeled) }.iter().map(|c| c.to_luma()[0]).collect();
+            self.set_palette(learn_gray_levels(img, &current).into_iter().map(|l| Rgb([l, l, l])).collect());
+            return;
+        }
+
+        if !labeled.is_empty() {
+            let averaged = average(&labeled);
+            self.set_palette(averaged);
+            // Inks don't change much across a page the way lighting does, so CMYK just uses the average.
+            if self.mode != MultiplexMode::Cmyk && labeled.len() > 1 {
+                self.local_palettes = labeled;
+            }
+            return;
+        }
+
+        if self.mode == MultiplexMode::Cmyk || self.custom_palette {
+            // The palette is ordered by ink or by hand rather than by hue, so only the swatches can tell us which color is which.
+            // Inks don't change much across a page the way lighting does, so average everything we find into one palette.
+            let local_palettes = self.find_local_palettes(img);
+            if !local_palettes.is_empty() {
+                let averaged = average(&local_palettes);
+                self.set_palette(averaged);
+            }
+            if self.mode == MultiplexMode::Cmyk {
+                return;
+            }
+        }
+
+        if self.profile.is_none() && !self.custom_palette {
+            self.palettize_from_clusters(num_colors, img);
+        }
+
+        // Only worth doing if there's more than one swatch to interpolate between.
+        let local_palettes = self.find_local_palettes(img);
+        if local_palettes.len() > 1 {
+            self.local_palettes = local_palettes;
+        }
+    }
+
+    // Works out the page-wide palette from the most common colors in the swatch corner of the page, or the whole lower half if that doesn't work.
+    fn palettize_from_clusters(&mut self, num_colors: u8, img: &DynamicImage) {
         // Find the most dominant colors in the image.
         // We only really want the palette sample in the bottom right corner, ignoring the antialiased lettering.
         let result = self.palettize_from_image_chunk(num_colors, img, img.width() / 2, img.height() / 8 * 7);
@@ -125,18 +808,15 @@ impl<'a> ColorMultiplexer {
                 match result2 {
                     Err(_e) => {
                         // Don't change the palette.
-                        return;
                     },
-                    Ok((rgb, hsl)) => {
+                    Ok((rgb, _hsl)) => {
                         // This time it worked.
-                        self.colors_rgb = rgb;
-                        self.colors_hsl = hsl;
+                        self.set_palette(rgb);
                     }
                 }
             },
-            Ok((rgb, hsl)) => {
-                self.colors_rgb = rgb;
-                self.colors_hsl = hsl;
+            Ok((rgb, _hsl)) => {
+                self.set_palette(rgb);
             }
         };
     }
@@ -235,95 +915,429 @@ impl<'a> ColorMultiplexer {
         colors_hsl.push(light_hsl);
         colors_rgb.push(light_rgb);
 
-        // Reorder by Gray code.
-        let out = reorder_by_gray_code(num_colors, colors_rgb, colors_hsl);
+        // Reorder by Gray code, unless it's a grouped palette, which stays in hue order.
+        let out = if num_colors.is_power_of_two() { reorder_by_gray_code(num_colors, colors_rgb, colors_hsl) } else { (colors_rgb, colors_hsl) };
 
         //println!("Repalettized to {:?}", self.colors_rgb);
 
         Ok(out)
     }
 
-    pub fn multiplex_planes(&self, p: Vec<RgbImage>) -> RgbImage {
+    // Combines barcode planes into one color image.
+    // Grouped palettes spread them over pixels_per_group() barcodes side by side, with the given number of pixels of white between them.
+    pub fn multiplex_planes(&self, p: Vec<RgbImage>, spacing: u32) -> RgbImage {
         if p.len() != self.num_planes() as usize {
             panic!("Wrong number of color planes to multiplex");
         }
 
         let w = p[0].width();
         let h = p[0].height();
-        let mut out_image = RgbImage::new(w, h);
+        let group = self.pixels_per_group();
+        let mut out_image = RgbImage::from_pixel(w * group + spacing * (group - 1), h, Rgb([255, 255, 255]));
+        let num_colors = self.colors_rgb.len() as u64;
+        let all_ones = (1u64 << p.len()) - 1;
+        let all_white = num_colors.pow(group) - 1;
         for x in 0..w {
             for y in 0..h {
                 // Multiplex the pixels into a single color.
-                let mut out_palette_index: usize = 0;
+                let mut out_palette_index: u64 = 0;
                 for c in 0..p.len() {
                     let pixel = p[c].get_pixel(x, y);
                     if pixel[0] > 127 {
                         out_palette_index |= 1 << c;
                     }
                 }
-                out_image.put_pixel(x, y, self.colors_rgb[out_palette_index]);
+
+                // Write it out as digits in base num_colors, one in each barcode of the group.
+                // With a power of two palette, that's just the palette index.  Otherwise, all ones is moved to all white so finder patterns stay black and white.
+                let mut digits = if out_palette_index == all_ones { all_white } else { out_palette_index };
+                for t in 0..group {
+                    out_image.put_pixel(x + t * (w + spacing), y, self.colors_rgb[(digits % num_colors) as usize]);
+                    digits /= num_colors;
+                }
             }
         }
         out_image
     }
 
+    // Works out barcode planes from the modules of a group of barcodes read from the demultiplexed planes, undoing what multiplex_planes did for grouped palettes.
+    // modules[t][k] holds the modules of barcode t of the group as read from demultiplexed plane k, with 0 for dark.  Returns each barcode plane's modules the same way.
+    pub fn ungroup_modules(&self, modules: &[Vec<Vec<u8>>]) -> Vec<Vec<u8>> {
+        let num_colors = self.colors_rgb.len() as u64;
+        let num_planes = self.num_planes() as usize;
+        let all_ones = (1u64 << num_planes) - 1;
+        let all_white = num_colors.pow(modules.len() as u32) - 1;
+        let num_modules = modules[0][0].len();
+        let mut planes = vec![vec![0; num_modules]; num_planes];
+        for m in 0..num_modules {
+            let mut digits: u64 = 0;
+            for barcode in modules.iter().rev() {
+                let code: u64 = barcode.iter().enumerate().map(|(k, plane)| if plane[m] != 0 { 1 << k } else { 0 }).sum();
+                // White is the only color whose code is past the end of the palette.
+                digits = digits * num_colors + code.min(num_colors - 1);
+            }
+            // Combinations past the last one we could have written are misreads, which we leave to the barcodes' error correction.
+            let value = if digits == all_white { all_ones } else { digits.min(all_ones) };
+            for (p, plane) in planes.iter_mut().enumerate() {
+                if (value >> p) & 1 == 1 {
+                    plane[m] = 255;
+                }
+            }
+        }
+        planes
+    }
+
     pub fn demultiplex_image(&self, color_image: &DynamicImage) -> Vec<DynamicImage> {
-        let num_images = self.num_planes() as usize;
+        self.demultiplex_image_with_confidence(color_image).into_iter().map(|(plane, _confidence)| plane).collect()
+    }
+
+    // Splits the image into bit planes like demultiplex_image, also returning a confidence map for each plane.
+    // Each confidence pixel says how clearly the color there picked its bit in that plane, from 0 (could have gone either way) to 255.
+    pub fn demultiplex_image_with_confidence(&self, color_image: &DynamicImage) -> Vec<(DynamicImage, GrayImage)> {
+        let num_images = self.num_demultiplexed_planes() as usize;
+        let codes = self.plane_codes();
         let mut planes = vec![];
+        let mut confidence_maps = vec![];
 
         // Fill the output array so we can start decoding.
         for _i in 0..num_images {
             planes.push(DynamicImage::new_luma8(color_image.width(), color_image.height()));
+            confidence_maps.push(GrayImage::new(color_image.width(), color_image.height()));
         }
 
         // Loop through each pixel and palettize it.
-        for x in 0..color_image.width() {
-            for y in 0..color_image.height() {
-                let palette_index;
-                let pixel = color_image.get_pixel(x, y);
-                let hsl = HSL::from_rgb(&[pixel[0], pixel[1], pixel[2]]);
-                if hsl.s < 0.5 {
-                    // Special case - either black or white.
-                    if hsl.l < 0.5 {
-                        // Black
-                        palette_index = 0;
-                    }
-                    else {
-                        // White - always the last color in the palette
-                        palette_index = self.colors_rgb.len() - 1;
-                    }
-                }
-                else {
-                    // Find the closest color
-                    let mut closest_index = 0;
-                    let mut best_hue_distance = 360.0;
-                    for c in 1..self.colors_hsl.len() - 1 {
-                        let this_color_distance = f64::abs(self.colors_hsl[c].h - hsl.h);
-                        if this_color_distance < best_hue_distance {
-                            closest_index = c;
-                            best_hue_distance = this_color_distance;
+        // If we have palettes from different parts of the page, work through it in tiles which each get their own blend of them.
+        let (w, h) = (color_image.width(), color_image.height());
+        let tile_size = if self.local_palettes.is_empty() { w.max(h) } else { LOCAL_PALETTE_TILE_SIZE };
+        let densities = if self.mode == MultiplexMode::Cmyk { self.ink_densities() } else { [[0.0; 3]; 4] };
+        let paper = self.colors_rgb[self.colors_rgb.len() - 1];
+        for tile_x in (0..w).step_by(tile_size as usize) {
+            for tile_y in (0..h).step_by(tile_size as usize) {
+                let tile_width = tile_size.min(w - tile_x);
+                let tile_height = tile_size.min(h - tile_y);
+                let palette_lab = self.palette_at((tile_x + tile_width / 2) as f32 / w as f32, (tile_y + tile_height / 2) as f32 / h as f32);
+
+                // Scans only have so many distinct colors, so remember the ones we've already classified.
+                let mut classified: HashMap<[u8; 3], (usize, Vec<u8>)> = HashMap::new();
+                for x in tile_x..(tile_x + tile_width) {
+                    for y in tile_y..(tile_y + tile_height) {
+                        let pixel = color_image.get_pixel(x, y);
+                        let rgb = [pixel[0], pixel[1], pixel[2]];
+                        let (palette_index, confidence) = classified.entry(rgb).or_insert_with(|| {
+                            let distances = match self.mode {
+                                MultiplexMode::Palette => palette_distances(&palette_lab, &self.inverse_covariance, &Rgb(rgb)),
+                                MultiplexMode::Cmyk => self.ink_combination_distances(&densities, &paper, &Rgb(rgb)),
+                                // Only lightness matters, so the nearest level is whichever side of the thresholds between them the pixel falls on.
+                                MultiplexMode::Grayscale => {
+                                    let luma = Rgb(rgb).to_luma()[0] as f32;
+                                    self.colors_rgb.iter().map(|c| (c.to_luma()[0] as f32 - luma).powi(2)).collect()
+                                }
+                            };
+                            nearest_with_confidence(&distances, &codes, num_images)
+                        });
+
+                        // We should have a palette index we can work with now.
+                        // Decode it into bits.
+                        //println!("Found palette index {}", palette_index);
+                        for p in 0..num_images {
+                            let plane_bit_is_set = (codes[*palette_index] >> p) & 0x1;
+                            if plane_bit_is_set != 0 {
+                                planes[p].put_pixel(x, y, Rgba([255, 255, 255, 0]));
+                            }
+                            else {
+                                planes[p].put_pixel(x, y, Rgba([0, 0, 0, 0]));
+                            }
+                            confidence_maps[p].put_pixel(x, y, Luma([confidence[p]]));
                         }
                     }
-                    palette_index = closest_index;
                 }
+            }
+        }
 
-                // We should have a palette index we can work with now.
-                // Decode it into bits.
-                //println!("Found palette index {}", palette_index);
-                for p in 0..num_images {
-                    let plane_bit_is_set = ((palette_index as u8) >> p) & 0x1;
-                    if plane_bit_is_set != 0 {
-                        planes[p].put_pixel(x, y, Rgba([255, 255, 255, 0]));
-                    }
-                    else {
-                        planes[p].put_pixel(x, y, Rgba([0, 0, 0, 0]));
+        //println!("Demultiplexed {} color planes", num_images);
+
+        planes.into_iter().zip(confidence_maps).collect()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive_human_output_file::{ArchiveHumanOutputFile, OutputFormat};
+
+    // Index of the palette color which looks closest to the given one.
+    fn nearest_index(palette_lab: &[[f32; 3]], pixel: &Rgb<u8>) -> usize {
+        nearest_with_confidence(&palette_distances(palette_lab, &[], pixel), &[], 0).0
+    }
+
+    // A rough model of what printing and scanning does to colors: ink never gets as dark as black or paper as white as white, colors lose some saturation, and the scanner adds a warm cast.
+    fn print_and_scan(c: &Rgb<u8>) -> Rgb<u8> {
+        let cast = [8.0, 0.0, -10.0];
+        let gray = (c[0] as f32 + c[1] as f32 + c[2] as f32) / 3.0;
+        Rgb([0, 1, 2].map(|i| {
+            let desaturated = gray + (c[i] as f32 - gray) * 0.8;
+            (20.0 + desaturated * 0.85 + cast[i]).round().clamp(0.0, 255.0) as u8
+        }))
+    }
+
+    // Per-row pixel noise directions, so every palette color is also tested slightly off from where it should be.
+    const NOISE: [[i16; 3]; 5] = [[0, 0, 0], [1, -1, 1], [-1, 1, 1], [1, 1, -1], [-1, -1, -1]];
+
+    // Bigger palettes have colors closer together, so they can only be expected to put up with less noise.
+    fn add_noise(c: &Rgb<u8>, row: u32, bits: u8) -> Rgb<u8> {
+        let n = NOISE[row as usize];
+        let amplitude = (64 >> bits).max(1);
+        Rgb([0, 1, 2].map(|i| (c[i] as i16 + n[i] * amplitude).clamp(0, 255) as u8))
+    }
+
+    #[test]
+    fn round_trip_every_palette_size_through_color_shift() {
+        for bits in 1..8 {
+            let num_colors: u8 = 1 << bits;
+            let encoder = ColorMultiplexer::new(num_colors).finalize();
+            assert_eq!(encoder.num_planes(), bits);
+
+            // One column per palette color, one row per noise sample.
+            let width = num_colors as u32;
+            let height = NOISE.len() as u32;
+            let planes: Vec<RgbImage> = (0..bits).map(|p| RgbImage::from_fn(width, height, |x, _y| {
+                if (x >> p) & 1 == 1 { Rgb([255, 255, 255]) } else { Rgb([0, 0, 0]) }
+            })).collect();
+            let printed = encoder.multiplex_planes(planes, 0);
+            let scanned = RgbImage::from_fn(width, height, |x, y| add_noise(&print_and_scan(printed.get_pixel(x, y)), y, bits));
+
+            // The decoder calibrates from the printed swatch, so it sees the palette after the same shift.
+            let mut decoder = ColorMultiplexer::new(num_colors).finalize();
+            decoder.set_palette(encoder.get_rgb().iter().map(print_and_scan).collect());
+            let demuxed = decoder.demultiplex_image(&DynamicImage::ImageRgb8(scanned));
+            assert_eq!(demuxed.len(), bits as usize);
+            for (p, plane) in demuxed.iter().enumerate() {
+                for x in 0..width {
+                    for y in 0..height {
+                        let expected = if (x >> p) & 1 == 1 { 255 } else { 0 };
+                        assert_eq!(plane.get_pixel(x, y)[0], expected, "{} colors, palette index {}, plane {}, noise row {}", num_colors, x, p, y);
                     }
                 }
             }
         }
+    }
 
-        //println!("Demultiplexed {} color planes", num_images);
+    #[test]
+    fn local_palettes_follow_uneven_lighting() {
+        // The top half of the page came out much darker than the bottom, like a phone photo with the light off to one side.
+        let darken = |c: &Rgb<u8>| Rgb([0, 1, 2].map(|i| (c[i] as f32 * 0.5) as u8));
+        let num_colors = 8;
+        let encoder = ColorMultiplexer::new(num_colors).finalize();
+        let (width, height) = (num_colors as u32, 2 * LOCAL_PALETTE_TILE_SIZE);
+        let scanned = RgbImage::from_fn(width, height, |x, y| {
+            let c = encoder.get_rgb()[x as usize];
+            if y < height / 2 { darken(&c) } else { c }
+        });
+        let check = |decoder: &ColorMultiplexer| -> bool {
+            let demuxed = decoder.demultiplex_image(&DynamicImage::ImageRgb8(scanned.clone()));
+            (0..width).all(|x| (0..height).all(|y| demuxed.iter().enumerate().all(|(p, plane)| {
+                plane.get_pixel(x, y)[0] == if (x >> p) & 1 == 1 { 255 } else { 0 }
+            })))
+        };
 
-        planes
+        // Calibrating only from the bottom of the page gets the top wrong.
+        let mut decoder = ColorMultiplexer::new(num_colors).finalize();
+        assert!(!check(&decoder));
+
+        // Swatches at the top and bottom let each half use what the palette looked like there.
+        decoder.local_palettes = vec![
+            LocalPalette { x: 0.5, y: 0.25, colors_rgb: vec![], colors_lab: encoder.get_rgb().iter().map(|c| rgb_to_oklab(&darken(c))).collect() },
+            LocalPalette { x: 0.5, y: 0.75, colors_rgb: vec![], colors_lab: encoder.get_rgb().iter().map(rgb_to_oklab).collect() }
+        ];
+        assert!(check(&decoder));
+    }
+
+    #[test]
+    fn local_palettes_are_found_on_a_printed_page() {
+        // Light falls off from the top of the page to the bottom.
+        let lighting = |y: f32| 1.0 - 0.4 * y;
+        let light = |c: &Rgb<u8>, y: f32| Rgb([0, 1, 2].map(|i| (c[i] as f32 * lighting(y)).round() as u8));
+        let multiplexer = ColorMultiplexer::new(8).finalize();
+        let writer = ArchiveHumanOutputFile::new("", OutputFormat::PNG)
+            .size(6.0, 4.0)
+            .dpi(200)
+            .colors(multiplexer.get_rgb())
+            .finalize();
+        let mut page = writer.build_page(1);
+        let height = page.height() as f32;
+        for (_x, y, p) in page.enumerate_pixels_mut() {
+            *p = light(p, y as f32 / height);
+        }
+
+        let local_palettes = multiplexer.find_local_palettes(&DynamicImage::ImageRgb8(page));
+        // The header and footer text is short enough to leave room for a swatch in the middle of each as well as on the right.
+        assert_eq!(local_palettes.len(), 4);
+        assert_eq!(local_palettes.iter().filter(|l| l.y < 0.5).count(), 2);
+        for local in &local_palettes {
+            // Black comes from the palette, since labeled swatches don't show the black border between patches, so check the colors and the paper.
+            for (c, (found, printed)) in local.colors_rgb.iter().zip(multiplexer.get_rgb().iter()).enumerate().skip(1) {
+                let expected = light(printed, local.y);
+                assert!((0..3).all(|i| (found[i] as i32 - expected[i] as i32).abs() <= 8), "color {} of the swatch at {}, {} came out {:?}, not {:?}", c, local.x, local.y, found, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn hue_wraps_around() {
+        // A slightly magenta red is much closer to red than to magenta, even though its hue angle is numerically far from red's.
+        let multiplexer = ColorMultiplexer::new(8).finalize();
+        let red_index = multiplexer.get_rgb().iter().position(|c| *c == Rgb([255, 0, 0])).unwrap();
+        let (r, g, b) = HSL { h: 350.0, s: 1.0, l: 0.5 }.to_rgb();
+        assert_eq!(nearest_index(&multiplexer.colors_lab, &Rgb([r, g, b])), red_index);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn light_and_dark_colors_are_distinct() {
+        // Above 8 colors, neighboring hues alternate between lighter and darker, so lightness has to count.
+        let multiplexer = ColorMultiplexer::new(16).finalize();
+        for (i, c) in multiplexer.get_rgb().iter().enumerate() {
+            assert_eq!(nearest_index(&multiplexer.colors_lab, c), i);
+        }
+    }
+
+    #[test]
+    fn cmyk_planes_unmix_from_ink_densities() {
+        // Every combination of inks, printed a little dimmer than the paper white we calibrated against.
+        let encoder = ColorMultiplexer::new(2).mode(MultiplexMode::Cmyk).finalize();
+        assert_eq!(encoder.num_planes(), 4);
+        let (width, height) = (16, 4);
+        let planes: Vec<RgbImage> = (0..4).map(|p| RgbImage::from_fn(width, height, |x, _y| {
+            if (x >> p) & 1 == 1 { Rgb([255, 255, 255]) } else { Rgb([0, 0, 0]) }
+        })).collect();
+        let printed = encoder.multiplex_planes(planes, 0);
+        let scanned = RgbImage::from_fn(width, height, |x, y| Rgb(printed.get_pixel(x, y).0.map(|c| (c as f32 * 0.9) as u8)));
+
+        let mut decoder = ColorMultiplexer::new(2).mode(MultiplexMode::Cmyk).finalize();
+        decoder.set_palette(encoder.get_rgb().iter().map(|c| Rgb(c.0.map(|v| (v as f32 * 0.9) as u8))).collect());
+        let demuxed = decoder.demultiplex_image(&DynamicImage::ImageRgb8(scanned));
+        for (p, plane) in demuxed.iter().enumerate() {
+            for x in 0..width {
+                let expected = if (x >> p) & 1 == 1 { 255 } else { 0 };
+                assert_eq!(plane.get_pixel(x, 0)[0], expected, "inks {:?}, plane {}", cmyk_palette_inks()[x as usize], p);
+            }
+        }
+    }
+
+    #[test]
+    fn confidence_drops_between_colors() {
+        // Black and red only differ in the first plane, so on a ramp between them the first plane should only be unsure where it switches over.
+        let multiplexer = ColorMultiplexer::new(8).finalize();
+        assert_eq!(multiplexer.get_rgb()[0], Rgb([0, 0, 0]));
+        assert_eq!(multiplexer.get_rgb()[1], Rgb([255, 0, 0]));
+        let ramp = RgbImage::from_fn(256, 1, |x, _y| Rgb([x as u8, 0, 0]));
+        let demuxed = multiplexer.demultiplex_image_with_confidence(&DynamicImage::ImageRgb8(ramp));
+        let switch = (0..256).find(|x| demuxed[0].0.get_pixel(*x, 0)[0] != 0).unwrap();
+        let unsure = demuxed[0].1.get_pixel(switch, 0)[0];
+        assert!(unsure < 8, "confidence {} at {}", unsure, switch);
+        for (p, (_plane, confidence)) in demuxed.iter().enumerate() {
+            assert_eq!(confidence.get_pixel(0, 0)[0], 255, "plane {}", p);
+            assert_eq!(confidence.get_pixel(255, 0)[0], 255, "plane {}", p);
+            if p != 0 {
+                assert!(confidence.get_pixel(switch, 0)[0] > unsure + 16, "plane {} confidence {}", p, confidence.get_pixel(switch, 0)[0]);
+            }
+        }
+    }
+
+    #[test]
+    fn only_palette_sizes_which_add_a_plane_are_kept() {
+        assert_eq!(usable_num_colors(3), 3);
+        assert_eq!(usable_num_colors(5), 4);
+        assert_eq!(usable_num_colors(6), 6);
+        assert_eq!(usable_num_colors(9), 8);
+        assert_eq!(usable_num_colors(12), 12);
+        assert_eq!(usable_num_colors(16), 16);
+        let multiplexer = ColorMultiplexer::new(6).finalize();
+        assert_eq!(multiplexer.num_colors(), 6);
+        assert_eq!(multiplexer.pixels_per_group(), 2);
+        assert_eq!(multiplexer.num_planes(), 5);
+        assert_eq!(multiplexer.num_demultiplexed_planes(), 3);
+    }
+
+    #[test]
+    fn grouped_planes_survive_multiplexing() {
+        for num_colors in [3, 6, 7, 12] {
+            let multiplexer = ColorMultiplexer::new(num_colors).finalize();
+            let num_planes = multiplexer.num_planes() as u32;
+            let spacing = 3;
+
+            // One column for every combination of plane bits.
+            let width = 1 << num_planes;
+            let planes: Vec<RgbImage> = (0..num_planes).map(|p| RgbImage::from_fn(width, 1, |x, _y| {
+                if (x >> p) & 1 == 1 { Rgb([255, 255, 255]) } else { Rgb([0, 0, 0]) }
+            })).collect();
+            let printed = multiplexer.multiplex_planes(planes, spacing);
+            assert_eq!(printed.width(), width * 2 + spacing);
+
+            // Finder patterns have to stay black and white in both barcodes of the pair.
+            let white = multiplexer.get_rgb()[num_colors as usize - 1];
+            assert_eq!(*printed.get_pixel(0, 0), Rgb([0, 0, 0]));
+            assert_eq!(*printed.get_pixel(width + spacing, 0), Rgb([0, 0, 0]));
+            assert_eq!(*printed.get_pixel(width - 1, 0), white);
+            assert_eq!(*printed.get_pixel(width * 2 + spacing - 1, 0), white);
+
+            // Read each barcode's modules back out of the demultiplexed planes, the way the recognizer samples them.
+            let demuxed = multiplexer.demultiplex_image(&DynamicImage::ImageRgb8(printed));
+            let modules: Vec<Vec<Vec<u8>>> = (0..2).map(|t| demuxed.iter().map(|plane| {
+                (0..width).map(|x| plane.get_pixel(x + t * (width + spacing), 0)[0]).collect()
+            }).collect()).collect();
+            let ungrouped = multiplexer.ungroup_modules(&modules);
+            assert_eq!(ungrouped.len(), num_planes as usize);
+            for (p, plane) in ungrouped.iter().enumerate() {
+                for x in 0..width {
+                    let expected = if (x >> p) & 1 == 1 { 255 } else { 0 };
+                    assert_eq!(plane[x as usize], expected, "{} colors, value {}, plane {}", num_colors, x, p);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn custom_palettes_put_lookalike_colors_one_bit_apart() {
+        let pairs = [[Rgb([230, 30, 30]), Rgb([200, 40, 30])], [Rgb([30, 160, 40]), Rgb([60, 180, 50])], [Rgb([30, 40, 200]), Rgb([50, 70, 230])]];
+        let mut colors = vec![Rgb([250, 248, 240]), Rgb([20, 20, 25])];
+        // Split the pairs up in the order given, so they have to be moved to end up next to each other.
+        colors.extend(pairs.iter().map(|p| p[0]));
+        colors.extend(pairs.iter().map(|p| p[1]));
+        let multiplexer = ColorMultiplexer::new(2).colors(&colors).finalize();
+        let ordered = multiplexer.get_rgb();
+        assert_eq!(multiplexer.num_colors(), 8);
+        assert_eq!(ordered[0], Rgb([20, 20, 25]));
+        assert_eq!(ordered[7], Rgb([250, 248, 240]));
+        for pair in pairs {
+            let a = ordered.iter().position(|c| *c == pair[0]).unwrap();
+            let b = ordered.iter().position(|c| *c == pair[1]).unwrap();
+            assert_eq!((a ^ b).count_ones(), 1);
+        }
+    }
+
+    #[test]
+    fn gray_levels_are_learned_from_a_washed_out_page() {
+        // Every combination of two planes, printed and scanned with the gray levels squeezed together.
+        let multiplexer = ColorMultiplexer::new(4).mode(MultiplexMode::Grayscale).finalize();
+        let planes: Vec<RgbImage> = (0..2).map(|p| RgbImage::from_fn(40, 4, |x, _y| {
+            if ((x / 10) >> p) & 1 == 1 { Rgb([255, 255, 255]) } else { Rgb([0, 0, 0]) }
+        })).collect();
+        let printed = multiplexer.multiplex_planes(planes, 0);
+        let scanned = RgbImage::from_fn(40, 4, |x, y| {
+            let l = 40 + printed.get_pixel(x, y)[0] as i16 * 3 / 4 + if y % 2 == 0 { 4 } else { -4 };
+            Rgb([l as u8, l as u8, l as u8])
+        });
+        let scanned = DynamicImage::ImageRgb8(scanned);
+        assert_eq!(learn_gray_levels(&scanned, &GRAY_LEVELS), vec![40, 74, 158, 231]);
+
+        let mut learned = ColorMultiplexer::new(4).mode(MultiplexMode::Grayscale).finalize();
+        learned.palettize_from_image(&scanned, &[]);
+        let demuxed = learned.demultiplex_image(&scanned);
+        assert_eq!(demuxed.len(), 2);
+        for x in 0..40 {
+            for (p, plane) in demuxed.iter().enumerate() {
+                let expected = if ((x / 10) >> p) & 1 == 1 { 255 } else { 0 };
+                assert_eq!(plane.get_pixel(x, 0)[0], expected, "value {}, plane {}", x / 10, p);
+            }
+        }
+    }
+}
@@ -0,0 +1,201 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0+ OR Zlib
+
+use crate::color_multiplexer::{MultiplexMode, rgb_to_oklab, usable_num_colors};
+use image::Rgb;
+use std::fs;
+use std::path::Path;
+
+// How a particular printer and scanner reproduce each palette color, measured from a scanned calibration sheet.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ColorProfile {
+    pub name: String,
+    pub mode: MultiplexMode,
+    // Colors as they were sent to the printer, in palette order.
+    pub printed: Vec<Rgb<u8>>,
+    // Average of each color as it came back from the scanner.
+    pub measured: Vec<Rgb<u8>>,
+    // How much each color varies as scanned, as a covariance matrix in OKLab.
+    pub covariance: Vec<[[f32; 3]; 3]>,
+    // Colors to print instead, so that they scan closer to the colors we asked for.  Only worked out when asked for.
+    pub predistorted: Option<Vec<Rgb<u8>>>
+}
+
+fn hex_color(c: &Rgb<u8>) -> String {
+    format!("{:02x}{:02x}{:02x}", c[0], c[1], c[2])
+}
+
+pub fn parse_hex_color(s: &str) -> Result<Rgb<u8>, &'static str> {
+    if s.len() != 6 {
+        return Err("Colors must be 6 hex digits");
+    }
+    let channel = |i: usize| u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).map_err(|_e| "Colors must be 6 hex digits");
+    Ok(Rgb([channel(0)?, channel(1)?, channel(2)?]))
+}
+
+impl ColorProfile {
+    // Builds a profile from the pixels scanned for each palette color.
+    pub fn from_samples(name: &str, mode: MultiplexMode, printed: &[Rgb<u8>], samples: &[Vec<Rgb<u8>>], predistort: bool) -> Result<ColorProfile, &'static str> {
+        if samples.len() != printed.len() || samples.iter().any(|s| s.is_empty()) {
+            return Err("Every color needs to be sampled at least once");
+        }
+        let measured: Vec<Rgb<u8>> = samples.iter().map(|s| {
+            Rgb([0, 1, 2].map(|i| (s.iter().map(|c| c[i] as f32).sum::<f32>() / s.len() as f32).round() as u8))
+        }).collect();
+        let covariance = samples.iter().map(|s| {
+            let lab: Vec<[f32; 3]> = s.iter().map(rgb_to_oklab).collect();
+            let mean = [0, 1, 2].map(|i| lab.iter().map(|l| l[i]).sum::<f32>() / lab.len() as f32);
+            [0, 1, 2].map(|i| [0, 1, 2].map(|j| lab.iter().map(|l| (l[i] - mean[i]) * (l[j] - mean[j])).sum::<f32>() / lab.len() as f32))
+        }).collect();
+
+        // Inks can't be made any purer than they are, so only palette colors can be predistorted.
+        // Correct each color by however far it was off, after scaling the scan so its paper white counts as white.
+        // One round of this gets most of the way there, and anything it can't reach just gets clamped.
+        let predistorted = if predistort && mode == MultiplexMode::Palette {
+            let white = measured[measured.len() - 1];
+            Some(printed.iter().zip(measured.iter()).map(|(p, m)| {
+                Rgb([0, 1, 2].map(|i| {
+                    let normalized = m[i] as f32 * 255.0 / white[i].max(1) as f32;
+                    (p[i] as f32 * 2.0 - normalized).round().clamp(0.0, 255.0) as u8
+                }))
+            }).collect())
+        }
+        else {
+            None
+        };
+
+        Ok(ColorProfile {
+            name: name.to_string(),
+            mode,
+            printed: printed.to_vec(),
+            measured,
+            covariance,
+            predistorted
+        })
+    }
+
+    // Colors for the encoder to print with.
+    pub fn encoding_colors(&self) -> &Vec<Rgb<u8>> {
+        self.predistorted.as_ref().unwrap_or(&self.printed)
+    }
+
+    // Colors the decoder should expect to see.
+    // Predistorted colors should scan as far from what we measured as they were moved from what we printed, which is the same model that predistortion itself uses.
+    // Colors which couldn't be moved because they were already as strong as they go still scan as measured.
+    pub fn decoding_colors(&self) -> Vec<Rgb<u8>> {
+        match &self.predistorted {
+            Some(predistorted) => {
+                let white = self.measured[self.measured.len() - 1];
+                self.measured.iter().zip(predistorted.iter().zip(self.printed.iter())).map(|(m, (d, p))| {
+                    Rgb([0, 1, 2].map(|i| (m[i] as f32 + (d[i] as f32 - p[i] as f32) * white[i] as f32 / 255.0).round().clamp(0.0, 255.0) as u8))
+                }).collect()
+            },
+            None => self.measured.clone()
+        }
+    }
+
+    // Profiles are stored as plain text, one color per line, so they can be checked over or tweaked by hand.
+    pub fn save(&self, filename: &str) -> std::io::Result<()> {
+        let mut out = String::from("# Real World Archive color profile\n");
+        out += &format!("name {}\n", self.name);
+        out += &format!("mode {}\n", match self.mode {
+            MultiplexMode::Palette => "palette",
+            MultiplexMode::Cmyk => "cmyk",
+            MultiplexMode::Grayscale => "grayscale"
+        });
+        out += "# color <printed> <measured> <predistorted or -> <OKLab covariance: LL La Lb aa ab bb>\n";
+        for c in 0..self.printed.len() {
+            let predistorted = self.predistorted.as_ref().map(|p| hex_color(&p[c])).unwrap_or("-".to_string());
+            let m = &self.covariance[c];
+            out += &format!("color {} {} {} {:e} {:e} {:e} {:e} {:e} {:e}\n", hex_color(&self.printed[c]), hex_color(&self.measured[c]), predistorted, m[0][0], m[0][1], m[0][2], m[1][1], m[1][2], m[2][2]);
+        }
+        fs::write(filename, out)
+    }
+
+    pub fn load(filename: &str) -> Result<ColorProfile, &'static str> {
+        let text = fs::read_to_string(filename).map_err(|_e| "Could not read color profile")?;
+        let mut profile = ColorProfile {
+            name: Path::new(filename).file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default(),
+            mode: MultiplexMode::Palette,
+            printed: vec![],
+            measured: vec![],
+            covariance: vec![],
+            predistorted: Some(vec![])
+        };
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "name" => profile.name = value.to_string(),
+                "mode" => profile.mode = match value {
+                    "palette" => MultiplexMode::Palette,
+                    "cmyk" => MultiplexMode::Cmyk,
+                    "grayscale" => MultiplexMode::Grayscale,
+                    _ => return Err("Unknown multiplexing mode in color profile")
+                },
+                "color" => {
+                    let fields: Vec<&str> = value.split_whitespace().collect();
+                    if fields.len() != 9 {
+                        return Err("Color profile lines need 9 fields");
+                    }
+                    profile.printed.push(parse_hex_color(fields[0])?);
+                    profile.measured.push(parse_hex_color(fields[1])?);
+                    if fields[2] == "-" {
+                        profile.predistorted = None;
+                    }
+                    else if let Some(p) = profile.predistorted.as_mut() {
+                        p.push(parse_hex_color(fields[2])?);
+                    }
+                    let mut m = [0.0; 6];
+                    for (v, f) in m.iter_mut().zip(&fields[3..]) {
+                        *v = f.parse::<f32>().map_err(|_e| "Bad covariance in color profile")?;
+                    }
+                    profile.covariance.push([[m[0], m[1], m[2]], [m[1], m[3], m[4]], [m[2], m[4], m[5]]]);
+                },
+                _ => return Err("Unknown line in color profile")
+            }
+        }
+        if profile.printed.len() < 2 || profile.printed.len() > u8::MAX as usize || usable_num_colors(profile.printed.len() as u8) as usize != profile.printed.len() {
+            return Err("Color profiles need a number of colors we can multiplex with");
+        }
+        Ok(profile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles_survive_saving_and_loading() {
+        let printed = vec![Rgb([0, 0, 0]), Rgb([255, 0, 0]), Rgb([0, 128, 255]), Rgb([255, 255, 255])];
+        let samples: Vec<Vec<Rgb<u8>>> = printed.iter().map(|c| {
+            (0..4).map(|i| Rgb(c.0.map(|v| (v as f32 * 0.8 + 20.0 + i as f32) as u8))).collect()
+        }).collect();
+        let profile = ColorProfile::from_samples("test", MultiplexMode::Palette, &printed, &samples, true).unwrap();
+        assert_eq!(profile.measured[1], Rgb([226, 22, 22]));
+        // The scan is washed out, so the half-strength green has to be printed darker to come back at half strength.
+        let predistorted = profile.predistorted.as_ref().unwrap();
+        assert!(predistorted[2][1] < 128);
+        assert_eq!(predistorted[3], Rgb([255, 255, 255]));
+
+        let filename = std::env::temp_dir().join(format!("rwa-profile-test-{}.txt", std::process::id()));
+        let filename = filename.to_str().unwrap();
+        profile.save(filename).unwrap();
+        let loaded = ColorProfile::load(filename).unwrap();
+        fs::remove_file(filename).unwrap();
+        assert_eq!(loaded.name, profile.name);
+        assert_eq!(loaded.printed, profile.printed);
+        assert_eq!(loaded.measured, profile.measured);
+        assert_eq!(loaded.predistorted, profile.predistorted);
+        for (a, b) in loaded.covariance.iter().zip(profile.covariance.iter()) {
+            for i in 0..3 {
+                for j in 0..3 {
+                    assert!((a[i][j] - b[i][j]).abs() <= b[i][j].abs() * 1e-5);
+                }
+            }
+        }
+    }
+}
@@ -2,6 +2,7 @@
 
 use std::fs::File;
 use positioned_io::{ReadAt, WriteAt};
+use sha2::{Digest, Sha256};
 
 pub struct DataFile<'a> {
     in_file: &'a str,
@@ -71,6 +72,22 @@ impl<'a> DataFile<'a> {
         self.file_hash
     }
 
+    /// Returns the SHA-256 digest of the whole file, for a much stronger check than the file hash
+    pub fn sha256(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        let mut start_pos = 0;
+        let mut buf = vec![0; 1024 * 1024];
+        while start_pos < self.stream_len() {
+            let num_bytes = self.get_chunk(start_pos, &mut buf);
+            if num_bytes == 0 {
+                break;
+            }
+            hasher.update(&buf[0..num_bytes]);
+            start_pos += num_bytes as u64;
+        }
+        hasher.finalize().into()
+    }
+
     /// Reads a chunk of data from the file and returns the actual read length
     pub fn get_chunk(&self, pos: u64, buf: &mut [u8]) -> usize {
         return self.file.read_at(pos, buf).unwrap();
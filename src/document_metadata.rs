@@ -0,0 +1,294 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0+ OR Zlib
+
+use crate::color_multiplexer::MultiplexMode;
+
+// Version of the metadata body layout, stored as its first byte.
+// Version 2 added the multiplexing mode.
+// Version 3 added an optional SHA-256 digest of the whole file.  Metadata without one is still written as version 2, so older decoders can read it.
+const METADATA_VERSION: u8 = 3;
+
+// Fixed-length fields at the start of the body, before the variable-length strings, for each metadata version.
+fn fixed_length(version: u8) -> usize {
+    match version {
+        1 => 1 + 1 + 2 + 4 + 4 + 1 + 1 + 1 + 1,
+        2 => 1 + 1 + 2 + 4 + 4 + 1 + 1 + 1 + 1 + 1,
+        _ => 1 + 1 + 2 + 4 + 4 + 1 + 1 + 1 + 1 + 1 + 1
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum EcFunction {
+    Constant,
+    Radial
+}
+
+// Describes how a document was encoded.  Stored in a dedicated monochrome barcode on every page so decoders can configure themselves.
+// There's deliberately no timestamp - the same input and options have to encode to exactly the same pages, so archives can be checked with --verifyreproducible.
+#[derive(Clone, PartialEq, Debug)]
+pub struct DocumentMetadata {
+    // User-supplied title.  Never derived from the input filename.
+    pub title: String,
+    // Filled in from the barcode header when decoding, since it's already stored there.
+    pub total_length: u64,
+    pub num_colors: u8,
+    pub multiplex_mode: MultiplexMode,
+    pub dpi: u16,
+    // Page size, in thousandths of an inch.
+    pub page_width: u32,
+    pub page_height: u32,
+    pub ec_function: EcFunction,
+    // Error correction range as a percentage, matching the --ecmin and --ecmax options.
+    pub ec_min: u8,
+    pub ec_max: u8,
+    pub parity_pages: u8,
+    pub encoder_version: String,
+    // SHA-256 of the whole file, for when the 24-bit hash in every barcode isn't enough assurance the file was rebuilt exactly.
+    pub sha256: Option<[u8; 32]>
+}
+
+impl DocumentMetadata {
+    pub fn new(title: &str) -> DocumentMetadata {
+        DocumentMetadata {
+            title: title.to_string(),
+            total_length: 0,
+            num_colors: 2,
+            multiplex_mode: MultiplexMode::Palette,
+            dpi: 300,
+            page_width: 8500,
+            page_height: 11000,
+            ec_function: EcFunction::Radial,
+            ec_min: 25,
+            ec_max: 100,
+            parity_pages: 0,
+            encoder_version: env!("CARGO_PKG_VERSION").to_string(),
+            sha256: None
+        }
+    }
+
+    pub fn total_length(mut self, l: u64) -> Self {
+        self.total_length = l;
+        self
+    }
+
+    pub fn num_colors(mut self, c: u8) -> Self {
+        self.num_colors = c;
+        self
+    }
+
+    pub fn multiplex_mode(mut self, m: MultiplexMode) -> Self {
+        self.multiplex_mode = m;
+        self
+    }
+
+    pub fn dpi(mut self, d: u16) -> Self {
+        self.dpi = d;
+        self
+    }
+
+    pub fn page_size(mut self, width: f32, height: f32) -> Self {
+        self.page_width = (width * 1000.0).round() as u32;
+        self.page_height = (height * 1000.0).round() as u32;
+        self
+    }
+
+    pub fn error_correction(mut self, f: EcFunction, min: u8, max: u8) -> Self {
+        self.ec_function = f;
+        self.ec_min = min;
+        self.ec_max = max;
+        self
+    }
+
+    pub fn parity_pages(mut self, p: u8) -> Self {
+        self.parity_pages = p;
+        self
+    }
+
+    pub fn encoder_version(mut self, v: &str) -> Self {
+        self.encoder_version = v.to_string();
+        self
+    }
+
+    pub fn sha256(mut self, d: Option<[u8; 32]>) -> Self {
+        self.sha256 = d;
+        self
+    }
+
+    pub fn finalize(self) -> DocumentMetadata {
+        DocumentMetadata {
+            title: self.title,
+            total_length: self.total_length,
+            num_colors: self.num_colors,
+            multiplex_mode: self.multiplex_mode,
+            dpi: self.dpi,
+            page_width: self.page_width,
+            page_height: self.page_height,
+            ec_function: self.ec_function,
+            ec_min: self.ec_min,
+            ec_max: self.ec_max,
+            parity_pages: self.parity_pages,
+            encoder_version: self.encoder_version,
+            sha256: self.sha256
+        }
+    }
+
+    // Serializes the metadata body, truncating the title if needed so it fits in the given number of bytes.
+    pub fn to_bytes(&self, max_length: usize) -> Vec<u8> {
+        let mut out: Vec<u8> = vec![];
+        out.push(if self.sha256.is_some() { METADATA_VERSION } else { 2 });
+        out.push(self.num_colors);
+        out.extend_from_slice(&self.dpi.to_be_bytes());
+        out.extend_from_slice(&self.page_width.to_be_bytes());
+        out.extend_from_slice(&self.page_height.to_be_bytes());
+        out.push(match self.ec_function {
+            EcFunction::Constant => 0,
+            EcFunction::Radial => 1
+        });
+        out.push(self.ec_min);
+        out.push(self.ec_max);
+        out.push(self.parity_pages);
+        out.push(match self.multiplex_mode {
+            MultiplexMode::Palette => 0,
+            MultiplexMode::Cmyk => 1,
+            MultiplexMode::Grayscale => 2
+        });
+        if let Some(digest) = &self.sha256 {
+            // Digest - length-prefixed, so other kinds of digest could be added later.
+            out.push(digest.len() as u8);
+            out.extend_from_slice(digest);
+        }
+
+        // Encoder version - length-prefixed UTF-8.
+        let version_bytes = self.encoder_version.as_bytes();
+        out.push(version_bytes.len() as u8);
+        out.extend_from_slice(version_bytes);
+
+        // Title - length-prefixed UTF-8, cut at a character boundary if there isn't room for all of it.
+        let room_for_title = max_length.saturating_sub(out.len() + 2).min(u16::MAX as usize);
+        let mut title_length = self.title.len().min(room_for_title);
+        while !self.title.is_char_boundary(title_length) {
+            title_length -= 1;
+        }
+        out.extend_from_slice(&(title_length as u16).to_be_bytes());
+        out.extend_from_slice(&self.title.as_bytes()[0..title_length]);
+
+        out
+    }
+
+    pub fn from_bytes(data: &[u8], total_length: u64) -> Result<DocumentMetadata, &'static str> {
+        if data.is_empty() {
+            return Err("Metadata is too short");
+        }
+        let version = data[0];
+        if version == 0 || version > METADATA_VERSION {
+            return Err("Unsupported metadata version");
+        }
+        let fixed_length = fixed_length(version);
+        if data.len() < fixed_length + 1 {
+            return Err("Metadata is too short");
+        }
+        let ec_function = match data[12] {
+            0 => EcFunction::Constant,
+            1 => EcFunction::Radial,
+            _ => return Err("Unknown error correction function")
+        };
+        let multiplex_mode = match version {
+            1 => MultiplexMode::Palette,
+            _ => match data[16] {
+                0 => MultiplexMode::Palette,
+                1 => MultiplexMode::Cmyk,
+                2 => MultiplexMode::Grayscale,
+                _ => return Err("Unknown multiplexing mode")
+            }
+        };
+
+        let mut sha256 = None;
+        let mut digest_end = fixed_length;
+        if version >= 3 {
+            let digest_length = data[17] as usize;
+            if digest_length != 32 {
+                return Err("Unsupported digest");
+            }
+            digest_end += digest_length;
+            if data.len() < digest_end + 1 {
+                return Err("Metadata is too short");
+            }
+            sha256 = Some(data[fixed_length..digest_end].try_into().unwrap());
+        }
+
+        let version_length = data[digest_end] as usize;
+        let version_start = digest_end + 1;
+        let title_length_start = version_start + version_length;
+        if data.len() < title_length_start + 2 {
+            return Err("Metadata is too short");
+        }
+        let title_length = u16::from_be_bytes([data[title_length_start], data[title_length_start + 1]]) as usize;
+        let title_start = title_length_start + 2;
+        if data.len() < title_start + title_length {
+            return Err("Metadata is too short");
+        }
+
+        Ok(DocumentMetadata {
+            title: String::from_utf8_lossy(&data[title_start..(title_start + title_length)]).to_string(),
+            total_length,
+            num_colors: data[1],
+            multiplex_mode,
+            dpi: u16::from_be_bytes([data[2], data[3]]),
+            page_width: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+            page_height: u32::from_be_bytes([data[8], data[9], data[10], data[11]]),
+            ec_function,
+            ec_min: data[13],
+            ec_max: data[14],
+            parity_pages: data[15],
+            encoder_version: String::from_utf8_lossy(&data[version_start..title_length_start]).to_string(),
+            sha256
+        })
+    }
+
+    pub fn print_report(&self) {
+        println!("Document metadata:");
+        if !self.title.is_empty() {
+            println!("- Title: {}", self.title);
+        }
+        println!("- Original size: {} bytes", self.total_length);
+        match self.multiplex_mode {
+            MultiplexMode::Palette => println!("- Colors: {}", self.num_colors),
+            MultiplexMode::Cmyk => println!("- Colors: CMYK inks"),
+            MultiplexMode::Grayscale => println!("- Colors: {} gray levels", self.num_colors)
+        };
+        println!("- DPI: {}", self.dpi);
+        println!("- Page size: {}x{} in", self.page_width as f32 / 1000.0, self.page_height as f32 / 1000.0);
+        match self.ec_function {
+            EcFunction::Constant => println!("- Error correction: constant at {}%", self.ec_min),
+            EcFunction::Radial => println!("- Error correction: radial from {}% to {}%", self.ec_min, self.ec_max)
+        };
+        println!("- Parity pages: {}", self.parity_pages);
+        println!("- Encoder version: {}", self.encoder_version);
+        if let Some(digest) = &self.sha256 {
+            println!("- SHA-256: {}", digest.iter().map(|b| format!("{:02x}", b)).collect::<String>());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digest_round_trip() {
+        let metadata = DocumentMetadata::new("Deeds")
+            .num_colors(4)
+            .parity_pages(2)
+            .finalize();
+        let bytes = metadata.to_bytes(200);
+        assert_eq!(bytes[0], 2);
+        assert_eq!(DocumentMetadata::from_bytes(&bytes, 0), Ok(metadata.clone()));
+
+        let digest = [0xa5; 32];
+        let with_digest = metadata.sha256(Some(digest)).finalize();
+        let bytes = with_digest.to_bytes(200);
+        assert_eq!(bytes[0], 3);
+        assert_eq!(bytes[17], 32);
+        assert_eq!(DocumentMetadata::from_bytes(&bytes, 0), Ok(with_digest));
+        assert!(DocumentMetadata::from_bytes(&bytes[0..40], 0).is_err());
+    }
+}
@@ -0,0 +1,314 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0+ OR Zlib
+
+use crate::archive_human_output_file::*;
+use crate::archive_human_input_file::*;
+use crate::color_multiplexer::ColorMultiplexer;
+use crate::document_metadata::{DocumentMetadata, EcFunction};
+use crate::file_decoder::read_barcodes;
+use crate::palette_swatch::find_labeled_swatches;
+use crate::page_barcode_packer::{BarcodeFormat, BarcodeSlot, PageBarcodePacker, make_constant_damage_map, make_radial_damage_map, header_length, parse_barcode_header, barcode_checksum_matches, LATEST_FORMAT_VERSION, BARCODE_LAYOUT_INTERLEAVED_FLAG};
+use crate::pseudorandom::Xorshift32;
+use crate::payload_encoding::{PayloadEncoding, decode_payload};
+use image::{DynamicImage, Rgb, RgbImage};
+
+// Seed for the page's pseudorandom data.
+const DRY_RUN_SEED: u32 = 0x5eed1e55;
+
+// How strongly the heatmap tints each barcode, out of 1.
+const HEATMAP_OPACITY: f32 = 0.5;
+
+// What reading a dry run page found.
+struct DryRunReport {
+    // The page laid out again, with every barcode tinted by how much of it was read.
+    heatmap: RgbImage,
+    planes_read: usize,
+    total_planes: usize,
+    metadata_read: bool,
+    // Barcodes which read but held the wrong data.
+    misreads: u32,
+    // Row, column, and what went wrong for every barcode which wasn't wholly read, counting from the top left, in reading order.
+    failed: Vec<(usize, usize, String)>
+}
+
+// Encodes a single page of known pseudorandom data exactly the way a document would be encoded with the same settings, so a scan of it shows which barcode positions fail in practice.
+pub struct DryRunPage {
+    format_version: u8,
+    payload_encoding: PayloadEncoding,
+    interleave_planes: bool,
+    include_metadata: bool
+}
+
+impl DryRunPage {
+    pub fn new() -> DryRunPage {
+        DryRunPage {
+            format_version: LATEST_FORMAT_VERSION,
+            payload_encoding: PayloadEncoding::Base45,
+            interleave_planes: false,
+            include_metadata: true
+        }
+    }
+
+    // When decoding, these are only used if the barcodes don't say.
+    pub fn format_version(mut self, v: u8) -> Self {
+        self.format_version = v;
+        self
+    }
+
+    pub fn payload_encoding(mut self, e: PayloadEncoding) -> Self {
+        self.payload_encoding = e;
+        self
+    }
+
+    pub fn interleave_planes(mut self, i: bool) -> Self {
+        self.interleave_planes = i;
+        self
+    }
+
+    pub fn include_metadata(mut self, m: bool) -> Self {
+        self.include_metadata = m;
+        self
+    }
+
+    pub fn finalize(self) -> DryRunPage {
+        DryRunPage {
+            format_version: self.format_version,
+            payload_encoding: self.payload_encoding,
+            interleave_planes: self.interleave_planes,
+            include_metadata: self.include_metadata
+        }
+    }
+
+    // The same data every time, so the decoder can check every byte.
+    fn page_data(length: u32) -> Vec<u8> {
+        let mut rng = Xorshift32::new(DRY_RUN_SEED);
+        (0..length).map(|_| (rng.next_u32() >> 24) as u8).collect()
+    }
+
+    // Barcodes only record the low 24 bits of the checksum, and the order barcodes are shuffled in depends on all of it, so keep it to 24 bits so the decoder can lay the page out again.
+    fn page_checksum(data: &[u8]) -> u32 {
+        crc32fast::hash(data) & 0x00ffffff
+    }
+
+    // Packs a page the same way the encoder would for a document too long to fit on one page.
+    fn make_packer(&self, width: u32, height: u32, color_multiplexer: ColorMultiplexer, metadata: &DocumentMetadata) -> PageBarcodePacker {
+        let ec_min = metadata.ec_min as f32 / 100.0;
+        let ec_max = metadata.ec_max as f32 / 100.0;
+        let mut packer = PageBarcodePacker::new(width, height, BarcodeFormat::QR)
+            .color_multiplexer(color_multiplexer)
+            .document_metadata(if self.include_metadata { Some(metadata.clone()) } else { None })
+            .format_version(self.format_version)
+            .payload_encoding(self.payload_encoding)
+            .interleave_planes(self.interleave_planes)
+            .damage_likelihood_map(match metadata.ec_function {
+                EcFunction::Constant => make_constant_damage_map(ec_min),
+                EcFunction::Radial => make_radial_damage_map(ec_min, ec_max)
+            })
+            .finalize();
+        let min_bytes_per_page = packer.data_bytes_per_page();
+        while packer.repack_barcodes_for_page_length(min_bytes_per_page) {};
+        packer.set_page_counts(1, 0);
+        packer
+    }
+
+    pub fn encode(&self, writer: &ArchiveHumanOutputFile, color_multiplexer: ColorMultiplexer, metadata: &DocumentMetadata) {
+        let (w, h) = writer.get_barcode_image_size();
+        let packer = self.make_packer(w, h, color_multiplexer, metadata);
+        let data = DryRunPage::page_data(packer.data_bytes_per_page());
+        println!("Generating dry run page with {} bytes of data...", data.len());
+        let mut out_image = RgbImage::new(w, h);
+        packer.encode(&mut out_image, 1, false, 0, DryRunPage::page_checksum(&data), 0, data.len() as u64, &data);
+        writer.write_page(&out_image, 1);
+    }
+
+    // Reads a scanned dry run page and reports which barcodes couldn't be read, saving the page with every barcode tinted by how much of it was read to the output file.
+    // The metadata describes how the page was encoded, either as read from its metadata barcode or as given on the command line.
+    pub fn decode(&self, reader: &ArchiveHumanInputFile, mut color_multiplexer: ColorMultiplexer, metadata: &DocumentMetadata, adjust_colors: bool, out_file: &str) {
+        println!("Reading image");
+        let image = reader.read_page().unwrap();
+        if adjust_colors {
+            color_multiplexer.palettize_from_image(&image, &find_labeled_swatches(&image));
+        }
+        let width_inches = metadata.page_width as f32 / 1000.0;
+        let height_inches = metadata.page_height as f32 / 1000.0;
+        let barcode_image_size = ArchiveHumanOutputFile::new(out_file, OutputFormat::PNG)
+            .size(width_inches, height_inches)
+            .dpi(metadata.dpi)
+            .get_barcode_image_size();
+        let report = self.check_page(&image, color_multiplexer, metadata, barcode_image_size);
+
+        println!("Read {} of {} barcode planes", report.planes_read, report.total_planes);
+        if !self.include_metadata {
+            println!("- No metadata barcode was expected");
+        }
+        else if !report.metadata_read {
+            println!("- The metadata barcode could not be read");
+        }
+        if report.misreads > 0 {
+            println!("- {} barcodes read with the wrong data", report.misreads);
+        }
+        if report.failed.is_empty() {
+            println!("Every barcode was read!");
+        }
+        else {
+            println!("Barcodes which failed, counting from the top left:");
+            for (row, column, what) in &report.failed {
+                println!("- Row {}, column {} ({})", row, column, what);
+            }
+        }
+        report.heatmap.save(out_file).expect("Could not save heatmap");
+        println!("Saved heatmap to {}", out_file);
+    }
+
+    // Works out which barcodes on a dry run page were read, given the size of the area the encoder put barcodes in.
+    fn check_page(&self, image: &DynamicImage, color_multiplexer: ColorMultiplexer, metadata: &DocumentMetadata, (w, h): (u32, u32)) -> DryRunReport {
+        // Go by what the barcodes say over what we were told, where they say anything.
+        let mut format_version = self.format_version;
+        let mut payload_encoding = self.payload_encoding;
+        let mut interleave_planes = self.interleave_planes;
+        let mut metadata_read = false;
+        let mut data_chunks: Vec<(u64, u32, Vec<u8>)> = vec![];
+        // Barcodes which read but hold the wrong data count as failures, too.
+        let mut misreads = 0;
+        for chunk in read_barcodes(&color_multiplexer, image, true).0 {
+            let data_chunk = match decode_payload(&chunk) {
+                Ok(d) => d,
+                _ => continue
+            };
+            let header = match parse_barcode_header(&data_chunk) {
+                Ok(h) => h,
+                _ => continue
+            };
+            if !barcode_checksum_matches(&data_chunk) {
+                misreads += 1;
+                continue;
+            }
+            if header.is_metadata {
+                metadata_read = true;
+                continue;
+            }
+            format_version = header.format_version;
+            payload_encoding = header.payload_encoding;
+            if format_version >= 2 {
+                interleave_planes = header.layout_id & BARCODE_LAYOUT_INTERLEAVED_FLAG != 0;
+            }
+            data_chunks.push((header.start_offset, header.hash, data_chunk[header_length(format_version) as usize..].to_vec()));
+        }
+
+        // Lay the page out again to see where everything should have been.
+        let detected = DryRunPage {
+            format_version,
+            payload_encoding,
+            interleave_planes,
+            include_metadata: self.include_metadata
+        };
+        let packer = detected.make_packer(w, h, color_multiplexer, metadata);
+        let data = DryRunPage::page_data(packer.data_bytes_per_page());
+        let checksum = DryRunPage::page_checksum(&data);
+        let slots = packer.barcode_slots(1, checksum);
+
+        let mut read_offsets: Vec<u64> = vec![];
+        for (start_offset, hash, payload) in &data_chunks {
+            if *hash != checksum {
+                println!("Warning: found a barcode from a different document, or from a dry run with different settings");
+                continue;
+            }
+            let start = *start_offset as usize;
+            if start + payload.len() <= data.len() && data[start..(start + payload.len())] == payload[..] {
+                read_offsets.push(*start_offset);
+            }
+            else {
+                misreads += 1;
+            }
+        }
+
+        let mut out_image = RgbImage::new(w, h);
+        packer.encode(&mut out_image, 1, false, 0, checksum, 0, data.len() as u64, &data);
+        let total_planes: usize = slots.iter().map(|s| s.plane_offsets.len()).sum();
+        let mut failed: Vec<(usize, usize, String)> = vec![];
+        for (slot, (row, column)) in slots.iter().zip(grid_positions(&slots)) {
+            let tint = if slot.is_metadata {
+                if metadata_read {
+                    Rgb([0, 0, 255])
+                }
+                else {
+                    failed.push((row, column, "metadata".to_string()));
+                    Rgb([255, 0, 0])
+                }
+            }
+            else {
+                let planes_read = slot.plane_offsets.iter().filter(|o| read_offsets.contains(o)).count();
+                if planes_read < slot.plane_offsets.len() {
+                    failed.push((row, column, format!("{} of {} planes read", planes_read, slot.plane_offsets.len())));
+                }
+                // Red for nothing read, through yellow, to green for everything read.
+                let fraction = planes_read as f32 / slot.plane_offsets.len() as f32;
+                Rgb([((1.0 - fraction) * 2.0).min(1.0), (fraction * 2.0).min(1.0), 0.0].map(|c| (c * 255.0) as u8))
+            };
+            for y in slot.y..(slot.y + slot.height).min(h) {
+                for x in slot.x..(slot.x + slot.width).min(w) {
+                    let pixel = out_image.get_pixel_mut(x, y);
+                    for i in 0..3 {
+                        pixel[i] = (pixel[i] as f32 * (1.0 - HEATMAP_OPACITY) + tint[i] as f32 * HEATMAP_OPACITY) as u8;
+                    }
+                }
+            }
+        }
+
+        failed.sort();
+        DryRunReport {
+            heatmap: out_image,
+            planes_read: read_offsets.len(),
+            total_planes,
+            metadata_read,
+            misreads,
+            failed
+        }
+    }
+}
+
+// The row and column of each slot, counting from 1 at the top left, for reporting where failures were.
+fn grid_positions(slots: &[BarcodeSlot]) -> Vec<(usize, usize)> {
+    let mut rows: Vec<u32> = slots.iter().map(|s| s.y).collect();
+    let mut columns: Vec<u32> = slots.iter().map(|s| s.x).collect();
+    rows.sort();
+    rows.dedup();
+    columns.sort();
+    columns.dedup();
+    slots.iter().map(|s| (rows.iter().position(|r| *r == s.y).unwrap() + 1, columns.iter().position(|c| *c == s.x).unwrap() + 1)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use imageproc::drawing::draw_filled_rect_mut;
+    use imageproc::rect::Rect;
+
+    #[test]
+    fn blanked_barcode_fails() {
+        let metadata = DocumentMetadata::new("").finalize();
+        let dry_run = DryRunPage::new().finalize();
+        let (w, h) = (400, 400);
+        let packer = dry_run.make_packer(w, h, ColorMultiplexer::new(2), &metadata);
+        let data = DryRunPage::page_data(packer.data_bytes_per_page());
+        let checksum = DryRunPage::page_checksum(&data);
+        let mut page = RgbImage::new(w, h);
+        packer.encode(&mut page, 1, false, 0, checksum, 0, data.len() as u64, &data);
+
+        let report = dry_run.check_page(&DynamicImage::ImageRgb8(page.clone()), ColorMultiplexer::new(2), &metadata, (w, h));
+        assert!(report.failed.is_empty());
+        assert!(report.metadata_read);
+        assert_eq!(report.planes_read, report.total_planes);
+
+        // Paint over the first data barcode.
+        let slots = packer.barcode_slots(1, checksum);
+        let blanked = slots.iter().position(|s| !s.is_metadata).unwrap();
+        let slot = &slots[blanked];
+        draw_filled_rect_mut(&mut page, Rect::at(slot.x as i32, slot.y as i32).of_size(slot.width, slot.height), Rgb([255, 255, 255]));
+        let report = dry_run.check_page(&DynamicImage::ImageRgb8(page), ColorMultiplexer::new(2), &metadata, (w, h));
+        let (row, column) = grid_positions(&slots)[blanked];
+        assert_eq!(report.failed, vec![(row, column, "0 of 1 planes read".to_string())]);
+        assert_eq!(report.planes_read, report.total_planes - 1);
+        // Painting over a barcode leaves it blank, rather than misread.
+        assert_eq!(report.misreads, 0);
+    }
+}
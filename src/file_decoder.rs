@@ -2,12 +2,21 @@
 
 use crate::archive_human_input_file::*;
 use crate::data_file::*;
-use crate::color_multiplexer::ColorMultiplexer;
-use crate::grayscale_recognizer::recognize_grayscale_barcodes;
-use base45::decode;
+use crate::color_multiplexer::{ColorMultiplexer, estimate_num_colors, usable_num_colors};
+use crate::grayscale_recognizer::{recognize_grayscale_barcodes, recognize_grayscale_barcodes_with_confidence, recognize_grouped_barcodes};
+use crate::payload_encoding::{decode_payload, FORMAT_VERSION_BYTE_MODE_FLAG};
+use crate::page_barcode_packer::{BarcodeHeader, parse_barcode_header, header_length, supported_format_version, barcode_checksum_matches, barcode_layout, same_barcode_arrangement, QUIET_ZONE_SIZE};
+use crate::document_metadata::DocumentMetadata;
+use crate::palette_swatch::{FoundSwatch, find_labeled_swatches};
+use image::DynamicImage;
 
 pub struct FileDecoder<'a> {
-    file_reader: &'a mut ArchiveHumanInputFile<'a>
+    file_reader: &'a mut ArchiveHumanInputFile<'a>,
+    metadata: Option<DocumentMetadata>,
+    // Barcodes which decoded, but whose checksum showed they decoded to the wrong data.
+    discarded_barcodes: usize,
+    // Labeled palette swatches on the page, found the first time they're needed.
+    swatches: Option<Vec<FoundSwatch>>
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -18,25 +27,164 @@ pub struct DecodedChunkInfo {
     pub start_offset: u64,
     pub total_length: u64,
     pub length: u32,
-    pub hash: u32
+    pub hash: u32,
+    pub format_version: u8,
+    // The following are only recorded from format version 2 onward, and are zero for older barcodes.
+    pub bytes_per_page: u32,
+    pub data_pages: u16,
+    pub parity_pages: u8,
+    pub color_planes: u8,
+    pub layout_id: u8,
+    pub is_metadata: bool
 }
 
+// Parses a decoded barcode as a metadata barcode, returning None if it isn't one.
+fn parse_metadata_barcode(data_chunk: &[u8]) -> Option<DocumentMetadata> {
+    let header = parse_barcode_header(data_chunk).ok()?;
+    if !header.is_metadata || !barcode_checksum_matches(data_chunk) {
+        return None;
+    }
+    DocumentMetadata::from_bytes(&data_chunk[header_length(header.format_version) as usize..], header.total_length).ok()
+}
+
+// Checks whether a decoded barcode has a header we understand, returning the number of color planes and the layout it says the document uses.
+// Format version 1 doesn't record those, so we get zeroes back for those.
+fn probe_header(data_chunk: &[u8]) -> Option<(u8, u8)> {
+    let header = parse_barcode_header(data_chunk).ok()?;
+    if !barcode_checksum_matches(data_chunk) {
+        return None;
+    }
+    Some((header.color_planes, header.layout_id))
+}
+
+// Reads every barcode on a page, along with how many of them were only read by retrying their least confident modules, which we only do if asked to.
+// Each barcode only comes back once, even though the overlapping blocks we search in find most of them more than once, so a misread isn't counted again for every block it turns up in.
+pub fn read_barcodes(color_multiplexer: &ColorMultiplexer, page_image: &DynamicImage, rescue: bool) -> (Vec<Vec<u8>>, usize) {
+    let (chunks, rescued) = read_barcodes_with_repeats(color_multiplexer, page_image, rescue);
+    let mut unique: Vec<Vec<u8>> = vec![];
+    for c in chunks {
+        if !unique.contains(&c) {
+            unique.push(c);
+        }
+    }
+    (unique, rescued)
+}
+
+fn read_barcodes_with_repeats(color_multiplexer: &ColorMultiplexer, page_image: &DynamicImage, rescue: bool) -> (Vec<Vec<u8>>, usize) {
+    if color_multiplexer.pixels_per_group() > 1 {
+        // Planes are spread over groups of neighboring barcodes, so they can only be read a whole group at a time.
+        let planes = color_multiplexer.demultiplex_image(page_image);
+        return (recognize_grouped_barcodes(&planes, color_multiplexer, QUIET_ZONE_SIZE as u32), 0);
+    }
+    let mut chunks = vec![];
+    let mut rescued = 0;
+    if rescue {
+        for (d, confidence) in color_multiplexer.demultiplex_image_with_confidence(page_image) {
+            let (chunks_in_plane, rescued_in_plane) = recognize_grayscale_barcodes_with_confidence(&d, Some(&confidence));
+            chunks.extend(chunks_in_plane);
+            rescued += rescued_in_plane;
+        }
+    }
+    else {
+        for d in color_multiplexer.demultiplex_image(page_image) {
+            chunks.extend(recognize_grayscale_barcodes(&d));
+        }
+    }
+    (chunks, rescued)
+}
+
+// How many candidate color counts we'll try when none of them can be confirmed by a version 2 header.
+// Each one means reading the whole page again, so past this it's quicker for the user to tell us with -c than to keep guessing.
+const MAX_UNCONFIRMED_COLOR_CANDIDATES: usize = 3;
+
 impl<'a, 'b> FileDecoder<'a> {
     pub fn new(file_reader: &'a mut ArchiveHumanInputFile<'a>) -> FileDecoder<'a> {
         FileDecoder {
-            file_reader: file_reader
+            file_reader,
+            metadata: None,
+            discarded_barcodes: 0,
+            swatches: None
         }
     }
 
     pub fn finalize(self) -> FileDecoder<'a> {
         FileDecoder {
-            file_reader: self.file_reader
+            file_reader: self.file_reader,
+            metadata: self.metadata,
+            discarded_barcodes: self.discarded_barcodes,
+            swatches: self.swatches
         }
     }
 
-    fn process_decoded_chunk(&mut self, encoded_data: &Vec<u8>, file_writer: &mut DataFile, parity_buffer: &mut Vec<Vec<u8>>) -> Result<DecodedChunkInfo, &str> {
+    // Searching the page for swatch labels means reading every barcode on it, so it's only done once however many palettes we try.
+    fn swatches(&mut self, page_image: &DynamicImage) -> &[FoundSwatch] {
+        self.swatches.get_or_insert_with(|| find_labeled_swatches(page_image))
+    }
+
+    // How many barcodes were thrown away because their checksum didn't match.
+    pub fn discarded_barcodes(&self) -> usize {
+        self.discarded_barcodes
+    }
+
+    // Looks for the monochrome metadata barcode without needing to know how many colors the page uses.
+    pub fn read_metadata(&mut self) -> Option<&DocumentMetadata> {
+        let page_image = self.file_reader.read_page().unwrap();
+        let monochrome = ColorMultiplexer::new(2).finalize();
+        for d in monochrome.demultiplex_image(&page_image) {
+            for c in recognize_grayscale_barcodes(&d) {
+                if let Ok(data_chunk) = decode_payload(&c) {
+                    if let Some(m) = parse_metadata_barcode(&data_chunk) {
+                        self.metadata = Some(m);
+                        return self.metadata.as_ref();
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    // Figures out how many colors a page was encoded with by trying candidate palette sizes until one of them gives us valid barcodes.
+    // Candidates are tried in order of how close they are to the number of colors in the printed palette swatch.
+    pub fn detect_num_colors(&mut self) -> Option<u8> {
+        let page_image = self.file_reader.read_page().unwrap();
+        let swatches = self.swatches(&page_image);
+        let estimate = estimate_num_colors(&page_image, swatches);
+        let mut candidates: Vec<u8> = (2..=128).filter(|c| usable_num_colors(*c) == *c).collect();
+        candidates.sort_by(|a, b| (*a as f32 / estimate as f32).ln().abs().total_cmp(&(*b as f32 / estimate as f32).ln().abs()));
+        candidates.truncate(MAX_UNCONFIRMED_COLOR_CANDIDATES);
+
+        // Version 1 barcodes don't say how many color planes there are, and a palette which is too small can still read some barcodes where the planes happen to match.
+        // So for those, we go with whichever candidate reads the most.
+        let mut best: Option<(u8, usize)> = None;
+        for c in &candidates {
+            let mut color_multiplexer = ColorMultiplexer::new(*c).finalize();
+            color_multiplexer.palettize_from_image(&page_image, swatches);
+            let num_planes = color_multiplexer.num_planes();
+            let layout = barcode_layout(&color_multiplexer);
+            let mut valid_barcodes = 0;
+            for chunk in read_barcodes(&color_multiplexer, &page_image, false).0 {
+                if let Ok(data_chunk) = decode_payload(&chunk) {
+                    match probe_header(&data_chunk) {
+                        Some((0, _)) => valid_barcodes += 1,
+                        Some((p, l)) if p == num_planes && same_barcode_arrangement(l, layout) => return Some(*c),
+                        _ => {}
+                    }
+                }
+            }
+            if valid_barcodes > 0 && best.is_none_or(|(_, b)| valid_barcodes > b) {
+                best = Some((*c, valid_barcodes));
+            }
+        }
+        if best.is_none() {
+            let tried: Vec<String> = candidates.iter().map(|c| c.to_string()).collect();
+            println!("Couldn't detect the number of colors, having tried {}; use -c to give it", tried.join(", "));
+        }
+        best.map(|(c, _)| c)
+    }
+
+    fn process_decoded_chunk(&mut self, encoded_data: &Vec<u8>, file_writer: &mut DataFile, parity_buffer: &mut Vec<Vec<Option<u8>>>) -> Result<DecodedChunkInfo, &str> {
         // Don't know why there are 4 bytes of junk at the start of this.
-        match decode(&std::str::from_utf8(&encoded_data).unwrap()) {
+        match decode_payload(encoded_data) {
             Ok(data_chunk) => {
                 // Skip blank chunks.
                 if data_chunk.len() < 1 {
@@ -44,23 +192,31 @@ impl<'a, 'b> FileDecoder<'a> {
                 }
 
                 //println!("Decoded chunk {:?}", data_chunk);
-                let format_version = data_chunk[0];
-                if format_version != 1 {
-                    panic!("Unsupported format version {}", format_version);
+                // Whether this was base-45 or byte mode has already been taken care of by the payload decoder.
+                let header = match parse_barcode_header(&data_chunk) {
+                    Ok(h) => h,
+                    Err(e) => {
+                        let format_version = data_chunk[0] & !FORMAT_VERSION_BYTE_MODE_FLAG;
+                        if !supported_format_version(format_version) {
+                            println!("Skipping barcode with unsupported format version {}", format_version);
+                        }
+                        return Err(e);
+                    }
+                };
+                let overhead = header_length(header.format_version) as usize;
+
+                // A misread barcode can't be trusted to say where it came from, so its data is left out and recovered from parity like any other missing barcode.
+                if !barcode_checksum_matches(&data_chunk) {
+                    println!("Discarding barcode which claims to be page {} barcode {}, because its checksum doesn't match", header.page_number, header.barcode_number & 0x00ff);
+                    self.discarded_barcodes += 1;
+                    return Err("Barcode checksum doesn't match");
+                }
+
+                let BarcodeHeader { format_version, page_number, barcode_number, is_parity, is_metadata, start_offset, parity_index, total_length, hash, bytes_per_page, data_pages, parity_pages, color_planes, layout_id, .. } = header;
+                if is_metadata && self.metadata.is_none() {
+                    self.metadata = parse_metadata_barcode(&data_chunk);
                 }
 
-                let page_number = u16::from_be_bytes([data_chunk[1], data_chunk[2]]);
-                let barcode_number = u16::from_be_bytes([data_chunk[3], data_chunk[4]]);
-                let is_parity: bool = (data_chunk[3] & 0b10000000) != 0;
-                let start_offset = if !is_parity {
-                    u64::from_be_bytes([0, 0, data_chunk[5], data_chunk[6], data_chunk[7], data_chunk[8], data_chunk[9], data_chunk[10]])
-                } else {
-                    u64::from_be_bytes([0, 0, 0, 0, data_chunk[7], data_chunk[8], data_chunk[9], data_chunk[10]])
-                };
-                let parity_index: u8 = data_chunk[6];
-                let total_length = u64::from_be_bytes([0, 0, data_chunk[11], data_chunk[12], data_chunk[13], data_chunk[14], data_chunk[15], data_chunk[16]]);
-                let hash = u32::from_be_bytes([0, data_chunk[17], data_chunk[18], data_chunk[19]]);
-                let overhead: usize = 20;
                 let mut amount_written: u32 = 0;
 
                 // If this is for parity, make sure the buffer's prepped.
@@ -70,7 +226,10 @@ impl<'a, 'b> FileDecoder<'a> {
                     }
                 }
 
-                if start_offset > total_length {
+                if is_metadata {
+                    // Nothing to write - the metadata has already been parsed.
+                }
+                else if start_offset > total_length {
                     //Padding - ignore it.
                     //println!("Pure padding - ignoring");
                 }
@@ -91,22 +250,30 @@ impl<'a, 'b> FileDecoder<'a> {
                         let end_index = start_offset as usize + amount_written as usize;
                         let p = parity_index as usize;
                         let s = start_offset as usize;
+                        // Anything no barcode fills in stays None, so it's treated as an erasure rather than as parity which happens to be zero.
                         while parity_buffer[p].len() < end_index {
-                            parity_buffer[p].push(0);
+                            parity_buffer[p].push(None);
                         }
                         for b in 0..amount_written as usize {
-                            parity_buffer[p][s + b] = data_chunk[overhead + b];
+                            parity_buffer[p][s + b] = Some(data_chunk[overhead + b]);
                         }
                     }
                 }
                 return Ok(DecodedChunkInfo {
-                    is_parity: is_parity,
-                    page_number: page_number,
-                    barcode_number: barcode_number,
-                    start_offset: start_offset,
-                    total_length: total_length,
+                    is_parity,
+                    page_number,
+                    barcode_number,
+                    start_offset,
+                    total_length,
                     length: amount_written,
-                    hash: hash
+                    hash,
+                    format_version,
+                    bytes_per_page,
+                    data_pages,
+                    parity_pages,
+                    color_planes,
+                    layout_id,
+                    is_metadata
                 });
             },
             Err(e) => {
@@ -116,28 +283,34 @@ impl<'a, 'b> FileDecoder<'a> {
         }
     }
 
-    pub fn decode(&mut self, file_writer: &mut DataFile, parity_buffer: &mut Vec<Vec<u8>>, color_multiplexer: &mut ColorMultiplexer, adjust_colors: bool) -> Vec<DecodedChunkInfo> {
+    pub fn decode(&mut self, file_writer: &mut DataFile, parity_buffer: &mut Vec<Vec<Option<u8>>>, color_multiplexer: &mut ColorMultiplexer, adjust_colors: bool) -> Vec<DecodedChunkInfo> {
         // Just doing this once for now.
         let mut chunk_info = vec![];
         let page_image = self.file_reader.read_page().unwrap();
         if adjust_colors {
-            color_multiplexer.palettize_from_image(&page_image);
+            color_multiplexer.palettize_from_image(&page_image, self.swatches(&page_image));
         }
-        let demuxed_images = color_multiplexer.demultiplex_image(&page_image);
-        for d in demuxed_images {
-            let chunks = recognize_grayscale_barcodes(&d);
-            for c in chunks {
-                let result = self.process_decoded_chunk(&c, file_writer, parity_buffer);
-                match result {
-                    Err(_e) => {
-                        // Ignore decode errors for now.
-                    },
-                    Ok(c) => {
+        let (chunks, rescued) = read_barcodes(color_multiplexer, &page_image, true);
+        let discarded_before = self.discarded_barcodes;
+        for c in chunks {
+            let result = self.process_decoded_chunk(&c, file_writer, parity_buffer);
+            match result {
+                Err(_e) => {
+                    // Ignore decode errors for now.
+                },
+                Ok(c) => {
+                    if !c.is_metadata {
                         chunk_info.push(c);
                     }
                 }
             }
         }
+        if rescued > 0 {
+            println!("Rescued {} barcodes by flipping low-confidence modules", rescued);
+        }
+        if self.discarded_barcodes > discarded_before {
+            println!("Discarded {} misread barcodes on this page", self.discarded_barcodes - discarded_before);
+        }
 
         chunk_info
     }
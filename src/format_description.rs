@@ -0,0 +1,119 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0+ OR Zlib
+
+use image::Rgb;
+use crate::document_metadata::{DocumentMetadata, EcFunction};
+use crate::payload_encoding::PayloadEncoding;
+use crate::page_barcode_packer::{header_length, BARCODE_LAYOUT_INTERLEAVED_FLAG};
+use crate::color_multiplexer::{MultiplexMode, CMYK_BLACK_TINT};
+use crate::palette_swatch::LABEL_GAP;
+
+// Builds the plain-text description of the format which is printed on the bootstrap page.
+// Each entry is a paragraph - wrapping them to the page is up to the output file.
+// This is meant to be enough for someone to write a new decoder from the paper alone, so keep it in sync with the design outline.
+#[allow(clippy::too_many_arguments)]
+pub fn describe_format(metadata: &DocumentMetadata, format_version: u8, payload_encoding: PayloadEncoding, bytes_per_page: u32, data_pages: u16, file_checksum: u32, palette: &[Rgb<u8>], layout_id: u8) -> Vec<String> {
+    let mut out: Vec<String> = vec![];
+    out.push("HOW TO READ THIS ARCHIVE".to_string());
+    out.push("This archive stores a single file as QR codes (ISO/IEC 18004).  Data pages are numbered from 1.  This page is page 0 and holds no data.  Each QR code can be read on its own - use the header inside it, not its position on the page, to put the data back together, since barcodes are shuffled on every page.".to_string());
+
+    out.push("PAYLOAD".to_string());
+    match payload_encoding {
+        PayloadEncoding::Base45 => out.push("QR codes hold text in alphanumeric mode.  Decode that text as base-45 (IETF RFC 9285) to get the binary data described below.".to_string()),
+        PayloadEncoding::Byte => out.push("QR codes hold binary data in byte mode.  To recover it, XOR every byte with 0xC3, then undo Consistent Overhead Byte Stuffing (COBS): read a code byte N, copy the next N-1 bytes, and if N is less than 255 and more data follows, add a zero byte.  Repeat until the end.".to_string())
+    }
+
+    out.push("BARCODE HEADER".to_string());
+    out.push("All numbers are big endian.  Byte 0: format version, with 0x40 set if the payload was stored in byte mode.  Bytes 1-2: page number.  Bytes 3-4: barcode number - 0x8000 is set for parity, 0x1000 for the metadata barcode, and the low 8 bits count barcodes on the page, color planes included, wrapping around after 255.  The other bits are always clear.  Bytes 5-10: for data, the file offset where this barcode's data starts; for parity, 1 reserved byte, 1 byte of parity page index, then 4 bytes of offset within the parity page.  Bytes 11-16: total file length.  Bytes 17-19: low 24 bits of the document hash.".to_string());
+    if format_version >= 2 {
+        out.push("Format version 2 adds: Bytes 20-23: data bytes per page.  Bytes 24-25: data page count.  Byte 26: parity page count.  Byte 27: color plane count.  Byte 28: barcode layout (1 = grid, 2 = grid of side by side groups), plus 128 if color planes are interleaved.".to_string());
+    }
+    if format_version >= 3 {
+        out.push("Format version 3 adds: Bytes 29-32: CRC-32 (the one used by zlib and PNG) of every other byte of the barcode, header and data alike.  Barcodes which don't match were misread and should be ignored.".to_string());
+    }
+    out.push(format!("The file data follows the header ({} bytes) and fills the rest of the barcode.  Anything past the total file length is padding, as are barcodes with an offset past the end of the file.", header_length(format_version)));
+    out.push("The metadata barcode is always black and white and describes the document: version, colors, DPI (2 bytes), page width and height in thousandths of an inch (4 bytes each), error correction function (0 = constant, 1 = radial), minimum and maximum error correction percentages, parity pages, multiplexing (0 = palette, 1 = CMYK inks, 2 = grayscale), then for metadata version 3 a SHA-256 digest of the whole file (1 byte length, always 32), then the encoder version (1 byte length) and title (2 byte length) as UTF-8 text.".to_string());
+
+    out.push("DOCUMENT HASH".to_string());
+    out.push("Split the file into 1 MiB (1048576 byte) blocks, padding the last one with zeroes.  Take the CRC-32 (the one used by zlib and PNG) of each block, write each one as 4 big endian bytes, and take the CRC-32 of all of those together.".to_string());
+
+    out.push("COLORS".to_string());
+    if metadata.multiplex_mode == MultiplexMode::Cmyk {
+        out.push(format!("Each barcode square holds 4 QR codes, one on each ink: cyan, magenta, yellow, then black.  Wherever an ink is printed, that QR code has a dark module.  Black is printed at {}% so the other inks show through it.  To separate them, work out which inks are on each pixel - for example, by comparing its optical density in red, green, and blue to the single-ink patches in the swatch, since densities add where inks overlap.", (CMYK_BLACK_TINT as u32 * 100 + 127) / 255));
+    }
+    else if metadata.multiplex_mode == MultiplexMode::Grayscale {
+        out.push(format!("Each barcode square holds 2 QR codes overlaid using 4 shades of gray: black, #{:02X}{:02X}{:02X}, #{:02X}{:02X}{:02X}, then white.  For each pixel, find the nearest shade - learn where the shades actually came out from the page itself, since printers rarely hit them exactly.  Bit N of the shade's index is plane N, where a set bit is a light module and a clear bit is a dark one.", palette[1][0], palette[1][1], palette[1][2], palette[2][0], palette[2][1], palette[2][2]));
+    }
+    else if palette.len() > 2 && !palette.len().is_power_of_two() {
+        let planes = (palette.len() * palette.len()).ilog2();
+        out.push(format!("Each barcode square is a group of 2 barcodes side by side, separated by the usual quiet zone, holding {} QR codes between them using {} colors.  For each pair of pixels at the same spot in the 2 barcodes, find the nearest palette color to each.  The indexes make a 2 digit number in base {}, with the left barcode holding the lowest digit.  Bit N of that number is plane N, where a set bit is a light module and a clear bit is a dark one - except that white in both barcodes, or any number too big to fit in {} bits, means all planes are light.  Colors are in hue order.  The swatches at the right of the header and footer (and in the middle, where there's room) show the palette without black and white, left to right then top to bottom.  Calibrate from the nearest swatch if the lighting varies across the page.", planes, palette.len(), palette.len(), planes));
+        let entries: Vec<String> = palette.iter().enumerate().map(|(i, c)| format!("{} = #{:02X}{:02X}{:02X}", i, c[0], c[1], c[2])).collect();
+        out.push(format!("Palette: {}", entries.join(", ")));
+    }
+    else if palette.len() > 2 {
+        let planes = palette.len().ilog2();
+        out.push(format!("Each barcode square holds {} QR codes overlaid using {} colors.  For each pixel, find the nearest palette color.  Bit N of its index is plane N, where a set bit is a light module and a clear bit is a dark one.  Colors are in Gray code order so colors that look alike only differ in one plane.  The swatches at the right of the header and footer (and in the middle, where there's room) show the palette without black and white, left to right then top to bottom.  Calibrate from the nearest swatch if the lighting varies across the page.", planes, palette.len()));
+        let entries: Vec<String> = palette.iter().enumerate().map(|(i, c)| format!("{} = #{:02X}{:02X}{:02X}", i, c[0], c[1], c[2])).collect();
+        out.push(format!("Palette: {}", entries.join(", ")));
+    }
+    else {
+        out.push("Black and white - each barcode square holds a single QR code.".to_string());
+    }
+    if palette.len() > 2 {
+        if layout_id & BARCODE_LAYOUT_INTERLEAVED_FLAG != 0 {
+            out.push("Color planes are interleaved: the first plane of every barcode on a page holds data before the second plane of any, so planes of the same barcode hold far apart parts of the file.  Go by the offsets in the headers.".to_string());
+        }
+        else {
+            out.push("Planes of the same barcode hold consecutive data.".to_string());
+        }
+        out.push(format!("Each swatch has a small QR code on its left reading \"SWATCH\", then the palette index of its first patch, the number of patches, and the number of columns, separated by spaces.  The swatch is drawn on the QR code's module grid, so it can be found from the code anywhere on the page: as tall as the code, starting {} modules to its right, with each patch taking up the middle half of a square cell as tall as the code divided by the number of rows.  Black is the middle of the code's finder patterns, and white is the gap between the code and the patches.", LABEL_GAP));
+    }
+
+    out.push("PARITY".to_string());
+    if metadata.parity_pages > 0 {
+        out.push(format!("The last {} pages hold Reed-Solomon parity over GF(2^8) with polynomial 0x11D, using a systematic Vandermonde matrix (as in the Backblaze and reed-solomon-erasure libraries).  Byte N of every data page and byte N of every parity page form one codeword, with the last data page padded with zeroes.  Any {} pages can be lost and rebuilt.", metadata.parity_pages, metadata.parity_pages));
+    }
+    else {
+        out.push("None.  Every data page is needed.".to_string());
+    }
+
+    out.push("THIS DOCUMENT".to_string());
+    if !metadata.title.is_empty() {
+        out.push(format!("Title: {}", metadata.title));
+    }
+    out.push(format!("Original size: {} bytes.  Document hash: {:08X}.  Format version: {}.  Payload: {}.", metadata.total_length, file_checksum, format_version, match payload_encoding { PayloadEncoding::Base45 => "base-45", PayloadEncoding::Byte => "byte mode" }));
+    out.push(format!("Data pages: {}.  Parity pages: {}.  Data bytes per page: {}.", data_pages, metadata.parity_pages, bytes_per_page));
+    out.push(format!("Colors: {}.  DPI: {}.  Page size: {} x {} in.  Error correction: {}.  Encoder version: {}.", metadata.num_colors, metadata.dpi, metadata.page_width as f32 / 1000.0, metadata.page_height as f32 / 1000.0, match metadata.ec_function {
+        EcFunction::Constant => format!("constant at {}%", metadata.ec_min),
+        EcFunction::Radial => format!("radial from {}% to {}%", metadata.ec_min, metadata.ec_max)
+    }, metadata.encoder_version));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_this_document() {
+        let metadata = DocumentMetadata::new("Deeds")
+            .total_length(123456)
+            .num_colors(2)
+            .dpi(300)
+            .parity_pages(2)
+            .finalize();
+        let palette = [Rgb([0, 0, 0]), Rgb([255, 255, 255])];
+        let text = |format_version: u8| describe_format(&metadata, format_version, PayloadEncoding::Base45, 4321, 29, 0x00abcdef, &palette, 1).join("\n");
+
+        let v3 = text(3);
+        for expected in ["Title: Deeds", "Original size: 123456 bytes", "Document hash: 00ABCDEF", "Format version: 3", "Data pages: 29", "Parity pages: 2", "Data bytes per page: 4321", "DPI: 300", "Bytes 20-23: data bytes per page", "Bytes 29-32: CRC-32"] {
+            assert!(v3.contains(expected), "Description is missing \"{}\"", expected);
+        }
+        assert!(v3.contains(&format!("header ({} bytes)", header_length(3))));
+        let v2 = text(2);
+        assert!(v2.contains("Byte 28: barcode layout"));
+        assert!(!v2.contains("Bytes 29-32"));
+        assert!(v2.contains(&format!("header ({} bytes)", header_length(2))));
+        let v1 = text(1);
+        assert!(!v1.contains("Bytes 20-23"));
+    }
+}
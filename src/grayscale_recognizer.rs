@@ -1,59 +1,418 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0+ OR Zlib
 
-use image::{DynamicImage, GenericImageView};
+use image::{DynamicImage, GenericImageView, GrayImage};
 use image::imageops::FilterType;
 //use rqrr::PreparedImage;
-use bardecoder;
+use bardecoder::prepare::{Prepare, BlockedMean};
+use bardecoder::detect::{Detect, LineScan, Location};
+use bardecoder::extract::{Extract, QRExtractor};
+use bardecoder::decode::{Decode, QRDecoder};
+use bardecoder::util::qr::{QRData, QRLocation};
+use crate::color_multiplexer::ColorMultiplexer;
+use bardecoder::util::Point;
+use std::cell::{Cell, OnceCell};
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
 
-pub fn recognize_grayscale_barcodes(in_image: &DynamicImage) -> Vec<Vec<u8>> {
+// bardecoder hands back byte mode segments as a String, decoded as ISO 8859-1 unless the segment contains a 0xC3 byte, in which case it's decoded as UTF-8.
+// Undo the ISO 8859-1 decoding so byte mode data comes back exactly as it was written.  Alphanumeric data is plain ASCII, so it's unaffected.
+fn raw_bytes_from_decoded_string(s: String) -> Vec<u8> {
+    if s.chars().all(|c| (c as u32) <= 0xff) {
+        s.chars().map(|c| c as u8).collect()
+    }
+    else {
+        s.into_bytes()
+    }
+}
+
+thread_local! {
+    // Set while this thread is inside bardecoder, whose panics we catch and treat as failed reads.
+    static IN_BARDECODER: Cell<bool> = const { Cell::new(false) };
+}
+
+// Keeps panics caught by catch_bardecoder_panic from cluttering the output, while still reporting every other panic.
+// The panic hook is shared by the whole process, so this only installs it once, and it only stays quiet for the thread that's inside bardecoder.
+pub fn quiet_bardecoder_panics() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !IN_BARDECODER.with(|b| b.get()) {
+                previous_hook(info);
+            }
+        }));
+    });
+}
+
+// Runs part of bardecoder, returning None if it panics.
+// It can index outside the image on some inputs, which is more likely on planes demultiplexed with the wrong palette.
+fn catch_bardecoder_panic<T>(f: impl FnOnce() -> T) -> Option<T> {
+    let was_inside = IN_BARDECODER.with(|b| b.replace(true));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    IN_BARDECODER.with(|b| b.set(was_inside));
+    result.ok()
+}
+
+// When a barcode fails to read, we retry it with every pixel less confident than each of these flipped to its other value, working up from the most doubtful.
+const RESCUE_CONFIDENCE_LEVELS: [u8; 4] = [8, 24, 48, 96];
+
+// QRLocation isn't Clone, and extracting consumes it, so we make our own copies for retries.
+fn copy_location(loc: &QRLocation) -> QRLocation {
+    QRLocation {
+        top_left: loc.top_left,
+        top_right: loc.top_right,
+        bottom_left: loc.bottom_left,
+        module_size: loc.module_size,
+        version: loc.version
+    }
+}
+
+enum LocationRead {
+    Read(Vec<u8>),
+    // We could sample the barcode's modules, but they didn't decode.
+    Damaged,
+    // bardecoder couldn't make out the barcode's grid at all, which is what happens with most of the finder patterns it thinks it's seen.
+    Unlocatable
+}
+
+// Reads one located barcode, treating a panic inside bardecoder as a failed read.
+fn read_location(prepared: &GrayImage, loc: QRLocation) -> LocationRead {
+    catch_bardecoder_panic(|| {
+        match QRExtractor::new().extract(prepared, loc) {
+            Ok(extracted) => match QRDecoder::new().decode(Ok(extracted)) {
+                Ok(r) => LocationRead::Read(raw_bytes_from_decoded_string(r)),
+                Err(_e) => LocationRead::Damaged
+            },
+            Err(_e) => LocationRead::Unlocatable
+        }
+    }).unwrap_or(LocationRead::Unlocatable)
+}
+
+// Retries a barcode which failed to read, flipping more and more of its least confident modules.
+// The confidence map is at the scale of the original plane, which is half the size of the image we decode from.
+fn rescue_location(prepared: &GrayImage, loc: &QRLocation, confidence: &GrayImage, chunk_x: u32, chunk_y: u32) -> Option<Vec<u8>> {
+    // Finder pattern centers are three and a half modules in from the edges of the barcode.
+    let bottom_right = (loc.top_right.x + loc.bottom_left.x - loc.top_left.x, loc.top_right.y + loc.bottom_left.y - loc.top_left.y);
+    let xs = [loc.top_left.x, loc.top_right.x, loc.bottom_left.x, bottom_right.0];
+    let ys = [loc.top_left.y, loc.top_right.y, loc.bottom_left.y, bottom_right.1];
+    let margin = loc.module_size * 4.0;
+    let left = (xs.iter().cloned().fold(f64::MAX, f64::min) - margin).max(0.0) as u32;
+    let top = (ys.iter().cloned().fold(f64::MAX, f64::min) - margin).max(0.0) as u32;
+    let right = ((xs.iter().cloned().fold(f64::MIN, f64::max) + margin) as u32).min(prepared.width());
+    let bottom = ((ys.iter().cloned().fold(f64::MIN, f64::max) + margin) as u32).min(prepared.height());
+
+    let mut doubtful = vec![];
+    for y in top..bottom {
+        for x in left..right {
+            let (cx, cy) = ((chunk_x + x) / 2, (chunk_y + y) / 2);
+            if cx < confidence.width() && cy < confidence.height() {
+                doubtful.push((x, y, confidence.get_pixel(cx, cy)[0]));
+            }
+        }
+    }
+
+    let mut flipped_count = 0;
+    for level in RESCUE_CONFIDENCE_LEVELS {
+        let to_flip: Vec<_> = doubtful.iter().filter(|(_, _, c)| *c < level).collect();
+        // Nothing new to flip at this level, so it would fail the same way as the last attempt.
+        if to_flip.len() == flipped_count {
+            continue;
+        }
+        flipped_count = to_flip.len();
+        let mut retry = prepared.clone();
+        for (x, y, _) in to_flip {
+            let value = retry.get_pixel(*x, *y)[0];
+            retry.get_pixel_mut(*x, *y)[0] = 255 - value;
+        }
+        if let LocationRead::Read(data) = read_location(&retry, copy_location(loc)) {
+            return Some(data);
+        }
+    }
+    None
+}
+
+// Overlapping blocks of this size are what we look for barcodes in, on images sized up to twice their size.
+const DECODE_BLOCK_SIZE: u32 = 800;
+
+// One of the overlapping blocks we look for barcodes in, prepared for bardecoder, with the barcodes it found there.
+struct Block {
+    // Top left corner of the block, on the sized up image.
+    x: u32,
+    y: u32,
+    // One prepared image for each plane, in the order they were given.
+    prepared: Vec<GrayImage>,
+    // Found in the first plane.
+    locations: Vec<QRLocation>,
+    // Where to find each location by the grid cells its top left and top right finder patterns are in.
+    // Only grouped barcodes need this, so it's made the first time it's asked for.
+    by_position: OnceCell<HashMap<[i32; 4], Vec<usize>>>
+}
+
+// Size of the grid cells locations are looked up in, in pixels on the sized up image.
+const POSITION_CELL_SIZE: f64 = 16.0;
+
+impl Block {
+    // Locations whose top left and top right finder patterns are both within the given distance of where they're expected.
+    // bardecoder reports a location for nearly every three finder patterns that could make up a barcode, which can be hundreds of thousands of them, so this goes through an index rather than checking every one.
+    fn locations_near(&self, top_left: Point, top_right: Point, distance: f64) -> Vec<&QRLocation> {
+        let cell = |v: f64| (v / POSITION_CELL_SIZE).floor() as i32;
+        let by_position = self.by_position.get_or_init(|| {
+            let mut by_position: HashMap<[i32; 4], Vec<usize>> = HashMap::new();
+            for (i, l) in self.locations.iter().enumerate() {
+                by_position.entry([cell(l.top_left.x), cell(l.top_left.y), cell(l.top_right.x), cell(l.top_right.y)]).or_default().push(i);
+            }
+            by_position
+        });
+        let cells = |v: f64| cell(v - distance)..=cell(v + distance);
+        let mut near = vec![];
+        for left_x in cells(top_left.x) {
+            for left_y in cells(top_left.y) {
+                for right_x in cells(top_right.x) {
+                    for right_y in cells(top_right.y) {
+                        for i in by_position.get(&[left_x, left_y, right_x, right_y]).into_iter().flatten() {
+                            let l = &self.locations[*i];
+                            if (l.top_left.x - top_left.x).hypot(l.top_left.y - top_left.y) < distance && (l.top_right.x - top_right.x).hypot(l.top_right.y - top_right.y) < distance {
+                                near.push(l);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        near
+    }
+}
+
+// Looks for barcodes in overlapping blocks of the given planes, calling back with each barcode location bardecoder reports and the block it was found in.
+// Every plane is sized up to twice its size and prepared for each block, but barcodes are only looked for in the first one.
+// Blocks overlap by half, so anything up to half a block across is wholly inside one of them, and most barcodes are found more than once.
+fn for_each_location(planes: &[&DynamicImage], block_size: u32, mut f: impl FnMut(&Block, &QRLocation)) {
     // Need GenericImageView trait to be able to use width() and height().
-    let sized_up = in_image.resize(in_image.width() * 2, in_image.height() * 2, FilterType::Nearest);
-    let mut recognized_fragments = vec![];
+    let sized_up: Vec<DynamicImage> = planes.iter().map(|p| p.resize(p.width() * 2, p.height() * 2, FilterType::Nearest)).collect();
+    let (width, height) = (sized_up[0].width(), sized_up[0].height());
 
-    let decoder = bardecoder::default_decoder();
+    // These are what bardecoder::default_decoder() uses, but we run the steps ourselves so we can get at the barcodes that fail.
+    let preparer = BlockedMean::new(5, 7);
+    let detector = LineScan::new();
 
-    // Decode in blocks of this size.
-    let decode_block_size = 800;
-    let mut x = 0;
+    // Images smaller than a block still get one block, covering the whole thing.
     let mut y = 0;
-    //let quiet_zone = 200;
+    loop {
+        let mut x = 0;
+        loop {
+            let w = block_size.min(width - x);
+            let h = block_size.min(height - y);
+            // A panic in bardecoder counts as finding nothing.
+            let (prepared, locations): (Vec<GrayImage>, Vec<Location>) = catch_bardecoder_panic(|| {
+                let prepared: Vec<GrayImage> = sized_up.iter().map(|p| preparer.prepare(&*p.view(x, y, w, h))).collect();
+                let locations = detector.detect(&prepared[0]);
+                (prepared, locations)
+            }).unwrap_or_default();
+            let locations = locations.into_iter().map(|l| { let Location::QR(loc) = l; loc }).collect();
+            let block = Block { x, y, prepared, locations, by_position: OnceCell::new() };
+            for loc in &block.locations {
+                f(&block, loc);
+            }
+
+            if x + block_size >= width {
+                break;
+            }
+            x += block_size / 2;
+        }
+        if y + block_size >= height {
+            break;
+        }
+        y += block_size / 2;
+    }
+}
+
+pub fn recognize_grayscale_barcodes(in_image: &DynamicImage) -> Vec<Vec<u8>> {
+    recognize_grayscale_barcodes_with_confidence(in_image, None).0
+}
 
-    //println!("Attempting to decode");
-    while y + decode_block_size / 2 < sized_up.height() {
-        //println!("Decoding block at {}, {}", x, y);
-        let mut w = decode_block_size;
-        if x + decode_block_size > sized_up.width() {
-            w = sized_up.width() - x;
+// Like recognize_grayscale_barcodes, but given the plane's confidence map from the demultiplexer, it also retries barcodes that fail to read by flipping their least confident modules.
+// Returns the barcodes read along with how many of them were only read thanks to a retry.
+pub fn recognize_grayscale_barcodes_with_confidence(in_image: &DynamicImage, confidence: Option<&GrayImage>) -> (Vec<Vec<u8>>, usize) {
+    let mut recognized_fragments = vec![];
+    let mut rescued_fragments = vec![];
+    for_each_location(&[in_image], DECODE_BLOCK_SIZE, |block, loc| {
+        let prepared = &block.prepared[0];
+        match read_location(prepared, copy_location(loc)) {
+            LocationRead::Read(r) => recognized_fragments.push(r),
+            // Only retry barcodes we could find the modules of - there's no point flipping pixels in something that isn't one.
+            LocationRead::Damaged => if let Some(c) = confidence {
+                if let Some(r) = rescue_location(prepared, loc, c, block.x, block.y) {
+                    rescued_fragments.push(r);
+                }
+            },
+            LocationRead::Unlocatable => {}
+        }
+    });
+
+    // Blocks overlap, so a barcode which needed rescuing in one block may have read fine in another.
+    let mut rescued_count = 0;
+    for r in rescued_fragments {
+        if !recognized_fragments.contains(&r) {
+            recognized_fragments.push(r);
+            rescued_count += 1;
         }
-        let mut h = decode_block_size;
-        if y + decode_block_size > sized_up.height() {
-            h = sized_up.height() - y;
+    }
+    (recognized_fragments, rescued_count)
+}
+
+// A barcode that was read, along with where it was on the page, for barcodes which label something printed beside them.
+pub struct LocatedBarcode {
+    pub data: Vec<u8>,
+    // Centers of the finder patterns, in pixels on the image the barcode was found in.
+    pub top_left: (f32, f32),
+    pub top_right: (f32, f32),
+    pub bottom_left: (f32, f32),
+    // Number of modules along each side.
+    pub side: u32
+}
+
+impl LocatedBarcode {
+    // Where a point given in modules from the barcode's top left corner is on the image, following the barcode's own rows and columns so it works on rotated scans.
+    // Points can be outside the barcode, for things drawn on its module grid beside it.
+    pub fn module_position(&self, x: f32, y: f32) -> (f32, f32) {
+        // Finder pattern centers are seven modules closer together than the width of the barcode, and three and a half modules in from its corner.
+        let steps = (self.side - 7) as f32;
+        let across = ((self.top_right.0 - self.top_left.0) / steps, (self.top_right.1 - self.top_left.1) / steps);
+        let down = ((self.bottom_left.0 - self.top_left.0) / steps, (self.bottom_left.1 - self.top_left.1) / steps);
+        (self.top_left.0 + (x - 3.5) * across.0 + (y - 3.5) * down.0, self.top_left.1 + (x - 3.5) * across.1 + (y - 3.5) * down.1)
+    }
+
+    // Pixels per module.
+    pub fn module_size(&self) -> f32 {
+        (self.top_right.0 - self.top_left.0).hypot(self.top_right.1 - self.top_left.1) / (self.side - 7) as f32
+    }
+}
+
+// Like recognize_grayscale_barcodes, but also says where each barcode was found.
+// Blocks overlap, so a barcode can be found more than once.
+pub fn locate_grayscale_barcodes(in_image: &DynamicImage) -> Vec<LocatedBarcode> {
+    let mut located = vec![];
+    for_each_location(&[in_image], DECODE_BLOCK_SIZE, |block, loc| {
+        // Back to the coordinates of the image we were given, which is half the size of the one we decoded from.
+        let unscale = |p: &Point| ((block.x as f64 + p.x) as f32 / 2.0, (block.y as f64 + p.y) as f32 / 2.0);
+        let (top_left, top_right, bottom_left) = (unscale(&loc.top_left), unscale(&loc.top_right), unscale(&loc.bottom_left));
+        let side = 17 + 4 * loc.version;
+        if let LocationRead::Read(data) = read_location(&block.prepared[0], copy_location(loc)) {
+            located.push(LocatedBarcode { data, top_left, top_right, bottom_left, side });
         }
-        let image_chunk = sized_up.view(x, y, w, h);
+    });
+    located
+}
+
+// Samples a located barcode's modules, with 0 for dark, treating a panic inside bardecoder as a failure.
+fn extract_modules(prepared: &GrayImage, loc: QRLocation) -> Option<Vec<u8>> {
+    catch_bardecoder_panic(|| {
+        QRExtractor::new().extract(prepared, loc).ok().map(|extracted| extracted.data)
+    }).flatten()
+}
+
+// Decodes a barcode from its modules.
+fn decode_modules(modules: Vec<u8>, version: u32) -> Option<Vec<u8>> {
+    catch_bardecoder_panic(|| {
+        QRDecoder::new().decode(Ok(QRData::new(modules, version))).ok().map(raw_bytes_from_decoded_string)
+    }).flatten()
+}
 
-        // Add a large white border around the chunk to work around https://github.com/piderman314/bardecoder/issues/50
-        /*let mut new_image = DynamicImage::new_rgb8(decode_block_size + quiet_zone * 2, decode_block_size + quiet_zone * 2);
-        draw_filled_rect_mut(&mut new_image, Rect::at(0, 0).of_size(decode_block_size + quiet_zone * 2, decode_block_size + quiet_zone * 2), Rgba([255, 255, 255, 0]));
-        imageops::overlay(&mut new_image, &image_chunk.to_image(), quiet_zone as i64, quiet_zone as i64);
+// How far along from a barcode the one after it in a group is: a barcode's width plus the spacing further along its rows.
+// Going by the barcode's own axes means this still works on rotated scans.
+fn group_offset(loc: &QRLocation, spacing: u32) -> (f64, f64) {
+    // Finder pattern centers are seven modules closer together than the width of the barcode.
+    let side = (17 + 4 * loc.version) as f64;
+    let scale = (side + spacing as f64) / (side - 7.0);
+    ((loc.top_right.x - loc.top_left.x) * scale, (loc.top_right.y - loc.top_left.y) * scale)
+}
 
-        let results = decoder.decode(&new_image); //&(DynamicImage::ImageRgba8(image_chunk.to_image())));*/
+// Finds the barcode after the given one in a group, by looking where it should be.
+// bardecoder also reports plenty of finder patterns from different barcodes grouped together, so all three of them have to line up.
+fn next_in_group<'a>(block: &'a Block, loc: &QRLocation, spacing: u32) -> Option<&'a QRLocation> {
+    let (offset_x, offset_y) = group_offset(loc, spacing);
+    let tolerance = loc.module_size * 3.0;
+    let shifted = |p: Point| Point { x: p.x + offset_x, y: p.y + offset_y };
+    let bottom_left = shifted(loc.bottom_left);
+    block.locations_near(shifted(loc.top_left), shifted(loc.top_right), tolerance).into_iter().find(|next| {
+        (next.bottom_left.x - bottom_left.x).hypot(next.bottom_left.y - bottom_left.y) < tolerance
+    })
+}
 
-        // Once https://github.com/piderman314/bardecoder/issues/50 is resolved, the lines above can be replaced with:
-        let results = decoder.decode(&*image_chunk);
-        
-        //println!("Done decoding - found {} results", results.len());
-        for result in results {
-            match result {
-                Result::Ok(r) => recognized_fragments.push(r.as_bytes().to_vec()),
-                //Result::Err(e) => println!("Error in QR code results: {}", e),
-                Result::Err(_e) => { /* Ignore errors, because there will be a lot of them */} //println!("Error in QR code results: {}", e),
+// Reads barcodes whose planes are spread over groups of neighboring barcodes, which is how palettes that aren't a power of two are multiplexed.
+// Takes the planes from demultiplex_image, along with the spacing between barcodes in a group, in modules.
+// Every plane shows the finder patterns, so we find barcodes in the first one, line each up with the ones beside it, and sample the whole group from every plane so the multiplexer can work out the barcode planes.
+// There's nothing to tell where one group ends and the next starts, so we try starting a group at every barcode - the ones that straddle two groups just don't decode.
+pub fn recognize_grouped_barcodes(planes: &[DynamicImage], multiplexer: &ColorMultiplexer, spacing: u32) -> Vec<Vec<u8>> {
+    let group = multiplexer.pixels_per_group() as usize;
+    let planes: Vec<&DynamicImage> = planes.iter().collect();
+    let mut recognized_fragments = vec![];
+    // Blocks need to be big enough for a whole group to fit in one.
+    for_each_location(&planes, DECODE_BLOCK_SIZE * group as u32, |block, first| {
+        let mut members = vec![first];
+        while members.len() < group {
+            match next_in_group(block, members[members.len() - 1], spacing) {
+                Some(next) => members.push(next),
+                None => break
             }
         }
-        x += decode_block_size / 2;
-        if x + decode_block_size / 2 > sized_up.width() {
-            x = 0;
-            y += decode_block_size / 2;
+        if members.len() < group {
+            return;
         }
-    }
+
+        // Sample every barcode in the group at the same size as the first, so their modules line up.
+        let modules: Option<Vec<Vec<Vec<u8>>>> = members.iter().map(|m| {
+            let mut loc = copy_location(m);
+            loc.version = first.version;
+            block.prepared.iter().map(|p| extract_modules(p, copy_location(&loc))).collect()
+        }).collect();
+        if let Some(modules) = modules {
+            for plane in multiplexer.ungroup_modules(&modules) {
+                if let Some(r) = decode_modules(plane, first.version) {
+                    recognized_fragments.push(r);
+                }
+            }
+        }
+    });
     recognized_fragments
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Luma;
+    use qrencode::{EcLevel, QrCode, Version};
+
+    #[test]
+    fn rescues_barcodes_from_their_least_confident_modules() {
+        let data = b"RESCUE THIS BARCODE".to_vec();
+        let code = QrCode::with_version(&data, Version::Normal(1), EcLevel::L).unwrap();
+        let module = 4;
+        let quiet_zone = 4;
+        let mut plane = code.render::<Luma<u8>>().module_dimensions(module, module).quiet_zone(true).build();
+        let mut confidence = GrayImage::from_pixel(plane.width(), plane.height(), Luma([255]));
+
+        // Version 1 has nothing but data in its bottom right corner, so damage far more of it there than error correction can fix, and say those modules could have gone either way.
+        for y in 9..21 {
+            for x in 9..21 {
+                if (x + 2 * y) % 3 != 0 {
+                    continue;
+                }
+                for py in (quiet_zone + y) * module..(quiet_zone + y + 1) * module {
+                    for px in (quiet_zone + x) * module..(quiet_zone + x + 1) * module {
+                        let value = plane.get_pixel(px, py)[0];
+                        plane.put_pixel(px, py, Luma([255 - value]));
+                        confidence.put_pixel(px, py, Luma([0]));
+                    }
+                }
+            }
+        }
+        let plane = DynamicImage::ImageLuma8(plane);
+        assert!(!recognize_grayscale_barcodes(&plane).contains(&data));
+
+        let (read, rescued_count) = recognize_grayscale_barcodes_with_confidence(&plane, Some(&confidence));
+        assert!(read.contains(&data));
+        assert!(rescued_count > 0);
+    }
+}
@@ -1,16 +1,18 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0+ OR Zlib
 
 extern crate clap;
-use clap::{Command, Arg, ArgAction};
+use clap::{Command, Arg, ArgAction, ArgGroup, ArgMatches};
+use clap::parser::ValueSource;
 extern crate image;
 //extern crate rqrr;
 extern crate bardecoder;
 extern crate env_logger;
 extern crate glob;
 extern crate reed_solomon_erasure;
-use image::RgbImage;
+use image::{Rgb, RgbImage};
 
 mod stress_test_page;
+mod calibration_sheet;
 mod archive_human_output_file;
 mod archive_human_input_file;
 mod grayscale_recognizer;
@@ -18,18 +20,262 @@ mod data_file;
 mod page_barcode_packer;
 mod color_multiplexer;
 mod file_decoder;
+mod payload_encoding;
+mod document_metadata;
+mod format_description;
+mod color_profile;
+mod palette_file;
+mod palette_swatch;
+mod settings_recommendation;
+mod dry_run_page;
+mod scan_simulator;
+mod archive_manifest;
+mod pseudorandom;
 use stress_test_page::StressTestPage;
+use calibration_sheet::CalibrationSheet;
 use archive_human_output_file::{OutputFormat, ArchiveHumanOutputFile};
 use archive_human_input_file::ArchiveHumanInputFile;
 use data_file::DataFile;
-use page_barcode_packer::{BarcodeFormat, PageBarcodePacker, make_constant_damage_map, make_radial_damage_map};
-use color_multiplexer::ColorMultiplexer;
+use page_barcode_packer::{BarcodeFormat, PageBarcodePacker, make_constant_damage_map, make_radial_damage_map, LATEST_FORMAT_VERSION, BARCODE_LAYOUT_INTERLEAVED_FLAG, barcode_layout, same_barcode_arrangement};
+use color_multiplexer::{ColorMultiplexer, MultiplexMode};
+use color_profile::ColorProfile;
+use palette_file::read_palette;
+use settings_recommendation::recommend_settings;
+use dry_run_page::DryRunPage;
+use scan_simulator::{ScanSimulator, DEFAULT_DAMAGE};
+use archive_manifest::{ArchiveManifest, ManifestBarcode, manifest_filename, hash_page_file};
 use file_decoder::FileDecoder;
+use payload_encoding::PayloadEncoding;
+use document_metadata::{DocumentMetadata, EcFunction};
+use format_description::describe_format;
 use glob::glob;
 use reed_solomon_erasure::galois_8::ReedSolomon;
 
+// Sets up the color multiplexer, letting a color profile or a custom palette override the number of colors if we have one.
+// A color profile also sets the multiplexing mode, and already has its own palette.
+fn make_color_multiplexer(num_colors: u8, mode: MultiplexMode, profile: &Option<ColorProfile>, palette: &Option<Vec<Rgb<u8>>>) -> ColorMultiplexer {
+    let color_multiplexer = ColorMultiplexer::new(num_colors).mode(mode);
+    match (profile, palette) {
+        (Some(p), _) => color_multiplexer.profile(p).finalize(),
+        (None, Some(colors)) => color_multiplexer.colors(colors).finalize(),
+        (None, None) => color_multiplexer.finalize()
+    }
+}
+
+// Encode options, as given on the command line or, when verifying an archive, as recorded with it.
+struct EncodeSettings {
+    num_colors: u8,
+    multiplex_mode: MultiplexMode,
+    // In inches.
+    page_width: f32,
+    page_height: f32,
+    dpi: u16,
+    ec_function: EcFunction,
+    ec_min: u8,
+    ec_max: u8,
+    parity_pages: u8,
+    format_version: u8,
+    payload_encoding: PayloadEncoding,
+    title: Option<String>,
+    encoder_version: Option<String>,
+    sha256: bool,
+    include_metadata: bool,
+    interleave_planes: bool,
+    bootstrap: bool
+}
+
+impl EncodeSettings {
+    fn new(matches: &ArgMatches, num_colors: u8, multiplex_mode: MultiplexMode) -> EncodeSettings {
+        EncodeSettings {
+            num_colors,
+            multiplex_mode,
+            page_width: *matches.get_one::<f32>("pagewidth").unwrap(),
+            page_height: *matches.get_one::<f32>("pageheight").unwrap(),
+            dpi: *matches.get_one::<u16>("dpi").unwrap(),
+            ec_function: if matches.get_one::<String>("ecfunction").unwrap() == "constant" { EcFunction::Constant } else { EcFunction::Radial },
+            ec_min: *matches.get_one::<u8>("ecmin").unwrap(),
+            ec_max: *matches.get_one::<u8>("ecmax").unwrap(),
+            parity_pages: *matches.get_one::<u8>("parity").unwrap(),
+            format_version: *matches.get_one::<u8>("formatversion").unwrap(),
+            payload_encoding: if matches.get_one::<String>("encoding").unwrap() == "byte" { PayloadEncoding::Byte } else { PayloadEncoding::Base45 },
+            title: matches.get_one::<String>("title").cloned(),
+            encoder_version: matches.get_one::<String>("encoderversion").cloned(),
+            sha256: matches.get_flag("sha256"),
+            include_metadata: !matches.get_flag("nometadata"),
+            interleave_planes: matches.get_flag("interleaveplanes"),
+            bootstrap: matches.get_flag("bootstrap")
+        }
+    }
+
+    // Takes everything which wasn't given on the command line from how an archive was encoded.
+    // The manifest records most of it, and the metadata barcode has the error correction and the rest of what the manifest doesn't.
+    // A color profile or custom palette isn't recorded in a form it can be made from again, so those still have to be given.
+    fn recorded(mut self, matches: &ArgMatches, colors_given: bool, mode_given: bool, in_file: &str, manifest: Option<&ArchiveManifest>, metadata: Option<&DocumentMetadata>) -> Self {
+        let given = |name: &str| matches.value_source(name) == Some(ValueSource::CommandLine);
+        if let Some(m) = metadata {
+            if !colors_given {
+                self.num_colors = m.num_colors;
+            }
+            if !mode_given {
+                self.multiplex_mode = m.multiplex_mode;
+            }
+            if !given("pagewidth") && !given("pageheight") {
+                self.page_width = m.page_width as f32 / 1000.0;
+                self.page_height = m.page_height as f32 / 1000.0;
+            }
+            if !given("dpi") {
+                self.dpi = m.dpi;
+            }
+            if !given("ecfunction") {
+                self.ec_function = m.ec_function;
+            }
+            if !given("ecmin") {
+                self.ec_min = m.ec_min;
+            }
+            if !given("ecmax") {
+                self.ec_max = m.ec_max;
+            }
+            if !given("parity") {
+                self.parity_pages = m.parity_pages;
+            }
+            if !given("title") && !m.title.is_empty() {
+                self.title = Some(m.title.clone());
+            }
+            if !given("encoderversion") {
+                self.encoder_version = Some(m.encoder_version.clone());
+            }
+            self.sha256 |= m.sha256.is_some();
+        }
+        if let Some(m) = manifest {
+            if !colors_given {
+                self.num_colors = m.palette.len() as u8;
+            }
+            if !mode_given {
+                self.multiplex_mode = m.multiplex_mode;
+            }
+            if !given("pagewidth") && !given("pageheight") {
+                self.page_width = m.page_width;
+                self.page_height = m.page_height;
+            }
+            if !given("dpi") {
+                self.dpi = m.dpi;
+            }
+            if !given("parity") {
+                self.parity_pages = m.parity_pages;
+            }
+            if !given("formatversion") {
+                self.format_version = m.format_version;
+            }
+            if !given("encoding") {
+                self.payload_encoding = m.payload_encoding;
+            }
+            // The manifest has the page header, which is the input file name unless there was a title.
+            if !given("title") && m.title != in_file {
+                self.title = Some(m.title.clone());
+            }
+            self.include_metadata &= m.barcodes.iter().any(|b| b.is_metadata);
+            self.interleave_planes |= m.layout_id & BARCODE_LAYOUT_INTERLEAVED_FLAG != 0;
+            self.bootstrap |= m.page(0).is_some();
+        }
+        self
+    }
+}
+
+// Describes the document as the given encode options would encode it.
+// Still needs the total length, and finalizing.
+fn make_document_metadata(settings: &EncodeSettings, color_multiplexer: &ColorMultiplexer) -> DocumentMetadata {
+    let metadata = DocumentMetadata::new(settings.title.as_deref().unwrap_or(""))
+        .num_colors(color_multiplexer.num_colors())
+        .multiplex_mode(settings.multiplex_mode)
+        .dpi(settings.dpi)
+        .page_size(settings.page_width, settings.page_height)
+        .error_correction(settings.ec_function, settings.ec_min, settings.ec_max)
+        .parity_pages(settings.parity_pages);
+    match &settings.encoder_version {
+        Some(v) => metadata.encoder_version(v),
+        None => metadata
+    }
+}
+
+// Sets up a dry run page with the given encode options.
+fn make_dry_run_page(settings: &EncodeSettings) -> DryRunPage {
+    DryRunPage::new()
+        .format_version(settings.format_version)
+        .payload_encoding(settings.payload_encoding)
+        .interleave_planes(settings.interleave_planes)
+        .include_metadata(settings.include_metadata)
+        .finalize()
+}
+
+// Reads the metadata barcode from one page of an archive, if the page is there and the barcode can be read.
+fn read_page_metadata(filename: &str, format: OutputFormat) -> Option<DocumentMetadata> {
+    if !std::path::Path::new(filename).exists() {
+        return None;
+    }
+    let mut reader = ArchiveHumanInputFile::new(filename, format)
+        .finalize();
+    let mut decoder = FileDecoder::new(&mut reader).finalize();
+    decoder.read_metadata().cloned()
+}
+
+// Compares each page just encoded with the same page of an archive encoded before, reporting any which differ.
+// Pages are compared by hash, taken from the archive's manifest if there is one so the pages themselves aren't needed.
+fn verify_reproducible(encoded: &ArchiveManifest, archive: &ArchiveHumanOutputFile, recorded: Option<&ArchiveManifest>) -> bool {
+    let mut reproducible = true;
+    let mut last_page = encoded.data_pages + encoded.parity_pages as u16;
+    match recorded {
+        Some(r) => {
+            println!("Comparing with the manifest...");
+            for what in encoded.differences(r) {
+                println!("- Different {}", what);
+                reproducible = false;
+            }
+            last_page = last_page.max(r.pages.iter().map(|p| p.number).max().unwrap_or(0));
+        },
+        None => {
+            println!("Comparing with {}...", archive.page_filename(1));
+            // A page past the end means the archive was longer.
+            last_page += 1;
+        }
+    }
+    let mut matching = 0;
+    // Page 0 is only there with --bootstrap.
+    for page_number in 0..=last_page {
+        let new_hash = encoded.page(page_number).map(|p| p.sha256.clone());
+        let old_hash = match recorded {
+            Some(r) => r.page(page_number).map(|p| p.sha256.clone()),
+            None => hash_page_file(&archive.page_filename(page_number))
+        };
+        match (new_hash, old_hash) {
+            (Some(new_hash), Some(old_hash)) => {
+                if new_hash == old_hash {
+                    matching += 1;
+                }
+                else {
+                    println!("- Page {} differs", page_number);
+                    reproducible = false;
+                }
+            },
+            (Some(_), None) => {
+                println!("- Page {} is missing from the archive", page_number);
+                reproducible = false;
+            },
+            (None, Some(_)) => {
+                println!("- Page {} is in the archive but wasn't encoded this time", page_number);
+                reproducible = false;
+            },
+            (None, None) => {}
+        }
+    }
+    if reproducible {
+        println!("Archive is reproducible: all {} pages match", matching);
+    }
+    reproducible
+}
+
 fn main() {
     env_logger::init();
+    grayscale_recognizer::quiet_bardecoder_panics();
 
     let matches = Command::new("Real World Archive")
                     .version("0.0.1")
@@ -38,20 +284,20 @@ fn main() {
                     .arg(Arg::new("input")
                         .short('i')
                         .long("input")
-                        .help("File or directory to read input from.  Required unless running a stress test in encode mode.")
-                        .required_unless_present_all(&["stresstest", "encode"])
+                        .help("File or directory to read input from.  Required unless generating a stress test or calibration sheet in encode mode.  When simulating damage, a glob pattern of the pages to damage.")
+                        .required_unless_present_all(["testpage", "encode"])
                         .display_order(1))
                     .arg(Arg::new("output")
                         .short('o')
                         .long("output")
-                        .help("File or directory to place output in.  Required unless running a stress test in decode mode.")
+                        .help("File or directory to place output in.  Required unless running a stress test in decode mode.  When reading a calibration sheet, this is where the color profile is saved.  When reading a stress test, module error rates are saved here as JSON if given.  When simulating damage, the directory the damaged pages are saved to, under the same names.")
                         .required_unless_present_all(&["stresstest", "decode"])
                         .display_order(2))
                     .arg(Arg::new("format")
                         .short('f')
                         .long("format")
-                        .help("Output format to use.  Currently only \"png\" is supported, and is the default output format.")
-                        .value_parser(["png"])
+                        .help("Output format to use.  \"png\" is the default output format.  \"tiff\" writes CMYK for printing with process inks, and puts each plane directly on its own ink when used with \"--multiplex cmyk\".")
+                        .value_parser(["png", "tiff"])
                         .default_value("png"))
                     .arg(Arg::new("units")
                         .short('u')
@@ -85,9 +331,14 @@ fn main() {
                     .arg(Arg::new("colors")
                         .short('c')
                         .long("colors")
-                        .help("Maximum number of colors.  Defaults to \"2\" for monochrome")
+                        .help("Maximum number of colors.  Counts which aren't a power of two, like 6 or 12, spread their color planes over pairs of neighboring barcodes to make use of every color, which only this program can read.  Counts which wouldn't hold any more data that way are rounded down to a power of two.  When decoding, this is detected automatically if not given.  Defaults to \"2\" for monochrome")
                         .value_parser(clap::value_parser!(u8).range(2..))
                         .default_value("2"))
+                    .arg(Arg::new("multiplex")
+                        .long("multiplex")
+                        .help("How barcodes are overlaid in color.  \"palette\" uses the number of colors given with -c, spaced around the color wheel.  \"cmyk\" puts one barcode on each of the cyan, magenta, yellow, and black inks, for 4 planes.  \"grayscale\" puts 2 planes into 4 shades of gray, for monochrome laser printers and engravers.  When decoding, this is taken from the metadata barcode if not given.  Defaults to \"palette\"")
+                        .value_parser(["palette", "cmyk", "grayscale"])
+                        .default_value("palette"))
                     .arg(Arg::new("ecfunction")
                         .long("ecfunction")
                         .help("Error correction function for how much error correction to use for each barcode depending on its position on the page.  Defaults to \"radial\" to skew error correction so there is less in the center of the page and more toward the corners but can be set to \"constant\" for a constant level of error correction across the entire page")
@@ -103,44 +354,207 @@ fn main() {
                         .help("Maximum percentage of error correction - just the number [0..100].  Please note this is not the amount of a barcode which can be lost and recovered but a percentage of the range we can run on.  For example, QR codes have a \"0\" level of 7% error corraction and \"100\" level of 30% of data which can be recovered.  Only applicable in non-constant error correction functions.  Defaults to \"100\"")
                         .value_parser(clap::value_parser!(u8).range(0..101))
                         .default_value("100"))
+                    .arg(Arg::new("encoding")
+                        .long("encoding")
+                        .help("How data is stored in each barcode.  \"base45\" stores it as alphanumeric text, which the widest range of barcode readers support.  \"byte\" stores raw bytes in byte mode for higher density.  Only used when encoding - the decoder detects it automatically.  Defaults to \"base45\"")
+                        .value_parser(["base45", "byte"])
+                        .default_value("base45"))
+                    .arg(Arg::new("formatversion")
+                        .long("formatversion")
+                        .help("Format version to write, for compatibility with older decoders.  Version 2 records the page size and document layout in every barcode, and version 3 adds a checksum so misread barcodes are discarded.  Only used when encoding.  Defaults to the latest version, \"3\"")
+                        .value_parser(clap::value_parser!(u8).range(1..(LATEST_FORMAT_VERSION as i64 + 1)))
+                        .default_value("3"))
+                    .arg(Arg::new("title")
+                        .long("title")
+                        .help("Title of the document, printed in the page header and stored in the metadata barcode.  Defaults to the input filename in the page header and no title in the metadata"))
+                    .arg(Arg::new("encoderversion")
+                        .long("encoderversion")
+                        .help("Encoder version to record instead of this one's, so tests which pin what pages look like don't change with every release")
+                        .hide(true))
+                    .arg(Arg::new("sha256")
+                        .long("sha256")
+                        .help("Store a SHA-256 digest of the whole input in the metadata barcode, which the decoder checks once the file has been rebuilt.  For archives which need stronger assurance than the 24-bit hash in every barcode that the file was rebuilt exactly.  When decoding, fails unless the file could be checked against that digest, instead of only warning if the metadata barcode couldn't be read")
+                        .conflicts_with("nometadata")
+                        .action(ArgAction::SetTrue))
+                    .arg(Arg::new("nometadata")
+                        .long("nometadata")
+                        .help("Leave out the metadata barcode which lets the decoder configure itself, to fit slightly more data on each page.  Only used when encoding")
+                        .action(ArgAction::SetTrue))
+                    .arg(Arg::new("bootstrap")
+                        .long("bootstrap")
+                        .help("Add a page 0 before the data which describes, in plain text, how to decode the archive without this software.  Only used when encoding")
+                        .action(ArgAction::SetTrue))
                     .arg(Arg::new("decode")
                         .short('d')
                         .long("decode")
-                        .help("Use this to decode the given filename.  Either encode or decode must be specified.")
-                        .required_unless_present("encode")
-                        .conflicts_with("encode")
+                        .help("Use this to decode the given filename.  Either encode, decode, or simulate must be specified.")
+                        .required_unless_present_any(["encode", "simulate"])
+                        .conflicts_with_all(["encode", "simulate"])
                         .action(ArgAction::SetTrue)
                         .display_order(1))
                     .arg(Arg::new("encode")
                         .short('e')
                         .long("encode")
-                        .help("Encode to the given filename as output.  Either encode or decode must be specified.")
-                        .required_unless_present("decode")
-                        .conflicts_with("decode")
+                        .help("Encode to the given filename as output.  Either encode, decode, or simulate must be specified.")
+                        .required_unless_present_any(["decode", "simulate"])
+                        .conflicts_with_all(["decode", "simulate"])
                         .action(ArgAction::SetTrue)
                         .display_order(2))
+                    .arg(Arg::new("verifyreproducible")
+                        .long("verifyreproducible")
+                        .help("When encoding, check an archive encoded before still comes out the same, byte for byte, instead of writing a new one.  Give the archive to check with -o and the input it was encoded from.  It's encoded again the way its manifest, given with --manifest, and its metadata barcode record, so other options are only needed to check it against something different, or for a color profile or custom palette")
+                        .requires("encode")
+                        .conflicts_with("testpage")
+                        .action(ArgAction::SetTrue))
+                    .arg(Arg::new("manifest")
+                        .long("manifest")
+                        .help("Manifest written alongside the pages when encoding, named after the output with \"manifest.txt\" on the end.  When decoding, the decoded file is also checked against the length and full 32-bit hash it records.  With --verifyreproducible, pages are checked against the hashes it records, so the archive's pages aren't needed"))
+                    .arg(Arg::new("simulate")
+                        .long("simulate")
+                        .help("Damage encoded pages the way printing, handling, and scanning them would, and save the damaged pages for the decoder to read.  Either encode, decode, or simulate must be specified.")
+                        .conflicts_with_all(["encode", "decode"])
+                        .action(ArgAction::SetTrue)
+                        .display_order(3))
+                    .arg(Arg::new("damage")
+                        .long("damage")
+                        .help("Damage to simulate, as a comma-separated list of name=value.  \"scale\" is how many pixels of the scan make up each pixel of the page, \"blur\" is the blur in pixels of the page, \"rotate\" the largest rotation in degrees, \"skew\" how far out of place corners get as a share of the page, \"noise\" the sensor noise out of 255, \"light\" how much darker the far side of the page gets out of 1, \"cast\" a color cast as red:green:blue, \"jpeg\" the JPEG quality to recompress at, and \"stains\", \"tears\", \"creases\", and \"staples\" how many of each to add.  Defaults to a careful trip through an office printer and scanner, \"scale=2,blur=0.3,light=0.15,noise=6,jpeg=85\"")
+                        .default_value(DEFAULT_DAMAGE))
+                    .arg(Arg::new("seed")
+                        .long("seed")
+                        .help("Seed for simulated damage.  The same seed always does the same damage to the same pages.  Defaults to \"0\"")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("0"))
                     .arg(Arg::new("parity")
                         .short('p')
                         .long("parity")
                         .help("Number of pages of parity to generate in the range [0..63].  This equates to the number of full pages which can be lost from the rest of the document.  Defaults to \"0\"")
                         .value_parser(clap::value_parser!(u8).range(0..64))
                         .default_value("0"))
+                    .arg(Arg::new("interleaveplanes")
+                        .long("interleaveplanes")
+                        .action(ArgAction::SetTrue)
+                        .help("Fill the first color plane of every barcode on a page before the second, and so on, instead of giving each barcode's planes consecutive data.  A smudge then costs small pieces of several ranges of the file instead of one long one.  Only used when encoding - the decoder doesn't need to know"))
                     .arg(Arg::new("stresstest")
                         .short('t')
                         .long("stresstest")
                         .action(ArgAction::SetTrue)
-                        .help("Generate a stress test"))
+                        .help("Generate a stress test.  The first page tests each DPI and number of colors, and the rest test how tightly barcodes can be packed at each error correction level, quiet zone, and barcode version.  When decoding, give all of the pages with a glob pattern"))
+                    .arg(Arg::new("calibrate")
+                        .long("calibrate")
+                        .action(ArgAction::SetTrue)
+                        .help("Generate a color calibration sheet of every palette color when encoding.  When decoding, read a scan of one made with the same colors and multiplexing mode, and save a color profile for this printer and scanner to the output file"))
+                    .arg(Arg::new("recommend")
+                        .long("recommend")
+                        .action(ArgAction::SetTrue)
+                        .requires("stresstest")
+                        .requires("decode")
+                        .help("When decoding a stress test, recommend encode settings which fit the most data on a page of the size given with -W and -H while keeping within the safety margin.  -D should be the DPI the stress test was printed at"))
+                    .arg(Arg::new("margin")
+                        .long("margin")
+                        .help("Safety margin for recommending settings - how many times the module error rate measured on the stress test the error correction has to be able to cover.  Defaults to \"2\"")
+                        .value_parser(clap::value_parser!(f32))
+                        .default_value("2"))
+                    .arg(Arg::new("dryrun")
+                        .long("dryrun")
+                        .action(ArgAction::SetTrue)
+                        .help("Generate a page of known pseudorandom data, laid out exactly like a page of a document encoded with the same options.  When decoding, report which barcodes on a scan of it couldn't be read, and save the page with each barcode tinted from red to green by how much of it was read to the output file"))
+                    .group(ArgGroup::new("testpage")
+                        .args(["stresstest", "calibrate", "dryrun"]))
+                    .arg(Arg::new("profile")
+                        .long("profile")
+                        .help("Color profile made with --calibrate, to encode and decode with the colors measured for a particular printer and scanner.  Sets the number of colors and multiplexing mode"))
+                    .arg(Arg::new("palette")
+                        .long("palette")
+                        .help("Colors to use instead of ones spaced around the hue circle, for specialty inks or materials.  Either hex colors separated by commas, like \"000000,1f4e9a,b03a2e,ffffff\", or a file with one hex color per line or in GIMP palette format.  The darkest color is used as black and the lightest as white, and the rest are ordered so colors which look alike differ in as few planes as possible.  Sets the number of colors.  Give the same palette when decoding"))
+                    .arg(Arg::new("predistort")
+                        .long("predistort")
+                        .action(ArgAction::SetTrue)
+                        .help("When making a color profile, also work out adjusted palette colors for the encoder to print with, so they scan closer to the intended ones"))
                     .get_matches();
-    let format = OutputFormat::PNG; //matches.value_of("format").unwrap().to_lowercase();
+    let format = if matches.get_one::<String>("format").unwrap() == "tiff" { OutputFormat::TIFF } else { OutputFormat::PNG };
     let colors = *matches.get_one::<u8>("colors").unwrap();
+    let profile = matches.get_one::<String>("profile").map(|p| ColorProfile::load(p).unwrap_or_else(|e| panic!("{}: {}", e, p)));
+    let multiplex_mode = match &profile {
+        Some(p) => p.mode,
+        None => match matches.get_one::<String>("multiplex").unwrap().as_str() {
+            "cmyk" => MultiplexMode::Cmyk,
+            "grayscale" => MultiplexMode::Grayscale,
+            _ => MultiplexMode::Palette
+        }
+    };
+    let palette = matches.get_one::<String>("palette").map(|p| read_palette(p).unwrap_or_else(|e| panic!("{}: {}", e, p)));
+    let manifest = matches.get_one::<String>("manifest").map(|m| ArchiveManifest::load(m).unwrap_or_else(|e| panic!("{}: {}", e, m)));
+    if let Some(p) = &profile {
+        println!("Using color profile {} with {} colors", p.name, p.printed.len());
+        if matches.value_source("colors") == Some(ValueSource::CommandLine) && colors as usize != p.printed.len() {
+            println!("Warning: ignoring the number of colors given, since the color profile has {}", p.printed.len());
+        }
+        if palette.is_some() {
+            println!("Warning: ignoring the palette given, since the color profile has its own");
+        }
+    }
+    else if let Some(p) = &palette {
+        if multiplex_mode != MultiplexMode::Palette {
+            panic!("Custom palettes can only be used with palette multiplexing");
+        }
+        println!("Using a custom palette with {} colors", p.len());
+        if matches.value_source("colors") == Some(ValueSource::CommandLine) && colors as usize != p.len() {
+            println!("Warning: ignoring the number of colors given, since the palette has {}", p.len());
+        }
+    }
     if matches.get_flag("encode") {
         // Encode.
-        let width = *matches.get_one::<f32>("pagewidth").unwrap();
-        let height = *matches.get_one::<f32>("pageheight").unwrap();
-        let dpi = *matches.get_one::<u16>("dpi").unwrap();
         let out_file = matches.get_one::<String>("output").unwrap().as_str();
-        let color_multiplexer = ColorMultiplexer::new(colors).finalize();
-        if matches.get_flag("stresstest") {
+        let verify = matches.get_flag("verifyreproducible");
+        let mut settings = EncodeSettings::new(&matches, colors, multiplex_mode);
+        if verify {
+            // Encode the archive again the way it was recorded as being encoded, apart from any options given to check it differently.
+            let archive = ArchiveHumanOutputFile::new(out_file, format)
+                .finalize();
+            let metadata = read_page_metadata(&archive.page_filename(1), format);
+            if manifest.is_none() && metadata.is_none() {
+                println!("Warning: there's no manifest or readable metadata barcode to take how the archive was encoded from, so going by the options given");
+            }
+            let colors_given = matches.value_source("colors") == Some(ValueSource::CommandLine) || profile.is_some() || palette.is_some();
+            let mode_given = matches.value_source("multiplex") == Some(ValueSource::CommandLine) || profile.is_some();
+            let in_file = matches.get_one::<String>("input").unwrap();
+            settings = settings.recorded(&matches, colors_given, mode_given, in_file, manifest.as_ref(), metadata.as_ref());
+            // Without a manifest, page 0 is only there with --bootstrap.
+            if manifest.is_none() && std::path::Path::new(&archive.page_filename(0)).exists() {
+                settings.bootstrap = true;
+            }
+        }
+        let width = settings.page_width;
+        let height = settings.page_height;
+        let dpi = settings.dpi;
+        let color_multiplexer = make_color_multiplexer(settings.num_colors, settings.multiplex_mode, &profile, &palette);
+        if profile.is_none() && palette.is_none() && settings.multiplex_mode == MultiplexMode::Palette && color_multiplexer.num_colors() != settings.num_colors {
+            println!("Using {} colors, since {} colors can't hold any more data than that", color_multiplexer.num_colors(), settings.num_colors);
+        }
+        if let Some(m) = manifest.as_ref().filter(|_m| verify && profile.is_none() && palette.is_none()) {
+            if m.palette != *color_multiplexer.get_rgb() {
+                println!("Note: the archive was encoded with other colors than the usual ones for {} colors.  Give the color profile or palette it was encoded with again", m.palette.len());
+            }
+        }
+        if color_multiplexer.pixels_per_group() > 1 {
+            println!("Warning: these pages can't be read by standard QR code decoders, only by this program, since {} colors spread their color planes over groups of {} barcodes.  Use a power of two number of colors if they need to be", color_multiplexer.num_colors(), color_multiplexer.pixels_per_group());
+        }
+        if matches.get_flag("calibrate") {
+            // Generate a color calibration sheet.
+            let writer = ArchiveHumanOutputFile::new(out_file, format)
+                .size(width, height)
+                .dpi(dpi)
+                .document_header("Color Calibration - {{total_overlay_colors}} Colors")
+                .document_footer("Scan and decode with --calibrate to make a color profile")
+                .total_pages(1)
+                .colors(color_multiplexer.get_rgb())
+                .inks(color_multiplexer.get_inks())
+                .finalize();
+            let calibration_sheet = CalibrationSheet::new()
+                .finalize();
+            calibration_sheet.encode(&writer, &color_multiplexer);
+        }
+        else if matches.get_flag("stresstest") {
             // Generate a stress test page.
             let header = "Stress Test - {{dpi}} DPI, {{total_overlay_colors}}x Color Packing";
             let writer = ArchiveHumanOutputFile::new(out_file, format)
@@ -148,37 +562,72 @@ fn main() {
                 .dpi(dpi)
                 .document_header(&header)
                 .document_footer("Scan to test limits of printing/scanning")
-                .total_pages(1)
+                .total_pages(StressTestPage::num_pages())
                 .colors(color_multiplexer.get_rgb())
+                .inks(color_multiplexer.get_inks())
                 .finalize();
             let stress_test = StressTestPage::new()
                 .finalize();
             stress_test.encode(&writer, &color_multiplexer);
         }
+        else if matches.get_flag("dryrun") {
+            // Generate a page of known data through the normal encoding path.
+            let writer = ArchiveHumanOutputFile::new(out_file, format)
+                .size(width, height)
+                .dpi(dpi)
+                .document_header("Dry Run - {{dpi}} DPI, {{total_overlay_colors}} Colors")
+                .document_footer("Scan and decode with --dryrun to see which barcodes could be read")
+                .total_pages(1)
+                .colors(color_multiplexer.get_rgb())
+                .inks(color_multiplexer.get_inks())
+                .finalize();
+            let metadata = make_document_metadata(&settings, &color_multiplexer).finalize();
+            let dry_run = make_dry_run_page(&settings);
+            dry_run.encode(&writer, color_multiplexer, &metadata);
+        }
         else {
             // Encode normal data.
             let in_file = matches.get_one::<String>("input").unwrap();
-            let out_file = matches.get_one::<String>("output").unwrap();
+            // When verifying, encode somewhere else and compare what we get with the archive that's already there.
+            let verify_dir = std::env::temp_dir().join(format!("realworldarchive-verify-{}", std::process::id()));
+            let out_file = if verify {
+                std::fs::create_dir_all(&verify_dir).expect("Could not create temporary directory");
+                verify_dir.join("page").to_string_lossy().to_string()
+            }
+            else {
+                out_file.to_string()
+            };
             let mut file_reader = DataFile::new(in_file, false).finalize();
-            let parity_pages = *matches.get_one::<u8>("parity").unwrap();
-            let damage_function = matches.get_one::<String>("ecfunction").unwrap().as_str();
-            let ec_min = *matches.get_one::<u8>("ecmin").unwrap() as f32 / 100.0;
-            let ec_max = *matches.get_one::<u8>("ecmax").unwrap() as f32 / 100.0;
-            let header = in_file;
-            let mut writer = ArchiveHumanOutputFile::new(out_file, format)
+            let parity_pages = settings.parity_pages;
+            let ec_min = settings.ec_min as f32 / 100.0;
+            let ec_max = settings.ec_max as f32 / 100.0;
+            let format_version = settings.format_version;
+            let payload_encoding = settings.payload_encoding;
+            let header = settings.title.as_deref().unwrap_or(in_file);
+            let mut writer = ArchiveHumanOutputFile::new(&out_file, format)
                 .size(width, height)
                 .dpi(dpi)
                 .document_header(&header)
                 .document_footer("Page {{page_num}}/{{total_pages}}")
                 .colors(color_multiplexer.get_rgb())
+                .inks(color_multiplexer.get_inks())
                 .finalize();
             if color_multiplexer.num_colors() > 2 {
                 writer.set_document_footer("Page {{page_num}}/{{total_pages}} - {{total_overlay_colors}} Colors");
             }
             let (w, h) = writer.get_barcode_image_size();
+            let metadata = make_document_metadata(&settings, &color_multiplexer)
+                .total_length(file_reader.stream_len())
+                .sha256(if settings.sha256 { Some(file_reader.sha256()) } else { None })
+                .finalize();
+            let palette = color_multiplexer.get_rgb().clone();
             let mut barcode_packer = PageBarcodePacker::new(w, h, BarcodeFormat::QR)
                 .color_multiplexer(color_multiplexer)
-                .damage_likelihood_map(if damage_function == "constant" { make_constant_damage_map(ec_min) } else { make_radial_damage_map(ec_min, ec_max) })
+                .document_metadata(if settings.include_metadata { Some(metadata.clone()) } else { None })
+                .format_version(format_version)
+                .payload_encoding(payload_encoding)
+                .interleave_planes(settings.interleave_planes)
+                .damage_likelihood_map(if settings.ec_function == EcFunction::Constant { make_constant_damage_map(ec_min) } else { make_radial_damage_map(ec_min, ec_max) })
                 .finalize();
             //println!("Maximum bytes per page: {}", barcode_packer.data_bytes_per_page());
 
@@ -188,18 +637,29 @@ fn main() {
             let total_len = file_reader.stream_len();
             //println!("Total file length: {}", total_len);
             let max_block_size = barcode_packer.data_bytes_per_page() as u64;
-            let total_pages_at_max_data_rate = ((total_len + (max_block_size - 1)) / max_block_size) as u16; // See https://www.reddit.com/r/rust/comments/bk7v15/my_next_favourite_way_to_divide_integers_rounding/
+            // An empty file still gets a page, so there's something to decode it from.
+            let total_pages_at_max_data_rate = total_len.div_ceil(max_block_size).max(1) as u16;
             let min_bytes_per_page = ((total_len + (total_pages_at_max_data_rate as u64 - 1)) / (total_pages_at_max_data_rate as u64)) as u32; // Redividing this so we can round properly.
             while barcode_packer.repack_barcodes_for_page_length(min_bytes_per_page) {};
             //println!("Ideal bytes per page: {}", barcode_packer.data_bytes_per_page());
+            if !barcode_packer.metadata_fits() {
+                if metadata.sha256.is_some() {
+                    panic!("Barcodes on this page are too small to hold the SHA-256 digest in the metadata barcode");
+                }
+                if barcode_packer.has_metadata_barcode() {
+                    panic!("Barcodes on this page are too small to hold the document metadata.  Use fewer colors, a lower DPI or a larger page, or leave the metadata out with --nometadata");
+                }
+                println!("Warning: there's only room for one barcode on each page, so the document metadata is left out");
+            }
             
             // Write to image files as a quick test.
             let mut out_image = RgbImage::new(w, h);
             //println!("Checking for data bytes per page");
             let block_size = barcode_packer.data_bytes_per_page() as u64;
-            let total_data_pages = ((total_len + (block_size - 1)) / block_size) as u16;
+            let total_data_pages = total_len.div_ceil(block_size).max(1) as u16;
             let total_pages = total_data_pages + parity_pages as u16;
             writer.set_total_pages(total_pages);
+            barcode_packer.set_page_counts(total_data_pages, parity_pages);
             //println!("Block size: {}", block_size);
             let mut block_buffer_vec: Vec<u8> = vec![];
             for _b in 0..block_size {
@@ -208,7 +668,12 @@ fn main() {
             let block_buffer: &mut [u8] = block_buffer_vec.as_mut_slice();
             let file_checksum = file_reader.file_hash();
             //println!("File checksum: {}", (file_checksum & 0x00ffffff));
-            while start_offset < total_len {
+            if settings.bootstrap {
+                println!("Generating format description page...");
+                let description = describe_format(&metadata, format_version, payload_encoding, block_size as u32, total_data_pages, file_checksum, &palette, barcode_packer.layout_id());
+                writer.write_text_page(&description, 0);
+            }
+            while start_offset < total_len || start_offset == 0 {
                 // Page numbers are 1-based to match what's shown to the user.
                 let page_number = ((start_offset / block_size) as u16) + 1;
                 println!("Generating page {}...", page_number);
@@ -311,19 +776,137 @@ fn main() {
                     writer.write_page(&out_image, page_number);
                 }
             }
+
+            // Write the manifest, so the archive can be checked later without decoding it.
+            let mut barcodes: Vec<ManifestBarcode> = barcode_packer.barcode_slots(1, file_checksum).iter().map(|b| ManifestBarcode {
+                x: b.x,
+                y: b.y,
+                width: b.width,
+                height: b.height,
+                version: b.version,
+                ec_level: b.ec_level,
+                is_metadata: b.is_metadata
+            }).collect();
+            barcodes.sort_by_key(|b| (b.y, b.x));
+            let mut encoded_manifest = ArchiveManifest {
+                title: header.to_string(),
+                input_length: total_len,
+                file_hash: file_checksum,
+                format_version,
+                payload_encoding,
+                page_bytes: block_size as u32,
+                data_pages: total_data_pages,
+                parity_pages,
+                page_width: width,
+                page_height: height,
+                dpi,
+                multiplex_mode: settings.multiplex_mode,
+                palette: palette.clone(),
+                layout_id: barcode_packer.layout_id(),
+                barcodes,
+                pages: vec![]
+            };
+            encoded_manifest.add_pages(|p| writer.page_filename(p));
+            let encoded_manifest_file = manifest_filename(&out_file);
+            encoded_manifest.save(&encoded_manifest_file).expect("Could not save manifest");
+            if verify {
+                let archive = ArchiveHumanOutputFile::new(matches.get_one::<String>("output").unwrap(), format)
+                    .finalize();
+                let reproducible = verify_reproducible(&encoded_manifest, &archive, manifest.as_ref());
+                std::fs::remove_dir_all(&verify_dir).expect("Could not remove temporary directory");
+                if !reproducible {
+                    panic!("The archive does not match what these options encode to now");
+                }
+            }
+            else {
+                println!("Wrote manifest to {}", encoded_manifest_file);
+            }
+        }
+    }
+    else if matches.get_flag("simulate") {
+        // Damage each page as if it had been printed and scanned.
+        let in_file: &String = matches.get_one("input").unwrap();
+        let out_dir: &String = matches.get_one("output").unwrap();
+        let spec: &String = matches.get_one("damage").unwrap();
+        let simulator = ScanSimulator::new()
+            .seed(*matches.get_one::<u64>("seed").unwrap())
+            .damage_spec(spec)
+            .unwrap_or_else(|e| panic!("{}: {}", e, spec))
+            .finalize();
+        std::fs::create_dir_all(out_dir).expect("Could not create output directory");
+        let in_files_glob = glob(in_file).expect("Failed to read glob pattern");
+        for (i, entry) in in_files_glob.filter_map(|f| f.ok()).enumerate() {
+            let in_path = entry.to_string_lossy().to_string();
+            let reader = ArchiveHumanInputFile::new(&in_path, format)
+                .finalize();
+            let page = reader.read_page().unwrap_or_else(|| panic!("Could not read page {}", in_path));
+            let out_path = std::path::Path::new(out_dir).join(entry.file_name().unwrap()).with_extension("png");
+            println!("Damaging {} into {}", in_path, out_path.display());
+            simulator.damage(&page, i as u64 + 1).save(&out_path).expect("Could not save damaged page");
         }
     }
     else {
         // Decode.
         let in_file: &String = matches.get_one("input").unwrap();
-        let mut color_multiplexer = ColorMultiplexer::new(colors).finalize();
-        if matches.get_flag("stresstest") {
-            // Decode a stress test page.
+        let mut color_multiplexer = make_color_multiplexer(colors, multiplex_mode, &profile, &palette);
+        if matches.get_flag("calibrate") {
+            // Read a scanned calibration sheet and save what we measured as a color profile.
+            let out_file: &String = matches.get_one("output").unwrap();
             let reader = ArchiveHumanInputFile::new(in_file, format)
                 .finalize();
+            let calibration_sheet = CalibrationSheet::new()
+                .finalize();
+            let samples = calibration_sheet.decode(&reader, &color_multiplexer).unwrap_or_else(|e| panic!("{}", e));
+            let name = std::path::Path::new(out_file).file_stem().unwrap().to_string_lossy().to_string();
+            let new_profile = ColorProfile::from_samples(&name, multiplex_mode, color_multiplexer.get_rgb(), &samples, matches.get_flag("predistort")).unwrap();
+            new_profile.save(out_file).expect("Could not save color profile");
+            println!("Saved color profile {} to {}", name, out_file);
+        }
+        else if matches.get_flag("stresstest") {
+            // Decode the stress test pages.
+            let filenames: Vec<String> = glob(in_file).expect("Failed to read glob pattern").filter_map(|f| f.ok()).map(|f| f.to_string_lossy().to_string()).collect();
+            let readers: Vec<ArchiveHumanInputFile> = filenames.iter().map(|f| ArchiveHumanInputFile::new(f, format).finalize()).collect();
             let stress_test = StressTestPage::new()
                 .finalize();
-            stress_test.decode(&reader, &color_multiplexer);
+            let measurements = stress_test.decode(&readers, &color_multiplexer, matches.get_one::<String>("output"));
+            if matches.get_flag("recommend") {
+                let width = *matches.get_one::<f32>("pagewidth").unwrap();
+                let height = *matches.get_one::<f32>("pageheight").unwrap();
+                println!();
+                println!("Working out recommended settings...");
+                match recommend_settings(&measurements, width, height, *matches.get_one::<f32>("margin").unwrap()) {
+                    Some(recommendation) => {
+                        println!();
+                        recommendation.print_report(width, height, *matches.get_one::<u16>("dpi").unwrap());
+                    },
+                    None => println!("Nothing on the stress test was read reliably enough to recommend settings with this safety margin")
+                }
+            }
+        }
+        else if matches.get_flag("dryrun") {
+            // Check a scanned dry run page against the data we know is on it.
+            let out_file: &String = matches.get_one("output").unwrap();
+            let mut metadata_reader = ArchiveHumanInputFile::new(in_file, format)
+                .finalize();
+            let mut decoder = FileDecoder::new(&mut metadata_reader).finalize();
+            let metadata = match decoder.read_metadata() {
+                Some(m) => {
+                    m.print_report();
+                    m.clone()
+                },
+                None => {
+                    println!("Could not read the metadata barcode, so going by the options given");
+                    make_document_metadata(&EncodeSettings::new(&matches, colors, multiplex_mode), &color_multiplexer).finalize()
+                }
+            };
+            let colors_given = matches.value_source("colors") == Some(ValueSource::CommandLine) || profile.is_some() || palette.is_some();
+            let mode_given = matches.value_source("multiplex") == Some(ValueSource::CommandLine) || profile.is_some();
+            let mode = if mode_given { multiplex_mode } else { metadata.multiplex_mode };
+            color_multiplexer = make_color_multiplexer(if colors_given { colors } else { metadata.num_colors }, mode, &profile, &palette);
+            let reader = ArchiveHumanInputFile::new(in_file, format)
+                .finalize();
+            let dry_run = make_dry_run_page(&EncodeSettings::new(&matches, colors, multiplex_mode));
+            dry_run.decode(&reader, color_multiplexer, &metadata, true, out_file);
         }
         else {
             // Decode normal data.
@@ -332,7 +915,9 @@ fn main() {
             let in_files_glob = glob(in_file).expect("Failed to read glob pattern");
             let mut first_file = true;
             let mut chunk_info = vec![];
-            let mut parity_buffer: Vec<Vec<u8>> = vec![]; // Each element is a vector of bytes for that page.
+            let mut parity_buffer: Vec<Vec<Option<u8>>> = vec![]; // Each element is a vector of bytes for that page, with None where no barcode was read.
+            let mut document_metadata: Option<DocumentMetadata> = None;
+            let mut discarded_barcodes = 0;
             for f in in_files_glob {
                 match f {
                     Ok(filename) => {
@@ -340,10 +925,45 @@ fn main() {
                         let mut one_in_file = ArchiveHumanInputFile::new(filename.to_str().unwrap(), format);
                         let mut decoder = FileDecoder::new(&mut one_in_file).finalize();
 
-                        // If it's the first page, go ahead and re-palettize the color multiplexer based on the colors found in it, to account for color distortion in the printing/scanning process.
-                        let mut chunks_on_page = decoder.decode(&mut file_writer, &mut parity_buffer, &mut color_multiplexer, first_file);
+                        // Every page has the metadata barcode, so keep looking until one of them can be read.
+                        if document_metadata.is_none() {
+                            document_metadata = decoder.read_metadata().cloned();
+                        }
+
+                        // Use the metadata from the first page with barcodes on it to configure ourselves, unless we've been told how many colors to use.
+                        if first_file {
+                            let colors_given = matches.value_source("colors") == Some(ValueSource::CommandLine);
+                            let mode_given = matches.value_source("multiplex") == Some(ValueSource::CommandLine) || profile.is_some();
+                            if let Some(metadata) = &document_metadata {
+                                metadata.print_report();
+                                let mode = if mode_given { multiplex_mode } else { metadata.multiplex_mode };
+                                let num_colors = if colors_given { colors } else { metadata.num_colors };
+                                color_multiplexer = make_color_multiplexer(num_colors, mode, &profile, &palette);
+                                if (colors_given || profile.is_some() || palette.is_some()) && metadata.num_colors != color_multiplexer.num_colors() {
+                                    println!("Warning: document was encoded with {} colors, but we were told to decode using {}", metadata.num_colors, color_multiplexer.num_colors());
+                                }
+                                if mode != metadata.multiplex_mode {
+                                    println!("Warning: document was encoded with {:?} multiplexing, but we were told to decode using {:?}", metadata.multiplex_mode, mode);
+                                }
+                            }
+                            else if !colors_given && profile.is_none() && palette.is_none() {
+                                // No metadata, so we'll have to work it out from the page itself.
+                                if let Some(detected_colors) = decoder.detect_num_colors() {
+                                    println!("Detected {} colors", detected_colors);
+                                    color_multiplexer = ColorMultiplexer::new(detected_colors).mode(multiplex_mode).finalize();
+                                }
+                            }
+                        }
+
+                        // Re-palettize the color multiplexer based on the colors found on each page, to account for color distortion in the printing/scanning process.
+                        // Pages can be printed and scanned differently, so this is done for every page rather than just the first.
+                        let mut chunks_on_page = decoder.decode(&mut file_writer, &mut parity_buffer, &mut color_multiplexer, true);
+                        // Pages without any barcodes, like the format description page, don't count as the first page.
+                        if !chunks_on_page.is_empty() {
+                            first_file = false;
+                        }
                         chunk_info.append(&mut chunks_on_page);
-                        first_file = false;
+                        discarded_barcodes += decoder.discarded_barcodes();
                     },
                     Err(e) => println!("{:?}", e)
                 }
@@ -355,6 +975,13 @@ fn main() {
             if chunk_info.len() < 1 {
                 panic!("Could not find even a single barcode to read");
             }
+            let mut format_versions: Vec<u8> = chunk_info.iter().map(|c| c.format_version).collect();
+            format_versions.sort();
+            format_versions.dedup();
+            println!("Read {} barcodes in format version {:?}", chunk_info.len(), format_versions);
+            if discarded_barcodes > 0 {
+                println!("Discarded {} barcodes whose checksums didn't match - their data counts as missing", discarded_barcodes);
+            }
 
             // Sort the chunks by start offset for easier detection later.
             // TODO: Use something other than bubble sort.
@@ -415,7 +1042,7 @@ fn main() {
             page_numbers_we_have.dedup();
 
             // Sort the ranges by starting offset.
-            for i in 0..ranges.len() - 1 {
+            for i in 0..ranges.len().saturating_sub(1) {
                 for j in (i + 1)..ranges.len() {
                     if ranges[j][0] < ranges[i][0] {
                         let temp = ranges[j];
@@ -426,53 +1053,79 @@ fn main() {
             }
 
             //println!("Ranges we have: {:?}", ranges);
+            // The barcodes we read can all be padding or parity, which leaves the whole file missing.
             let mut missing_ranges = vec![];
-            if ranges[0][0] != 0 {
-                missing_ranges.push([0, ranges[0][0]]);
-            }
-            for i in 0..ranges.len() - 1 {
-                missing_ranges.push([ranges[i][1], ranges[i + 1][0]]);
+            let mut covered_to = 0;
+            for r in &ranges {
+                if r[0] > covered_to {
+                    missing_ranges.push([covered_to, r[0]]);
+                }
+                covered_to = r[1];
             }
 
-            if chunk_info[0].total_length != ranges[ranges.len() - 1][1] {
+            if chunk_info[0].total_length != covered_to {
                 // We're missing a chunk at the end.
                 // Add it to the list so we can attempt recovery.
-                missing_ranges.push([ranges[ranges.len() - 1][1], chunk_info[0].total_length]);
+                missing_ranges.push([covered_to, chunk_info[0].total_length]);
             }
             if missing_ranges.len() > 0 {
                 println!("Missing chunks...attempting recovery...");
                 
                 // First, we need to figure out how large a page is.
+                // Version 2 and later record it in every barcode.
                 let mut page_size = 0;
-                for c in 0..chunk_info.len() - 1 {
-                    if chunk_info[c].is_parity {
-                        continue;
+                for c in &chunk_info {
+                    if c.bytes_per_page != 0 {
+                        page_size = c.bytes_per_page as u64;
+                        if !same_barcode_arrangement(c.layout_id, barcode_layout(&color_multiplexer)) {
+                            println!("Warning: document was encoded with barcode layout {}, but we decoded using {} - reconstruction may not work", c.layout_id, barcode_layout(&color_multiplexer));
+                        }
+                        if c.color_planes != color_multiplexer.num_planes() {
+                            println!("Warning: document was encoded with {} color planes, but we decoded using {}", c.color_planes, color_multiplexer.num_planes());
+                        }
+                        break;
                     }
-                    for d in (c + 1)..chunk_info.len() {
-                        if chunk_info[d].is_parity {
+                }
+
+                // Version 1 doesn't, so we have to infer it from matching barcodes on consecutive pages.
+                if page_size == 0 {
+                    for c in 0..chunk_info.len() - 1 {
+                        if chunk_info[c].is_parity {
                             continue;
                         }
+                        for d in (c + 1)..chunk_info.len() {
+                            if chunk_info[d].is_parity {
+                                continue;
+                            }
 
-                        // If barcode numbers match and page number only differs by 1, then we have a stride we can work with.
-                        if chunk_info[c].barcode_number == chunk_info[d].barcode_number && (chunk_info[c].page_number as i32 - chunk_info[d].page_number as i32).abs() == 1 {
-                            page_size = chunk_info[c].start_offset.max(chunk_info[d].start_offset) - chunk_info[c].start_offset.min(chunk_info[d].start_offset);
+                            // If barcode numbers match and page number only differs by 1, then we have a stride we can work with.
+                            if chunk_info[c].barcode_number == chunk_info[d].barcode_number && (chunk_info[c].page_number as i32 - chunk_info[d].page_number as i32).abs() == 1 {
+                                page_size = chunk_info[c].start_offset.max(chunk_info[d].start_offset) - chunk_info[c].start_offset.min(chunk_info[d].start_offset);
+                            }
+                        }
+                        if page_size != 0 {
+                            break;
                         }
-                    }
-                    if page_size != 0 {
-                        break;
                     }
                 }
                 if page_size == 0 {
                     panic!("Could not find enough information to calculate page size.  Unable to continue with reconstruction of missing chunks.");
                 }
 
+                // If we know how many parity pages there should be, make sure we account for ones we didn't find at all so the Reed-Solomon matrix is the same size it was when encoding.
+                let recorded_data_pages = chunk_info.iter().map(|c| c.data_pages as u64).max().unwrap_or(0);
+                let recorded_parity_pages = chunk_info.iter().map(|c| c.parity_pages as usize).max().unwrap_or(0);
+                while parity_buffer.len() < recorded_parity_pages {
+                    parity_buffer.push(vec![]);
+                }
+
                 // Attempt to reconstruct each chunk.
                 while missing_ranges.len() > 0 {
                     // TODO: Do this in batches instead of one byte at a time, to save on I/O operations.  We're only doing it this way for now for simplicity.
                     for b in missing_ranges[0][0]..missing_ranges[0][1] {
                         // For each missing byte...
                         let total_length = chunk_info[0].total_length;
-                        let num_data_pages = (total_length + page_size - 1) / page_size;
+                        let num_data_pages = if recorded_data_pages > 0 { recorded_data_pages } else { total_length.div_ceil(page_size) };
                         //let total_length_rounded_up_for_padding = page_size * num_pages;
                         let offset_into_parity = b % page_size;
                         let missing_on_page_number = b / page_size;
@@ -500,14 +1153,9 @@ fn main() {
                         }
 
                         // Add the parity onto the end and try to reconstruct.
+                        // Parity from barcodes which were lost or discarded is missing, just like missing data.
                         for p in 0..parity_pages as usize {
-                            recovery_byte_buffer.push(
-                                if (offset_into_parity as usize) < parity_buffer[p].len() {
-                                    Some(vec![parity_buffer[p][offset_into_parity as usize]])
-                                }
-                                else {
-                                    None
-                                });
+                            recovery_byte_buffer.push(parity_buffer[p].get(offset_into_parity as usize).copied().flatten().map(|b| vec![b]));
                         }
                         //println!("Byte buffer before reconstruction: {:?}", recovery_byte_buffer);
                         dec.reconstruct(recovery_byte_buffer.as_mut_slice()).unwrap();
@@ -544,6 +1192,32 @@ fn main() {
                 panic!("File checksum {} did not match the expected {}", hash, chunk_info[0].hash);
             }
             println!("File passed integrity checks!");
+            // Asked for on the command line, a digest has to be checked, rather than being checked only if one turns up.
+            let require_sha256 = matches.get_flag("sha256");
+            match document_metadata.as_ref().map(|m| m.sha256) {
+                Some(Some(digest)) => {
+                    if file_writer.sha256() != digest {
+                        panic!("File does not match the SHA-256 digest in the metadata barcode");
+                    }
+                    println!("File matches the SHA-256 digest in the metadata barcode");
+                },
+                Some(None) => if require_sha256 {
+                    panic!("The metadata barcode has no SHA-256 digest to check the file against");
+                },
+                None => if require_sha256 {
+                    panic!("The metadata barcode could not be read, so the file could not be checked against its SHA-256 digest");
+                }
+                else {
+                    println!("Warning: the metadata barcode could not be read, so the SHA-256 digest, if one was stored, could not be checked");
+                }
+            }
+            if let Some(m) = &manifest {
+                // Barcodes only hold the low 24 bits of the hash, but the manifest has all of it.
+                if m.input_length != file_writer.stream_len() || m.file_hash != file_writer.file_hash() {
+                    panic!("File does not match the manifest's length of {} and hash of {:08x}", m.input_length, m.file_hash);
+                }
+                println!("File matches the manifest's length and full 32-bit hash");
+            }
         }
     }
 }
@@ -2,29 +2,184 @@
 
 use image::Rgb;
 use image::imageops;
-use qrencode::QrCode;
+use qrencode::{QrCode, QrResult};
 use qrencode::bits::Bits;
 use qrencode::types::{Version, EcLevel, Mode};
 use image::RgbImage;
 use imageproc::rect::Rect;
 use imageproc::drawing::*;
 use crate::color_multiplexer::ColorMultiplexer;
-use base45::encode;
+use crate::document_metadata::DocumentMetadata;
+use crate::payload_encoding::{PayloadEncoding, encode_payload, max_unstuffed_length, FORMAT_VERSION_BYTE_MODE_FLAG};
 
 // Quiet zone size between QR codes, in pixels.  Default is a little more than the required 4, but not 10 like some folks recommend.  If this is unreliable, we might need to change it.
 // Experimentally determined to need to be around 40 to work around https://github.com/piderman314/bardecoder/issues/50
-const QUIET_ZONE_SIZE:u8 = 8; //6;
+pub const QUIET_ZONE_SIZE:u8 = 8; //6;
 
 const MAX_QR_VERSION_TO_TRY:i16 = 20;
 
+// Latest format version we know how to write.
+pub const LATEST_FORMAT_VERSION: u8 = 3;
+
+// Flags in the high byte of the barcode number.
+const BARCODE_NUMBER_PARITY_FLAG: u8 = 0b10000000;
+pub const BARCODE_NUMBER_METADATA_FLAG: u8 = 0b00010000;
+
+// Metadata barcodes are small and important, so they always get the most error correction.
+const METADATA_EC_LEVEL: EcLevel = EcLevel::H;
+
+// Barcode layout IDs, recorded in version 2 headers so decoders know how barcodes were arranged.
+// 1 = a uniform grid of equally-sized barcodes, shuffled pseudorandomly on each page, with color planes holding consecutive chunks of data.
+pub const BARCODE_LAYOUT_GRID: u8 = 1;
+// 2 = the same grid, but with each set of color planes spread over a group of barcodes side by side, for palettes which aren't a power of two.
+pub const BARCODE_LAYOUT_GROUPED: u8 = 2;
+// Set on top of either layout when each color plane of a square holds data from a different part of the page, rather than consecutive chunks.
+// Planes are filled one at a time across every square on the page, so a smudge over one square costs a little of several ranges instead of one long run.
+pub const BARCODE_LAYOUT_INTERLEAVED_FLAG: u8 = 0x80;
+
 // This doesn't really matter that much - we're not going for cryptographic security here, just for jumbling for damage resistance.
 const PRNG_PRIME:u64 = 2147483647;
 
+// Layout ID for pages multiplexed with the given colors.
+pub fn barcode_layout(color_multiplexer: &ColorMultiplexer) -> u8 {
+    if color_multiplexer.pixels_per_group() > 1 { BARCODE_LAYOUT_GROUPED } else { BARCODE_LAYOUT_GRID }
+}
+
+// Whether two layout IDs put barcodes in the same places, which is all decoding needs - interleaving only changes which offsets they hold, and those are in every header.
+pub fn same_barcode_arrangement(a: u8, b: u8) -> bool {
+    (a & !BARCODE_LAYOUT_INTERLEAVED_FLAG) == (b & !BARCODE_LAYOUT_INTERLEAVED_FLAG)
+}
+
 #[derive(Copy, Clone)]
 pub enum BarcodeFormat {
     QR
 }
 
+// Number of bytes of header at the start of each barcode for a given format version.
+pub fn header_length(format_version: u8) -> u32 {
+    let bytes_for_version = 1;
+    let bytes_for_page_number = 2;
+    let bytes_for_barcode_number = 2;
+    let bytes_for_offset = 6;
+    let bytes_for_total_length = 6;
+    let bytes_for_hash = 3;
+    let mut overhead = bytes_for_version + bytes_for_page_number + bytes_for_barcode_number + bytes_for_offset + bytes_for_total_length + bytes_for_hash;
+    if format_version >= 2 {
+        let bytes_for_bytes_per_page = 4;
+        let bytes_for_data_page_count = 2;
+        let bytes_for_parity_page_count = 1;
+        let bytes_for_color_plane_count = 1;
+        let bytes_for_layout_id = 1;
+        overhead += bytes_for_bytes_per_page + bytes_for_data_page_count + bytes_for_parity_page_count + bytes_for_color_plane_count + bytes_for_layout_id;
+    }
+    if format_version >= 3 {
+        let bytes_for_barcode_checksum = 4;
+        overhead += bytes_for_barcode_checksum;
+    }
+    overhead
+}
+
+// Whether we know how to read barcodes written with a given format version.
+pub fn supported_format_version(format_version: u8) -> bool {
+    (1..=LATEST_FORMAT_VERSION).contains(&format_version)
+}
+
+// Where the barcode checksum sits in version 3 headers - right after the version 2 fields.
+const BARCODE_CHECKSUM_OFFSET: usize = 29;
+
+// CRC-32 of everything in the barcode except the checksum itself.
+fn barcode_checksum(barcode_data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&barcode_data[..BARCODE_CHECKSUM_OFFSET]);
+    hasher.update(&barcode_data[BARCODE_CHECKSUM_OFFSET + 4..]);
+    hasher.finalize()
+}
+
+// Whether a decoded barcode holds what was encoded.  Barcodes from before version 3 have no checksum, so they always pass.
+// QR error correction usually catches damage, but a badly damaged barcode can still decode to the wrong bytes now and then.
+pub fn barcode_checksum_matches(data_chunk: &[u8]) -> bool {
+    let format_version = data_chunk[0] & !FORMAT_VERSION_BYTE_MODE_FLAG;
+    if format_version < 3 {
+        return true;
+    }
+    if data_chunk.len() < header_length(format_version) as usize {
+        return false;
+    }
+    let stored = u32::from_be_bytes(data_chunk[BARCODE_CHECKSUM_OFFSET..BARCODE_CHECKSUM_OFFSET + 4].try_into().unwrap());
+    stored == barcode_checksum(data_chunk)
+}
+
+// What a barcode's header says, as written by generate_header.
+pub struct BarcodeHeader {
+    pub format_version: u8,
+    pub payload_encoding: PayloadEncoding,
+    pub page_number: u16,
+    // Including the parity and metadata flags in its high bits.
+    pub barcode_number: u16,
+    pub is_parity: bool,
+    pub is_metadata: bool,
+    // For parity barcodes, this is within the page's data rather than the file.
+    pub start_offset: u64,
+    // Which parity page this is, counting from 0.  Only meaningful for parity barcodes.
+    pub parity_index: u8,
+    pub total_length: u64,
+    // Low 24 bits of the file hash.
+    pub hash: u32,
+    // Layout fields added in version 2.  Zero in version 1 barcodes.
+    pub bytes_per_page: u32,
+    pub data_pages: u16,
+    pub parity_pages: u8,
+    pub color_planes: u8,
+    pub layout_id: u8
+}
+
+// Reads the header at the start of a decoded barcode.  Doesn't check the barcode checksum - use barcode_checksum_matches for that.
+pub fn parse_barcode_header(data_chunk: &[u8]) -> Result<BarcodeHeader, &'static str> {
+    if data_chunk.is_empty() {
+        return Err("Data was blank");
+    }
+    let format_version = data_chunk[0] & !FORMAT_VERSION_BYTE_MODE_FLAG;
+    if !supported_format_version(format_version) {
+        return Err("Unsupported format version");
+    }
+    if data_chunk.len() < header_length(format_version) as usize {
+        return Err("Barcode is too short to hold its header");
+    }
+    let is_parity = data_chunk[3] & BARCODE_NUMBER_PARITY_FLAG != 0;
+    let (bytes_per_page, data_pages, parity_pages, color_planes, layout_id) = match format_version {
+        1 => (0, 0, 0, 0, 0),
+        _ => (
+            u32::from_be_bytes([data_chunk[20], data_chunk[21], data_chunk[22], data_chunk[23]]),
+            u16::from_be_bytes([data_chunk[24], data_chunk[25]]),
+            data_chunk[26],
+            data_chunk[27],
+            data_chunk[28]
+        )
+    };
+    Ok(BarcodeHeader {
+        format_version,
+        payload_encoding: if data_chunk[0] & FORMAT_VERSION_BYTE_MODE_FLAG != 0 { PayloadEncoding::Byte } else { PayloadEncoding::Base45 },
+        page_number: u16::from_be_bytes([data_chunk[1], data_chunk[2]]),
+        barcode_number: u16::from_be_bytes([data_chunk[3], data_chunk[4]]),
+        is_parity,
+        is_metadata: data_chunk[3] & BARCODE_NUMBER_METADATA_FLAG != 0,
+        // Parity barcodes give the first two of these bytes over to the parity index.
+        start_offset: if is_parity {
+            u64::from_be_bytes([0, 0, 0, 0, data_chunk[7], data_chunk[8], data_chunk[9], data_chunk[10]])
+        } else {
+            u64::from_be_bytes([0, 0, data_chunk[5], data_chunk[6], data_chunk[7], data_chunk[8], data_chunk[9], data_chunk[10]])
+        },
+        parity_index: data_chunk[6],
+        total_length: u64::from_be_bytes([0, 0, data_chunk[11], data_chunk[12], data_chunk[13], data_chunk[14], data_chunk[15], data_chunk[16]]),
+        hash: u32::from_be_bytes([0, data_chunk[17], data_chunk[18], data_chunk[19]]),
+        bytes_per_page,
+        data_pages,
+        parity_pages,
+        color_planes,
+        layout_id
+    })
+}
+
 pub type DamageLikelihoodMap = Box<dyn Fn(f32, f32) -> f32>;
 
 // Always returns a constant damage likelihood.
@@ -54,7 +209,22 @@ struct MultiplexedBarcodeInfo {
     version: Version,
     ec_level: EcLevel,
 //    mode: Mode,
-    capacity_per_color_plane: u32
+    capacity_per_color_plane: u32,
+    // Holds document metadata instead of data.
+    is_metadata: bool
+}
+
+// Where one square of barcodes goes on a page, for checking which of them could be read.
+pub struct BarcodeSlot {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub version: i16,
+    pub ec_level: EcLevel,
+    // Where each color plane's data starts within the page.  Empty for the metadata barcode, which holds no data.
+    pub plane_offsets: Vec<u64>,
+    pub is_metadata: bool
 }
 
 pub struct PageBarcodePacker {
@@ -64,6 +234,11 @@ pub struct PageBarcodePacker {
     color_multiplexer: ColorMultiplexer,
     damage_likelihood_map: DamageLikelihoodMap,
     format_version: u8,
+    payload_encoding: PayloadEncoding,
+    document_metadata: Option<DocumentMetadata>,
+    data_pages: u16,
+    parity_pages: u8,
+    interleave_planes: bool,
     packing_cached: bool,
     cache_barcodes: Vec<MultiplexedBarcodeInfo>,
     cache_bytes_per_page: u32
@@ -75,7 +250,12 @@ impl<'a> PageBarcodePacker {
             width,
             height,
             barcode_format,
-            format_version: 1,
+            format_version: LATEST_FORMAT_VERSION,
+            payload_encoding: PayloadEncoding::Base45,
+            document_metadata: None,
+            data_pages: 0,
+            parity_pages: 0,
+            interleave_planes: false,
             color_multiplexer: ColorMultiplexer::new(2).finalize(),
             packing_cached: false,
             cache_barcodes: vec!(),
@@ -99,13 +279,27 @@ impl<'a> PageBarcodePacker {
         self
     }
 
-    /*pub fn format_version(mut self, v: u8) -> Self{
+    pub fn format_version(mut self, v: u8) -> Self{
         if self.format_version != v {
             self.packing_cached = false;
         }
         self.format_version = v;
         self
-    }*/
+    }
+
+    pub fn payload_encoding(mut self, e: PayloadEncoding) -> Self {
+        if self.payload_encoding != e {
+            self.packing_cached = false;
+        }
+        self.payload_encoding = e;
+        self
+    }
+
+    pub fn document_metadata(mut self, m: Option<DocumentMetadata>) -> Self {
+        self.packing_cached = false;
+        self.document_metadata = m;
+        self
+    }
 
     pub fn color_multiplexer(mut self, c: ColorMultiplexer) -> Self {
         self.packing_cached = false;
@@ -113,6 +307,12 @@ impl<'a> PageBarcodePacker {
         self
     }
 
+    // Doesn't change how much fits on a page, so the packing stays cached.
+    pub fn interleave_planes(mut self, i: bool) -> Self {
+        self.interleave_planes = i;
+        self
+    }
+
     pub fn finalize(self) -> PageBarcodePacker {
         let mut out = PageBarcodePacker {
             width: self.width,
@@ -121,6 +321,11 @@ impl<'a> PageBarcodePacker {
             color_multiplexer: self.color_multiplexer,
             damage_likelihood_map: self.damage_likelihood_map,
             format_version: self.format_version,
+            payload_encoding: self.payload_encoding,
+            document_metadata: self.document_metadata,
+            data_pages: self.data_pages,
+            parity_pages: self.parity_pages,
+            interleave_planes: self.interleave_planes,
             packing_cached: self.packing_cached,
             cache_barcodes: self.cache_barcodes,
             cache_bytes_per_page: self.cache_bytes_per_page
@@ -140,15 +345,18 @@ impl<'a> PageBarcodePacker {
         let v = qr_version; // Size ("version") of QR code - version 40 does not seem to be recognized well
         let qrv = Version::Normal(v);
         let barcode_size: u32 = qrv.width() as u32;
+        // Grouped palettes need room for a whole group of barcodes side by side.
+        let group = self.color_multiplexer.pixels_per_group();
+        let group_width = barcode_size * group + QUIET_ZONE_SIZE as u32 * (group - 1);
         let mut cache_barcodes: Vec<MultiplexedBarcodeInfo> = vec![];
         let mut cache_bytes_per_page: u32 = 0;
         // This is a very quick approximation of where the barcodes should be.
-        let centering_offset_left = (self.width % (barcode_size + QUIET_ZONE_SIZE as u32) + QUIET_ZONE_SIZE as u32) / 2;
+        let centering_offset_left = (self.width % (group_width + QUIET_ZONE_SIZE as u32) + QUIET_ZONE_SIZE as u32) / 2;
         let centering_offset_top = (self.height % (barcode_size + QUIET_ZONE_SIZE as u32) + QUIET_ZONE_SIZE as u32) / 2;
         let mut next_x: u32 = centering_offset_left;
         let mut next_y: u32 = centering_offset_top;
         while next_y < self.height {
-            let dl = (self.damage_likelihood_map)((next_x + barcode_size / 2) as f32 / self.width as f32, (next_y + barcode_size / 2) as f32 / self.height as f32);
+            let dl = (self.damage_likelihood_map)((next_x + group_width / 2) as f32 / self.width as f32, (next_y + barcode_size / 2) as f32 / self.height as f32);
             let ec = 
                 if dl >= 0.0 && dl < 0.25 {
                     EcLevel::L
@@ -162,22 +370,10 @@ impl<'a> PageBarcodePacker {
                 else {
                     EcLevel::H
                 };
-            let bits = Bits::new(qrv);
-            let max_bits = bits.max_len(ec).unwrap();
-            let metadata_bits = Mode::Alphanumeric.length_bits_count(qrv) + 4 + qrv.mode_bits_count();
-            let max_bytes: u32 = (max_bits - metadata_bits) as u32 / 8;
-            let bytes_for_version = 1;
-            let bytes_for_page_number = 2;
-            let bytes_for_barcode_number = 2;
-            let bytes_for_offset = 6;
-            let bytes_for_total_length = 6;
-            let bytes_for_hash = 3;
-            let overhead = bytes_for_version + bytes_for_page_number + bytes_for_barcode_number + bytes_for_offset + bytes_for_total_length + bytes_for_hash;
-            let data_capacity_per_color_bit_unencoded: u32 = max_bytes - overhead;
-            let data_capacity_per_color_bit = data_capacity_per_color_bit_unencoded * 2 / 3;
-            let data_capacity = data_capacity_per_color_bit;
-
-            if next_y + barcode_size > self.height {
+            let data_capacity = self.data_capacity(qrv, ec);
+
+            // A group of barcodes might not fit across the page at all.
+            if next_y + barcode_size > self.height || next_x + group_width > self.width {
                 break;
             }
 
@@ -189,8 +385,9 @@ impl<'a> PageBarcodePacker {
                 //damage_likelihood: dl,
                 version: qrv,
                 ec_level: ec,
-                //mode: Mode::Alphanumeric,
-                capacity_per_color_plane: data_capacity
+                //mode: mode,
+                capacity_per_color_plane: data_capacity,
+                is_metadata: false
             };
             cache_barcodes.push(new_code);
 
@@ -198,16 +395,57 @@ impl<'a> PageBarcodePacker {
             cache_bytes_per_page += data_capacity * (self.color_multiplexer.num_planes() as u32);
 
             // Move to the next one.
-            next_x += barcode_size + QUIET_ZONE_SIZE as u32;
-            if next_x + barcode_size > self.width {
+            next_x += group_width + QUIET_ZONE_SIZE as u32;
+            if next_x + group_width > self.width {
                 next_x = centering_offset_left;
                 next_y += barcode_size + QUIET_ZONE_SIZE as u32;
             }
         }
 
+        // Give the barcode closest to the center of the page, where damage is least likely, over to document metadata.
+        if self.document_metadata.is_some() && cache_barcodes.len() > 1 {
+            let distance_from_center = |b: &MultiplexedBarcodeInfo| {
+                let dx = (b.x + group_width / 2) as i64 - (self.width / 2) as i64;
+                let dy = (b.y + barcode_size / 2) as i64 - (self.height / 2) as i64;
+                dx * dx + dy * dy
+            };
+            let mut metadata_index = 0;
+            for (i, b) in cache_barcodes.iter().enumerate() {
+                if distance_from_center(b) < distance_from_center(&cache_barcodes[metadata_index]) {
+                    metadata_index = i;
+                }
+            }
+            cache_barcodes[metadata_index].is_metadata = true;
+            cache_bytes_per_page -= cache_barcodes[metadata_index].capacity_per_color_plane * (self.color_multiplexer.num_planes() as u32);
+        }
+
         (cache_barcodes, cache_bytes_per_page)
     }
 
+    // How many bytes of data fit in a single barcode plane, after the header.
+    fn data_capacity(&self, qrv: Version, ec: EcLevel) -> u32 {
+        let bits = Bits::new(qrv);
+        let max_bits = bits.max_len(ec).unwrap();
+        let mode = match self.payload_encoding {
+            PayloadEncoding::Base45 => Mode::Alphanumeric,
+            PayloadEncoding::Byte => Mode::Byte
+        };
+        let metadata_bits = mode.length_bits_count(qrv) + 4 + qrv.mode_bits_count();
+        let max_bytes: u32 = (max_bits - metadata_bits) as u32 / 8;
+        let overhead = header_length(self.format_version);
+        // Small barcodes at high error correction levels, like the metadata barcode, might not even have room for the header.
+        match self.payload_encoding {
+            PayloadEncoding::Base45 => {
+                let data_capacity_per_color_bit_unencoded: u32 = max_bytes.saturating_sub(overhead);
+                data_capacity_per_color_bit_unencoded * 2 / 3
+            },
+            PayloadEncoding::Byte => {
+                // Byte stuffing only costs a byte per 254, but it has to cover the header as well.
+                max_unstuffed_length(max_bytes).saturating_sub(overhead)
+            }
+        }
+    }
+
     pub fn repack_barcodes_for_page_length(&mut self, min_needed_length: u32) -> bool {
         // TODO: Implement this function such that we can try to expand the size of barcodes by integer multiples of the pixel size so we can use larger, lower-DPI barcodes for better readability.
 
@@ -269,16 +507,175 @@ impl<'a> PageBarcodePacker {
         self.cache_bytes_per_page
     }
 
-    fn generate_barcode_filling_bits(&self, qrcode_version: Version, ec_level: EcLevel, byte_array: &[u8]) -> Bits {
+    // Width of the barcodes on the page, in modules.  Every barcode on a page is the same size.
+    pub fn barcode_width(&self) -> u32 {
+        self.cache_barcodes.first().map(|b| b.version.width() as u32).unwrap_or(0)
+    }
+
+    // Whether a barcode slot was given over to the document metadata.  Pages with room for just one barcode keep it for data.
+    pub fn has_metadata_barcode(&self) -> bool {
+        self.cache_barcodes.iter().any(|b| b.is_metadata)
+    }
+
+    // Whether the document metadata fits in the metadata barcode, apart from however much of the title has to be cut to fit.
+    pub fn metadata_fits(&self) -> bool {
+        match (&self.document_metadata, self.cache_barcodes.iter().find(|b| b.is_metadata)) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(m), Some(b)) => {
+                let max_metadata_length = self.data_capacity(b.version, METADATA_EC_LEVEL) as usize;
+                m.to_bytes(max_metadata_length).len() <= max_metadata_length
+            }
+        }
+    }
+
+    // Layout ID recorded in version 2 headers.
+    pub fn layout_id(&self) -> u8 {
+        barcode_layout(&self.color_multiplexer) | if self.interleave_planes { BARCODE_LAYOUT_INTERLEAVED_FLAG } else { 0 }
+    }
+
+    // Where each color plane of each barcode starts within the page's data, in the order barcodes are placed.
+    // Normally the planes of a square hold consecutive chunks.  Interleaved, the first plane of every square is filled before the second, and so on.
+    fn plane_offsets(&self, barcodes: &[MultiplexedBarcodeInfo]) -> (Vec<Vec<usize>>, usize) {
+        let num_color_planes = self.color_multiplexer.num_planes() as usize;
+        let mut offsets = vec![vec![0; num_color_planes]; barcodes.len()];
+        let mut start_offset: usize = 0;
+        if self.interleave_planes {
+            for c in 0..num_color_planes {
+                for (b_offsets, b_info) in offsets.iter_mut().zip(barcodes).filter(|(_, b)| !b.is_metadata) {
+                    b_offsets[c] = start_offset;
+                    start_offset += b_info.capacity_per_color_plane as usize;
+                }
+            }
+        }
+        else {
+            for (b_offsets, b_info) in offsets.iter_mut().zip(barcodes).filter(|(_, b)| !b.is_metadata) {
+                for o in b_offsets.iter_mut() {
+                    *o = start_offset;
+                    start_offset += b_info.capacity_per_color_plane as usize;
+                }
+            }
+        }
+        (offsets, start_offset)
+    }
+
+    // Lays out a page the same way encode does, without rendering anything.
+    pub fn barcode_slots(&self, page_number: u16, file_checksum: u32) -> Vec<BarcodeSlot> {
+        let barcodes = self.randomize_barcodes(file_checksum.wrapping_add(page_number as u32));
+        let (plane_offsets, _) = self.plane_offsets(&barcodes);
+        let group = self.color_multiplexer.pixels_per_group();
+        barcodes.iter().zip(plane_offsets).map(|(b, offsets)| {
+            let side = b.version.width() as u32;
+            BarcodeSlot {
+                x: b.x,
+                y: b.y,
+                width: side * group + QUIET_ZONE_SIZE as u32 * (group - 1),
+                height: side,
+                version: match b.version { Version::Normal(v) | Version::Micro(v) => v },
+                ec_level: b.ec_level,
+                plane_offsets: if b.is_metadata { vec![] } else { offsets.iter().map(|o| *o as u64).collect() },
+                is_metadata: b.is_metadata
+            }
+        }).collect()
+    }
+
+    // Only needed for format version 2 and later, which record the page counts in every barcode.
+    pub fn set_page_counts(&mut self, data_pages: u16, parity_pages: u8) {
+        self.data_pages = data_pages;
+        self.parity_pages = parity_pages;
+    }
+
+    fn generate_barcode_filling_bits(&self, qrcode_version: Version, ec_level: EcLevel, byte_array: &[u8]) -> QrResult<Bits> {
         let mut bits = Bits::new(qrcode_version);
-        let encoded = encode(byte_array);
-        bits.push_alphanumeric_data(encoded.as_bytes()).unwrap();
-        bits.push_terminator(ec_level).unwrap();
-        bits
+        let encoded = encode_payload(self.payload_encoding, byte_array);
+        match self.payload_encoding {
+            PayloadEncoding::Base45 => bits.push_alphanumeric_data(&encoded)?,
+            PayloadEncoding::Byte => bits.push_byte_data(&encoded)?
+        };
+        bits.push_terminator(ec_level)?;
+        Ok(bits)
+    }
+
+    // Generates the header which starts every barcode, up to but not including the data chunk.
+    fn generate_header(&self, page_number: u16, barcode_number_bytes: [u8; 2], offset_bytes: [u8; 6], file_checksum: u32, total_length: u64) -> Vec<u8> {
+        let mut barcode_data: Vec<u8> = vec!();
+
+        // First byte - format version, flagged if we're using byte mode.
+        barcode_data.push(self.format_version | self.payload_encoding.format_version_flag());
+
+        // Next two bytes - page number, big endian.
+        barcode_data.extend_from_slice(&page_number.to_be_bytes());
+
+        // Next two bytes - barcode number, big endian, with some metadata bits.
+        barcode_data.extend_from_slice(&barcode_number_bytes);
+
+        // Next 6 bytes - offset from the start of the file, or parity information, big endian.
+        barcode_data.extend_from_slice(&offset_bytes);
+
+        // Next 6 bytes - total document length, big endian.
+        let total_length_bytes = total_length.to_be_bytes();
+        barcode_data.extend_from_slice(&total_length_bytes[2..8]);
+
+        // Next 3 bytes - lower bytes document checksum, big endian.
+        let checksum_bytes = file_checksum.to_be_bytes();
+        barcode_data.extend_from_slice(&checksum_bytes[1..4]);
+
+        if self.format_version >= 2 {
+            // Version 2 records enough about the layout of the document that the decoder doesn't have to infer it.
+            // Next 4 bytes - data bytes per page, big endian.
+            barcode_data.extend_from_slice(&self.cache_bytes_per_page.to_be_bytes());
+
+            // Next 2 bytes - number of data pages, big endian.
+            barcode_data.extend_from_slice(&self.data_pages.to_be_bytes());
+
+            // Next byte - number of parity pages.
+            barcode_data.push(self.parity_pages);
+
+            // Next byte - number of color planes.
+            barcode_data.push(self.color_multiplexer.num_planes());
+
+            // Next byte - barcode layout ID.
+            barcode_data.push(self.layout_id());
+        }
+
+        if self.format_version >= 3 {
+            // Next 4 bytes - CRC-32 of the rest of the barcode, big endian.  Filled in by seal_barcode once the data is there.
+            barcode_data.extend_from_slice(&[0; 4]);
+        }
+
+        let overhead = barcode_data.len();
+        let expected_overhead = header_length(self.format_version) as usize;
+        if overhead != expected_overhead {
+            panic!("Something went wrong with the format generator - got {} bytes when it should be {}", overhead, expected_overhead);
+        }
+
+        barcode_data
+    }
+
+    // Fills in the barcode checksum, once the data has been added after the header.
+    fn seal_barcode(&self, barcode_data: &mut [u8]) {
+        if self.format_version >= 3 {
+            let checksum = barcode_checksum(barcode_data);
+            barcode_data[BARCODE_CHECKSUM_OFFSET..BARCODE_CHECKSUM_OFFSET + 4].copy_from_slice(&checksum.to_be_bytes());
+        }
+    }
+
+    // Renders the document metadata as a monochrome barcode, so it can be read without knowing how many colors the document uses.
+    // Fails if the barcode is too small to hold the metadata, which metadata_fits can check for ahead of time.
+    fn render_metadata_barcode(&self, b_info: &MultiplexedBarcodeInfo, metadata: &DocumentMetadata, page_number: u16, file_checksum: u32, total_length: u64) -> Result<RgbImage, &'static str> {
+        // Pointing the offset past the end of any possible document means older decoders will treat this as padding and ignore it.
+        let mut barcode_data = self.generate_header(page_number, [BARCODE_NUMBER_METADATA_FLAG, 0], [0xff; 6], file_checksum, total_length);
+        let max_metadata_length = self.data_capacity(b_info.version, METADATA_EC_LEVEL) as usize;
+        barcode_data.extend_from_slice(&metadata.to_bytes(max_metadata_length));
+        self.seal_barcode(&mut barcode_data);
+        let too_small = |_e| "Metadata barcode is too small to hold the document metadata";
+        let bits = self.generate_barcode_filling_bits(b_info.version, METADATA_EC_LEVEL, &barcode_data).map_err(too_small)?;
+        let code = QrCode::with_bits(bits, METADATA_EC_LEVEL).map_err(too_small)?;
+        Ok(code.render::<Rgb<u8>>().module_dimensions(1, 1).quiet_zone(false).build())
     }
 
     fn render_barcode(&self, b_info: &MultiplexedBarcodeInfo, data: &[u8]) -> RgbImage {
-        let bits = self.generate_barcode_filling_bits(b_info.version, b_info.ec_level, data);
+        let bits = self.generate_barcode_filling_bits(b_info.version, b_info.ec_level, data).unwrap();
         let code = QrCode::with_bits(bits, b_info.ec_level).unwrap();
         let code_image = code.render::<Rgb<u8>>().module_dimensions(1, 1).quiet_zone(false).build();
         code_image
@@ -296,79 +693,58 @@ impl<'a> PageBarcodePacker {
             panic!("We can currently only generate whole pages of parity");
         }
 
-        let mut start_offset: usize = 0;
+        let (plane_offsets, end_offset) = self.plane_offsets(barcodes);
         let num_color_planes = self.color_multiplexer.num_planes() as usize;
         //println!("Number of color planes: {}", num_color_planes);
         for (b_index, b_info) in barcodes.iter().enumerate() {
+            if b_info.is_metadata {
+                // Black and white only, so every plane is the same.
+                // If it doesn't fit, the slot is left blank - the metadata is only a convenience, and the data barcodes don't depend on it.
+                let code_image = match self.render_metadata_barcode(b_info, self.document_metadata.as_ref().unwrap(), page_number, file_checksum, total_length) {
+                    Ok(code_image) => code_image,
+                    Err(e) => {
+                        println!("Warning: {} on page {}", e, page_number);
+                        continue;
+                    }
+                };
+                // Repeat it across the whole group, since bardecoder takes the edge of a finder pattern straight after a long white gap for noise, and would miss every barcode after the gap on the same row.
+                for k in 0..self.color_multiplexer.pixels_per_group() {
+                    imageops::overlay(out_image, &code_image, (b_info.x + k * (code_image.width() + QUIET_ZONE_SIZE as u32)) as i64, b_info.y as i64);
+                }
+                continue;
+            }
+
             let mut color_planes: Vec<RgbImage> = vec![];
-            for c in 0..(num_color_planes) {
+            for (c, &start_offset) in plane_offsets[b_index].iter().enumerate() {
                 let full_barcode_index = b_index * num_color_planes + c;
 
                 //println!("Generating page {} barcode {}/{}", page_number, full_barcode_index, barcodes.len() * num_color_planes);
-                // Pull in the amount of data we need to fill this barcode, padded out with zeroes.
-                let mut barcode_data: Vec<u8> = vec!();
-
-                // First byte - format version.
-                barcode_data.push(self.format_version);
-
-                // Next two bytes - page number, big endian.
-                let page_number_bytes = page_number.to_be_bytes();
-                barcode_data.push(page_number_bytes[0]);
-                barcode_data.push(page_number_bytes[1]);
-
-                // Next two bytes - barcode number, big endian, with some metadata bits.
+                // Barcode number, big endian, with some metadata bits.
                 let mut byte_1 = ((full_barcode_index >> 16) & 0x0f) as u8;
                 let byte_2 = (full_barcode_index & 0xff) as u8;
                 if is_parity_page {
-                    byte_1 |= 0b10000000;
+                    byte_1 |= BARCODE_NUMBER_PARITY_FLAG;
                 }
-                barcode_data.push(byte_1);
-                barcode_data.push(byte_2);
 
                 // Next 6 bytes - offset from the start of the file, big endian.
                 // TODO: This might need to be the parity page number we're encoding.
+                let mut offset_bytes = [0u8; 6];
                 if !is_parity_page {
                     let start_offset_bytes = (page_start_offset + (start_offset as u64)).to_be_bytes();
-                    barcode_data.push(start_offset_bytes[2]);
-                    barcode_data.push(start_offset_bytes[3]);
-                    barcode_data.push(start_offset_bytes[4]);
-                    barcode_data.push(start_offset_bytes[5]);
-                    barcode_data.push(start_offset_bytes[6]);
-                    barcode_data.push(start_offset_bytes[7]);
+                    offset_bytes.copy_from_slice(&start_offset_bytes[2..8]);
                 }
                 else {
                     // Format is:
                     // * 1 byte reserved - set to 0 for now
                     // * 1 byte parity index
                     // * 4 bytes start offset
-                    barcode_data.push(0);
-                    barcode_data.push(parity_index);
+                    offset_bytes[0] = 0;
+                    offset_bytes[1] = parity_index;
                     let start_offset_bytes = (((page_start_offset + (start_offset as u64)) & 0xffffffff) as u32).to_be_bytes();
-                    barcode_data.push(start_offset_bytes[0]);
-                    barcode_data.push(start_offset_bytes[1]);
-                    barcode_data.push(start_offset_bytes[2]);
-                    barcode_data.push(start_offset_bytes[3]);
+                    offset_bytes[2..6].copy_from_slice(&start_offset_bytes);
                 }
 
-                // Next 6 bytes - total document length, big endian.
-                let total_length_bytes = total_length.to_be_bytes();
-                barcode_data.push(total_length_bytes[2]);
-                barcode_data.push(total_length_bytes[3]);
-                barcode_data.push(total_length_bytes[4]);
-                barcode_data.push(total_length_bytes[5]);
-                barcode_data.push(total_length_bytes[6]);
-                barcode_data.push(total_length_bytes[7]);
-
-                // Next 3 bytes - lower bytes document checksum, big endian.
-                let checksum_bytes = file_checksum.to_be_bytes();
-                barcode_data.push(checksum_bytes[1]);
-                barcode_data.push(checksum_bytes[2]);
-                barcode_data.push(checksum_bytes[3]);
-
-                let overhead = barcode_data.len();
-                if overhead != 20 {
-                    panic!("Something went wrong with the format generator - got {} bytes when it should be 20", overhead);
-                }
+                let mut barcode_data = self.generate_header(page_number, [byte_1, byte_2], offset_bytes, file_checksum, total_length);
                 let data_capacity = b_info.capacity_per_color_plane as usize;
 
                 let mut v: Vec<u8>;
@@ -394,24 +770,91 @@ impl<'a> PageBarcodePacker {
                 };
                 //println!("Data to encode: {:?}", barcode_slice);
                 barcode_data.extend_from_slice(barcode_slice);
+                self.seal_barcode(&mut barcode_data);
                 //println!("Data to encode: {:?}", barcode_data);
                 //println!("Starting offset {}, advancing {}", start_offset, data_capacity);
 
                 color_planes.push(self.render_barcode(&b_info, &barcode_data));
-
-                // Advance the offset for the next barcode.
-                start_offset += data_capacity;
             }
 
             // Multiplex the barcodes.
-            let code_image = self.color_multiplexer.multiplex_planes(color_planes);
+            let code_image = self.color_multiplexer.multiplex_planes(color_planes, QUIET_ZONE_SIZE as u32);
 
             imageops::overlay(out_image, &code_image, b_info.x as i64, b_info.y as i64);
         }
 
         // Final check to make sure we didn't miss anything.
-        if start_offset < data.len() {
+        if end_offset < data.len() {
             panic!("Couldn't encode entire buffer with length {} - some data skipped.", data.len());
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interleaved_planes_spread_consecutive_data_over_squares() {
+        let packer = PageBarcodePacker::new(600, 600, BarcodeFormat::QR)
+            .color_multiplexer(ColorMultiplexer::new(8).finalize())
+            .interleave_planes(true)
+            .finalize();
+        let barcodes = packer.randomize_barcodes(1234);
+        assert!(barcodes.len() > 1);
+        let (offsets, end) = packer.plane_offsets(&barcodes);
+        assert_eq!(end, packer.data_bytes_per_page() as usize);
+
+        // Every byte of the page is held by exactly one plane, and the data right after a plane never sits in the same square.
+        let mut starts: Vec<(usize, usize, usize)> = vec![];
+        for (b_index, b_info) in barcodes.iter().enumerate() {
+            for &offset in &offsets[b_index] {
+                starts.push((offset, b_index, b_info.capacity_per_color_plane as usize));
+            }
+        }
+        starts.sort();
+        for pair in starts.windows(2) {
+            assert_eq!(pair[0].0 + pair[0].2, pair[1].0);
+            assert_ne!(pair[0].1, pair[1].1);
+        }
+        assert_eq!(packer.layout_id(), BARCODE_LAYOUT_GRID | BARCODE_LAYOUT_INTERLEAVED_FLAG);
+        assert!(same_barcode_arrangement(packer.layout_id(), BARCODE_LAYOUT_GRID));
+    }
+
+    #[test]
+    fn metadata_too_big_for_its_barcode_is_an_error() {
+        let packer = PageBarcodePacker::new(600, 600, BarcodeFormat::QR)
+            .document_metadata(Some(DocumentMetadata::new("Title")))
+            .finalize();
+        assert!(packer.has_metadata_barcode());
+        assert!(packer.metadata_fits());
+        let tiny = MultiplexedBarcodeInfo { x: 0, y: 0, version: Version::Normal(2), ec_level: METADATA_EC_LEVEL, capacity_per_color_plane: 0, is_metadata: true };
+        assert_eq!(packer.data_capacity(tiny.version, METADATA_EC_LEVEL), 0);
+        assert!(packer.render_metadata_barcode(&tiny, &DocumentMetadata::new("Title"), 1, 0, 100).is_err());
+    }
+
+    #[test]
+    fn barcode_checksum_catches_misreads() {
+        let packer = PageBarcodePacker::new(600, 600, BarcodeFormat::QR).finalize();
+        let mut barcode_data = packer.generate_header(1, [0, 5], [0, 0, 0, 0, 0x10, 0], 0x123456, 5000);
+        assert_eq!(barcode_data.len(), 33);
+        barcode_data.extend_from_slice(b"some file data");
+        packer.seal_barcode(&mut barcode_data);
+        assert!(barcode_checksum_matches(&barcode_data));
+
+        // Damage to the header or the data is caught, and so is damage to the checksum itself.
+        for i in [2, 30, barcode_data.len() - 1] {
+            let mut misread = barcode_data.clone();
+            misread[i] ^= 0x04;
+            assert!(!barcode_checksum_matches(&misread));
+        }
+
+        // Older versions have nothing to check.
+        let packer = PageBarcodePacker::new(600, 600, BarcodeFormat::QR).format_version(2).finalize();
+        let mut barcode_data = packer.generate_header(1, [0, 5], [0; 6], 0x123456, 5000);
+        barcode_data.extend_from_slice(b"some file data");
+        packer.seal_barcode(&mut barcode_data);
+        barcode_data[20] ^= 0x04;
+        assert!(barcode_checksum_matches(&barcode_data));
+    }
+}
@@ -0,0 +1,77 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0+ OR Zlib
+
+use crate::color_multiplexer::usable_num_colors;
+use crate::color_profile::parse_hex_color;
+use image::Rgb;
+use std::fs;
+use std::path::Path;
+
+// Reads a palette given on the command line, which is either the name of a palette file or hex colors separated by commas.
+pub fn read_palette(spec: &str) -> Result<Vec<Rgb<u8>>, &'static str> {
+    let colors = if Path::new(spec).is_file() {
+        let text = fs::read_to_string(spec).map_err(|_e| "Could not read palette file")?;
+        parse_palette_file(&text)?
+    }
+    else {
+        spec.split(',').map(|c| parse_hex_color(c.trim().trim_start_matches('#'))).collect::<Result<Vec<Rgb<u8>>, &'static str>>()?
+    };
+
+    if colors.len() < 2 || colors.len() > u8::MAX as usize || usable_num_colors(colors.len() as u8) as usize != colors.len() {
+        return Err("Palettes need a number of colors we can multiplex with");
+    }
+    for (i, c) in colors.iter().enumerate() {
+        if colors[..i].contains(c) {
+            return Err("Palette colors must all be different");
+        }
+    }
+    Ok(colors)
+}
+
+// Palette files are either plain text with a hex color at the start of each line, optionally followed by a name, or GIMP palettes, which most paint and design programs can export.
+// Lines starting with # that aren't colors are comments.
+fn parse_palette_file(text: &str) -> Result<Vec<Rgb<u8>>, &'static str> {
+    let mut colors = vec![];
+    let mut lines = text.lines().map(|l| l.trim()).peekable();
+    if lines.peek() == Some(&"GIMP Palette") {
+        // Colors are red, green, and blue in decimal, after a header of "Name:" and "Columns:" lines.
+        for line in lines.skip(1) {
+            if line.is_empty() || line.starts_with('#') || line.contains(':') {
+                continue;
+            }
+            let channels: Vec<u8> = line.split_whitespace().take(3).map(|v| v.parse::<u8>()).collect::<Result<Vec<u8>, _>>().map_err(|_e| "Bad color in GIMP palette")?;
+            if channels.len() != 3 {
+                return Err("Bad color in GIMP palette");
+            }
+            colors.push(Rgb([channels[0], channels[1], channels[2]]));
+        }
+        return Ok(colors);
+    }
+
+    for line in lines {
+        let first = line.split_whitespace().next().unwrap_or("");
+        match parse_hex_color(first.trim_start_matches('#')) {
+            Ok(c) => colors.push(c),
+            Err(e) => if !line.is_empty() && !line.starts_with('#') {
+                return Err(e);
+            }
+        }
+    }
+    Ok(colors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn palettes_are_read_from_lists_and_files() {
+        assert_eq!(read_palette("000000, #ff0000,00ff00,ffffff").unwrap(), vec![Rgb([0, 0, 0]), Rgb([255, 0, 0]), Rgb([0, 255, 0]), Rgb([255, 255, 255])]);
+        assert_eq!(parse_palette_file("# Anodized aluminum\n1a1a1a  Black dye\n\n#c0c0c0 Bare\n").unwrap(), vec![Rgb([0x1a, 0x1a, 0x1a]), Rgb([0xc0, 0xc0, 0xc0])]);
+        assert_eq!(parse_palette_file("GIMP Palette\nName: Inks\nColumns: 4\n#\n  0  10  20 Carbon\n255 255 250\tPaper\n").unwrap(), vec![Rgb([0, 10, 20]), Rgb([255, 255, 250])]);
+
+        // 5 colors can't carry any more than 4, so they're no use.
+        assert!(read_palette("000000,ff0000,00ff00,0000ff,ffffff").is_err());
+        assert!(read_palette("000000,000000,ff0000,ffffff").is_err());
+        assert!(read_palette("000000,red,ffffff").is_err());
+    }
+}
@@ -0,0 +1,182 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0+ OR Zlib
+
+use image::{DynamicImage, Rgb, RgbImage};
+use imageproc::rect::Rect;
+use imageproc::drawing::draw_filled_rect_mut;
+use qrencode::QrCode;
+use qrencode::types::EcLevel;
+use crate::color_multiplexer::sample_solid_color;
+use crate::grayscale_recognizer::{locate_grayscale_barcodes, LocatedBarcode};
+
+// Palette swatches are labeled with a small QR code on their left, which says which palette color each patch is.
+// The label's finder patterns double as locator marks: the swatch is drawn on the label's own module grid, as tall as the label and LABEL_GAP modules to the right of it, so a decoder can find the patches from the label anywhere on the page, even on a shifted or rotated scan.
+const LABEL_PREFIX: &str = "SWATCH";
+
+// White modules between the label and the swatch, which is also the label's quiet zone on that side.
+pub const LABEL_GAP: u32 = 4;
+
+// What a swatch's label says: patches are palette colors first_index onward, laid out left to right and then top to bottom in this many columns.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SwatchLabel {
+    pub first_index: u8,
+    pub count: u8,
+    pub columns: u32
+}
+
+// A swatch found on a scanned page.
+pub struct FoundSwatch {
+    pub label: SwatchLabel,
+    // Center of the swatch, in pixels.
+    pub x: f32,
+    pub y: f32,
+    pub patches: Vec<Rgb<u8>>,
+    // Measured from the label's finder pattern and the gap beside it.
+    pub black: Rgb<u8>,
+    pub white: Rgb<u8>
+}
+
+impl SwatchLabel {
+    pub fn rows(&self) -> u32 {
+        (self.count as u32).div_ceil(self.columns)
+    }
+
+    // Only uses characters from the QR alphanumeric set, to keep the label small.
+    fn to_text(&self) -> String {
+        format!("{} {} {} {}", LABEL_PREFIX, self.first_index, self.count, self.columns)
+    }
+
+    fn parse(data: &[u8]) -> Option<SwatchLabel> {
+        let text = std::str::from_utf8(data).ok()?;
+        let fields: Vec<&str> = text.split(' ').collect();
+        if fields.len() != 4 || fields[0] != LABEL_PREFIX {
+            return None;
+        }
+        let label = SwatchLabel {
+            first_index: fields[1].parse().ok()?,
+            count: fields[2].parse().ok()?,
+            columns: fields[3].parse().ok()?
+        };
+        if label.count == 0 || label.columns == 0 {
+            return None;
+        }
+        Some(label)
+    }
+
+    fn qrcode(&self) -> QrCode {
+        QrCode::with_error_correction_level(self.to_text(), EcLevel::M).unwrap()
+    }
+
+    // Where the middle of a patch is, in modules from the label's top left corner, given how many modules wide the label is.
+    fn patch_center(&self, patch: u32, side: u32) -> (f32, f32) {
+        let cell = side as f32 / self.rows() as f32;
+        ((side + LABEL_GAP) as f32 + ((patch % self.columns) as f32 + 0.5) * cell, ((patch / self.columns) as f32 + 0.5) * cell)
+    }
+
+    // Pixels per module for a labeled swatch at the given height.
+    // Labels need at least a pixel per module, so on very low resolution pages they come out a little taller than asked for.
+    fn module_size(side: u32, height: u32) -> u32 {
+        (height / side).max(1)
+    }
+
+    // Width and height of a labeled swatch drawn at the given height.
+    pub fn size_at_height(&self, height: u32) -> (u32, u32) {
+        let side = self.qrcode().width() as u32;
+        let module = SwatchLabel::module_size(side, height);
+        let cell = (side * module) as f32 / self.rows() as f32;
+        ((side + LABEL_GAP) * module + (cell * self.columns as f32).ceil() as u32, side * module)
+    }
+
+    // Draws the label and its swatch with their top left corner at the given spot, as big as fits in the given height.
+    // The patches are the palette colors the label names, taken from the given palette.
+    pub fn draw(&self, image: &mut RgbImage, left: u32, top: u32, height: u32, palette: &[Rgb<u8>]) {
+        let code = self.qrcode();
+        let side = code.width() as u32;
+        let module = SwatchLabel::module_size(side, height);
+        let label_image = code.render::<Rgb<u8>>().module_dimensions(module, module).quiet_zone(false).build();
+        image::imageops::overlay(image, &label_image, left as i64, top as i64);
+
+        // Each patch takes up the middle half of a square cell, with paper around it.
+        // Unlabeled swatches are on a black background, but a long black run right beside the label keeps barcode locators from seeing the white gap between them, so black is measured from the label instead.
+        let cell = (side * module) as f32 / self.rows() as f32;
+        for p in 0..self.count as u32 {
+            let (x, y) = self.patch_center(p, side);
+            let patch_left = (left as f32 + x * module as f32 - cell / 4.0).round() as i32;
+            let patch_top = (top as f32 + y * module as f32 - cell / 4.0).round() as i32;
+            let patch_size = ((cell / 2.0).round() as u32).max(1);
+            draw_filled_rect_mut(image, Rect::at(patch_left, patch_top).of_size(patch_size, patch_size), palette[self.first_index as usize + p as usize]);
+        }
+    }
+}
+
+// Finds every labeled swatch on a page and reads its colors.
+pub fn find_labeled_swatches(img: &DynamicImage) -> Vec<FoundSwatch> {
+    let mut found: Vec<FoundSwatch> = vec![];
+    for barcode in locate_grayscale_barcodes(img) {
+        if let Some(label) = SwatchLabel::parse(&barcode.data) {
+            if let Some(swatch) = read_swatch(img, &barcode, label) {
+                // The blocks we search in overlap, so the same swatch can turn up more than once.
+                let module = barcode.module_size();
+                if !found.iter().any(|f| (f.x - swatch.x).hypot(f.y - swatch.y) < module * barcode.side as f32) {
+                    found.push(swatch);
+                }
+            }
+        }
+    }
+    found
+}
+
+// Samples a swatch's patches by following the label's rows and columns, so rotated scans work as well as straight ones.
+fn read_swatch(img: &DynamicImage, barcode: &LocatedBarcode, label: SwatchLabel) -> Option<FoundSwatch> {
+    let side = barcode.side;
+    // Positions are measured from the pixels' corners, where sample_solid_color goes by their centers.
+    let at = |x: f32, y: f32| {
+        let (px, py) = barcode.module_position(x, y);
+        (px - 0.5, py - 0.5)
+    };
+    let module = barcode.module_size();
+
+    let cell = side as f32 / label.rows() as f32;
+    let mut patches = vec![];
+    for p in 0..label.count as u32 {
+        let (x, y) = label.patch_center(p, side);
+        let (px, py) = at(x, y);
+        patches.push(sample_solid_color(img, px, py, (cell * module / 8.0).max(1.0))?);
+    }
+
+    // The finder pattern has a solid block of black three modules across in the middle.
+    let (bx, by) = at(3.5, 3.5);
+    let black = sample_solid_color(img, bx, by, (module * 0.9).max(1.0))?;
+    let (wx, wy) = at((side as f32) + LABEL_GAP as f32 / 2.0, side as f32 / 2.0);
+    let white = sample_solid_color(img, wx, wy, (module * LABEL_GAP as f32 / 4.0).max(1.0))?;
+
+    let swatch_width = cell * label.columns as f32;
+    let (x, y) = at((side + LABEL_GAP) as f32 + swatch_width / 2.0, side as f32 / 2.0);
+    Some(FoundSwatch { label, x, y, patches, black, white })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::imageops;
+
+    #[test]
+    fn labeled_swatches_are_found_wherever_they_are() {
+        let palette: Vec<Rgb<u8>> = vec![Rgb([0, 0, 0]), Rgb([255, 0, 0]), Rgb([0, 255, 0]), Rgb([255, 255, 0]), Rgb([0, 0, 255]), Rgb([255, 0, 255]), Rgb([0, 255, 255]), Rgb([255, 255, 255])];
+        let label = SwatchLabel { first_index: 1, count: 6, columns: 3 };
+        assert_eq!(SwatchLabel::parse(label.to_text().as_bytes()), Some(label.clone()));
+
+        // Off in a corner where no swatch would usually be, and upside down.
+        let mut page = RgbImage::from_pixel(400, 300, Rgb([255, 255, 255]));
+        let (width, height) = label.size_at_height(21);
+        label.draw(&mut page, 210, 200, 21, &palette);
+        assert!(210 + width < 400 && 200 + height < 300);
+        for image in [page.clone(), imageops::rotate180(&page)] {
+            let found = find_labeled_swatches(&DynamicImage::ImageRgb8(image));
+            assert_eq!(found.len(), 1);
+            assert_eq!(found[0].label, label);
+            assert_eq!(found[0].patches, palette[1..7].to_vec());
+            assert_eq!(found[0].black, Rgb([0, 0, 0]));
+            assert_eq!(found[0].white, Rgb([255, 255, 255]));
+        }
+    }
+}
@@ -0,0 +1,190 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0+ OR Zlib
+
+use base45::{encode, decode};
+
+// Set in the format version byte of barcodes whose payload is stored in QR byte mode rather than as base-45 text.
+// The high bit stays reserved for multi-byte versions, so this leaves versions 0-63 available.
+pub const FORMAT_VERSION_BYTE_MODE_FLAG: u8 = 0b01000000;
+
+// bardecoder interprets a byte mode segment as UTF-8 if it contains even a single 0xC3 byte, and as ISO 8859-1 otherwise.
+// Random data almost never survives the UTF-8 interpretation, so we make sure that byte never appears in what we write.
+const AVOIDED_BYTE: u8 = 0xc3;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PayloadEncoding {
+    // Base-45 text in alphanumeric mode - widest reader support, but costs density.
+    Base45,
+    // Raw bytes in byte mode, stuffed so they come back intact from bardecoder.
+    Byte
+}
+
+impl PayloadEncoding {
+    pub fn format_version_flag(&self) -> u8 {
+        match self {
+            PayloadEncoding::Base45 => 0,
+            PayloadEncoding::Byte => FORMAT_VERSION_BYTE_MODE_FLAG
+        }
+    }
+}
+
+// Consistent Overhead Byte Stuffing (Cheshire and Baker, 1999) removes every zero byte at a cost of at most one byte per 254.
+// Every output byte is then XORed with the byte we want to avoid, so zeroes become the only value which cannot appear.
+pub fn stuff_bytes(data: &[u8]) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::with_capacity(stuffed_length(data.len()));
+    let mut code_index = 0;
+    let mut code: u8 = 1;
+    out.push(0);
+    for b in data {
+        if *b == 0 {
+            out[code_index] = code;
+            code_index = out.len();
+            out.push(0);
+            code = 1;
+        }
+        else {
+            out.push(*b);
+            code += 1;
+            if code == 0xff {
+                out[code_index] = code;
+                code_index = out.len();
+                out.push(0);
+                code = 1;
+            }
+        }
+    }
+    out[code_index] = code;
+
+    out.iter().map(|b| b ^ AVOIDED_BYTE).collect()
+}
+
+pub fn unstuff_bytes(stuffed: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let data: Vec<u8> = stuffed.iter().map(|b| b ^ AVOIDED_BYTE).collect();
+    let mut out = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        let code = data[i];
+        if code == 0 {
+            return Err("Unexpected zero code in stuffed data");
+        }
+        i += 1;
+        for _b in 1..code {
+            if i >= data.len() || data[i] == 0 {
+                return Err("Stuffed data ended early");
+            }
+            out.push(data[i]);
+            i += 1;
+        }
+        if code < 0xff && i < data.len() {
+            out.push(0);
+        }
+    }
+    Ok(out)
+}
+
+// Worst-case length of stuffed data.
+pub fn stuffed_length(unstuffed_length: usize) -> usize {
+    unstuffed_length + unstuffed_length / 254 + 1
+}
+
+// Largest unstuffed length which is guaranteed to fit within the given number of bytes once stuffed.
+pub fn max_unstuffed_length(available_bytes: u32) -> u32 {
+    let mut length = available_bytes;
+    while length > 0 && stuffed_length(length as usize) > available_bytes as usize {
+        length -= 1;
+    }
+    length
+}
+
+// The raw data to hand to the QR encoder for a given payload.
+pub fn encode_payload(encoding: PayloadEncoding, data: &[u8]) -> Vec<u8> {
+    match encoding {
+        PayloadEncoding::Base45 => encode(data).into_bytes(),
+        PayloadEncoding::Byte => stuff_bytes(data)
+    }
+}
+
+// Decodes the raw data read from a barcode, figuring out which encoding was used from the flag in the format version byte.
+pub fn decode_payload(raw: &[u8]) -> Result<Vec<u8>, &'static str> {
+    // Base-45 is tried first since it's the default, and since byte mode data is almost never valid base-45 text.
+    if let Ok(text) = std::str::from_utf8(raw) {
+        if let Ok(data) = decode(text) {
+            if !data.is_empty() && (data[0] & FORMAT_VERSION_BYTE_MODE_FLAG) == 0 {
+                return Ok(data);
+            }
+        }
+    }
+    let data = unstuff_bytes(raw)?;
+    if data.is_empty() {
+        return Err("Data was blank");
+    }
+    if (data[0] & FORMAT_VERSION_BYTE_MODE_FLAG) == 0 {
+        return Err("Byte mode data without the byte mode flag set");
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_stuffing_round_trips(data: &[u8]) {
+        let stuffed = stuff_bytes(data);
+        assert!(!stuffed.contains(&AVOIDED_BYTE), "Stuffed data contains the avoided byte");
+        assert!(stuffed.len() <= stuffed_length(data.len()), "Stuffed data is longer than the worst case");
+        assert_eq!(unstuff_bytes(&stuffed), Ok(data.to_vec()));
+    }
+
+    #[test]
+    fn stuffing_round_trips() {
+        assert_stuffing_round_trips(&[]);
+        assert_stuffing_round_trips(&[0; 10]);
+        // Runs either side of where a block fills up and a new code byte has to start without a zero.
+        for run in [253, 254, 255, 508, 509] {
+            assert_stuffing_round_trips(&vec![0x5a; run]);
+            let mut with_zeroes = vec![0];
+            with_zeroes.extend(vec![0xa5; run]);
+            with_zeroes.push(0);
+            assert_stuffing_round_trips(&with_zeroes);
+        }
+        // 0xC3 is what the stuffing XORs with, so zeroes are what would come out as it.
+        assert_stuffing_round_trips(&[AVOIDED_BYTE, 0, AVOIDED_BYTE, AVOIDED_BYTE, 0]);
+        assert_stuffing_round_trips(&(0..=255).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn max_unstuffed_length_fits() {
+        assert_eq!(max_unstuffed_length(0), 0);
+        assert_eq!(max_unstuffed_length(1), 0);
+        assert_eq!(max_unstuffed_length(2), 1);
+        for available in 0..1000 {
+            let length = max_unstuffed_length(available);
+            assert!(stuffed_length(length as usize) <= available as usize || length == 0);
+            assert!(stuffed_length(length as usize + 1) > available as usize);
+            // A run with no zeroes at all is the worst case.
+            assert!(stuff_bytes(&vec![0x5a; length as usize]).len() <= available.max(1) as usize);
+        }
+    }
+
+    #[test]
+    fn payloads_round_trip() {
+        let data = [FORMAT_VERSION_BYTE_MODE_FLAG | 3, 0, AVOIDED_BYTE, 0xff, 0];
+        assert_eq!(decode_payload(&encode_payload(PayloadEncoding::Byte, &data)), Ok(data.to_vec()));
+        let data = [3, 0, AVOIDED_BYTE, 0xff, 0];
+        assert_eq!(decode_payload(&encode_payload(PayloadEncoding::Base45, &data)), Ok(data.to_vec()));
+    }
+
+    #[test]
+    fn malformed_stuffing_is_rejected() {
+        let xor = |bytes: &[u8]| bytes.iter().map(|b| b ^ AVOIDED_BYTE).collect::<Vec<u8>>();
+        // A zero code byte.
+        assert!(decode_payload(&xor(&[0, 1])).is_err());
+        // A code byte promising more than is there.
+        assert!(decode_payload(&xor(&[5, FORMAT_VERSION_BYTE_MODE_FLAG, 2])).is_err());
+        // A zero inside a block.
+        assert!(decode_payload(&xor(&[3, FORMAT_VERSION_BYTE_MODE_FLAG, 0])).is_err());
+        // Nothing at all.
+        assert!(decode_payload(&[]).is_err());
+        // Well formed, but without the byte mode flag, so it can't be byte mode data.
+        assert!(decode_payload(&stuff_bytes(&[3, 1, 2])).is_err());
+    }
+}
@@ -0,0 +1,23 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0+ OR Zlib
+
+// xorshift32, for test pages whose contents the decoder has to be able to work out again.
+// Small, fast, and the same everywhere, which is all that needs.
+pub struct Xorshift32 {
+    state: u32
+}
+
+impl Xorshift32 {
+    pub fn new(seed: u32) -> Xorshift32 {
+        // xorshift gets stuck on zero.
+        Xorshift32 {
+            state: seed | 1
+        }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state
+    }
+}
@@ -0,0 +1,430 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0+ OR Zlib
+
+use image::{DynamicImage, Rgb, RgbImage};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops;
+use imageproc::drawing::{draw_filled_circle_mut, draw_polygon_mut};
+use imageproc::geometric_transformations::{warp, Interpolation, Projection};
+use imageproc::noise::gaussian_noise_mut;
+use imageproc::point::Point;
+
+// What a page picks up from a careful trip through an office printer and scanner.
+// The page is taken to be lying square on the glass, since the decoder doesn't yet read barcodes turned even a fraction of a degree.
+pub const DEFAULT_DAMAGE: &str = "scale=2,blur=0.3,light=0.15,noise=6,jpeg=85";
+
+// Coffee stains are this color where they're darkest.
+const STAIN_COLOR: Rgb<u8> = Rgb([130, 85, 40]);
+
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+// Small, fast, and the same everywhere, which is all we need to make damage repeatable.
+struct DamageRng {
+    state: u64
+}
+
+impl DamageRng {
+    // Seeds which are close together, like the page numbers of a document, are mixed with splitmix64 first so they start out nowhere near each other.
+    fn new(seed: u64, page_number: u64) -> DamageRng {
+        let state = splitmix64(splitmix64(seed) ^ page_number);
+        DamageRng {
+            // xorshift64* gets stuck on zero.
+            state: if state == 0 { 0x9e3779b97f4a7c15 } else { state }
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    // Somewhere in [min, max).
+    fn range(&mut self, min: f32, max: f32) -> f32 {
+        min + (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32 * (max - min)
+    }
+}
+
+fn blend(pixel: &mut Rgb<u8>, color: Rgb<u8>, alpha: f32) {
+    for i in 0..3 {
+        pixel[i] = (pixel[i] as f32 * (1.0 - alpha) + color[i] as f32 * alpha).round() as u8;
+    }
+}
+
+// Damages pages the way printing, handling, and scanning them would, so settings can be tried without using up paper.
+// The same seed always does the same damage to the same page.
+pub struct ScanSimulator {
+    seed: u64,
+    // Scanner pixels to each pixel of the page, since a page is drawn with one pixel per module and scanned at a higher resolution than that.
+    scale: u32,
+    // Standard deviation of the blur from ink spreading into the paper, in pixels.
+    blur: f32,
+    // Largest rotation, in degrees either way.
+    rotation: f32,
+    // Furthest any corner of the page gets pulled out of place by the scanner's perspective, as a share of the page size.
+    skew: f32,
+    // 0 to skip recompression.
+    jpeg_quality: u8,
+    // Standard deviation of sensor noise, in levels out of 255.
+    noise: f32,
+    // How much darker the far side of the page is than the side nearest the light, out of 1.
+    illumination: f32,
+    // Added to each channel.
+    color_cast: [i16; 3],
+    stains: u32,
+    torn_corners: u32,
+    creases: u32,
+    staple_holes: u32
+}
+
+impl ScanSimulator {
+    pub fn new() -> ScanSimulator {
+        ScanSimulator {
+            seed: 0,
+            scale: 1,
+            blur: 0.0,
+            rotation: 0.0,
+            skew: 0.0,
+            jpeg_quality: 0,
+            noise: 0.0,
+            illumination: 0.0,
+            color_cast: [0, 0, 0],
+            stains: 0,
+            torn_corners: 0,
+            creases: 0,
+            staple_holes: 0
+        }
+    }
+
+    // Takes a list of damage like "blur=0.7,rotate=0.5,stains=2", as given on the command line.
+    // Each kind of damage is set with the builder method of the same meaning, and a color cast is given as "cast=red:green:blue".
+    pub fn damage_spec(mut self, spec: &str) -> Result<Self, &'static str> {
+        for item in spec.split(',').map(|i| i.trim()).filter(|i| !i.is_empty()) {
+            let (name, value) = item.split_once('=').ok_or("Damage must be given as name=value")?;
+            let number = || value.parse::<f32>().map_err(|_e| "Damage amounts must be numbers");
+            let count = || value.parse::<u32>().map_err(|_e| "Counts of damage must be whole numbers");
+            self = match name {
+                "scale" => self.scale(value.parse::<u32>().ok().filter(|s| *s > 0).ok_or("Scale must be a whole number of at least 1")?),
+                "blur" => self.blur(number()?),
+                "rotate" => self.rotation(number()?),
+                "skew" => self.skew(number()?),
+                "jpeg" => self.jpeg_quality(value.parse::<u8>().ok().filter(|q| (1..=100).contains(q)).ok_or("JPEG quality must be from 1 to 100")?),
+                "noise" => self.noise(number()?),
+                "light" => self.illumination(number()?),
+                "cast" => {
+                    let channels: Vec<i16> = value.split(':').map(|c| c.parse::<i16>()).collect::<Result<Vec<i16>, _>>().map_err(|_e| "Color casts must be given as red:green:blue")?;
+                    if channels.len() != 3 {
+                        return Err("Color casts must be given as red:green:blue");
+                    }
+                    self.color_cast([channels[0], channels[1], channels[2]])
+                },
+                "stains" => self.stains(count()?),
+                "tears" => self.torn_corners(count()?),
+                "creases" => self.creases(count()?),
+                "staples" => self.staple_holes(count()?),
+                _ => return Err("Unknown kind of damage")
+            };
+        }
+        Ok(self)
+    }
+
+    pub fn seed(mut self, s: u64) -> Self {
+        self.seed = s;
+        self
+    }
+
+    pub fn scale(mut self, s: u32) -> Self {
+        self.scale = s;
+        self
+    }
+
+    // In pixels of the page, before scaling.
+    pub fn blur(mut self, sigma: f32) -> Self {
+        self.blur = sigma;
+        self
+    }
+
+    pub fn rotation(mut self, degrees: f32) -> Self {
+        self.rotation = degrees;
+        self
+    }
+
+    pub fn skew(mut self, s: f32) -> Self {
+        self.skew = s;
+        self
+    }
+
+    pub fn jpeg_quality(mut self, q: u8) -> Self {
+        self.jpeg_quality = q;
+        self
+    }
+
+    pub fn noise(mut self, stddev: f32) -> Self {
+        self.noise = stddev;
+        self
+    }
+
+    pub fn illumination(mut self, i: f32) -> Self {
+        self.illumination = i;
+        self
+    }
+
+    pub fn color_cast(mut self, c: [i16; 3]) -> Self {
+        self.color_cast = c;
+        self
+    }
+
+    pub fn stains(mut self, n: u32) -> Self {
+        self.stains = n;
+        self
+    }
+
+    // At most one per corner.
+    pub fn torn_corners(mut self, n: u32) -> Self {
+        self.torn_corners = n;
+        self
+    }
+
+    pub fn creases(mut self, n: u32) -> Self {
+        self.creases = n;
+        self
+    }
+
+    pub fn staple_holes(mut self, n: u32) -> Self {
+        self.staple_holes = n;
+        self
+    }
+
+    pub fn finalize(self) -> ScanSimulator {
+        ScanSimulator {
+            seed: self.seed,
+            scale: self.scale,
+            blur: self.blur,
+            rotation: self.rotation,
+            skew: self.skew,
+            jpeg_quality: self.jpeg_quality,
+            noise: self.noise,
+            illumination: self.illumination,
+            color_cast: self.color_cast,
+            stains: self.stains,
+            torn_corners: self.torn_corners,
+            creases: self.creases,
+            staple_holes: self.staple_holes
+        }
+    }
+
+    // Page number mixes into the seed, so every page of a document gets damaged differently.
+    pub fn damage(&self, page: &DynamicImage, page_number: u64) -> RgbImage {
+        let mut rng = DamageRng::new(self.seed, page_number);
+        let mut img = page.to_rgb8();
+        if self.scale > 1 {
+            img = imageops::resize(&img, img.width() * self.scale, img.height() * self.scale, imageops::FilterType::Nearest);
+        }
+
+        // Printing.
+        if self.blur > 0.0 {
+            img = imageops::blur(&img, self.blur * self.scale as f32);
+        }
+
+        // Handling.
+        for _ in 0..self.stains {
+            ScanSimulator::add_stain(&mut img, &mut rng);
+        }
+        for _ in 0..self.creases {
+            ScanSimulator::add_crease(&mut img, &mut rng);
+        }
+        for _ in 0..self.staple_holes {
+            ScanSimulator::add_staple_hole(&mut img, &mut rng);
+        }
+        let mut corners = vec![0, 1, 2, 3];
+        for _ in 0..self.torn_corners.min(4) {
+            let corner = corners.remove((rng.next_u64() % corners.len() as u64) as usize);
+            ScanSimulator::tear_corner(&mut img, &mut rng, corner);
+        }
+
+        // Scanning.
+        if self.rotation > 0.0 || self.skew > 0.0 {
+            img = self.misalign(&img, &mut rng);
+        }
+        if self.illumination > 0.0 {
+            self.light_unevenly(&mut img, &mut rng);
+        }
+        if self.color_cast != [0, 0, 0] {
+            for pixel in img.pixels_mut() {
+                for i in 0..3 {
+                    pixel[i] = (pixel[i] as i16 + self.color_cast[i]).clamp(0, 255) as u8;
+                }
+            }
+        }
+        if self.noise > 0.0 {
+            gaussian_noise_mut(&mut img, 0.0, self.noise as f64, rng.next_u64());
+        }
+        if self.jpeg_quality > 0 {
+            let mut jpeg: Vec<u8> = vec![];
+            JpegEncoder::new_with_quality(&mut jpeg, self.jpeg_quality).encode_image(&img).unwrap();
+            img = image::load_from_memory(&jpeg).unwrap().to_rgb8();
+        }
+        img
+    }
+
+    // A coffee ring: a dark, slightly wobbly ring where the cup sat, with a faint wash inside it.
+    fn add_stain(img: &mut RgbImage, rng: &mut DamageRng) {
+        let (w, h) = img.dimensions();
+        let size = w.min(h) as f32;
+        let (cx, cy) = (rng.range(0.0, w as f32), rng.range(0.0, h as f32));
+        let radius = rng.range(0.03, 0.1) * size;
+        let thickness = radius * 0.08;
+        let wobble_phase = rng.range(0.0, std::f32::consts::TAU);
+        let reach = radius * 1.1 + thickness;
+        for y in (cy - reach).max(0.0) as u32..((cy + reach) as u32).min(h) {
+            for x in (cx - reach).max(0.0) as u32..((cx + reach) as u32).min(w) {
+                let (dx, dy) = (x as f32 - cx, y as f32 - cy);
+                let ring = radius * (1.0 + 0.05 * (dy.atan2(dx) * 3.0 + wobble_phase).sin());
+                let from_ring = ((dx * dx + dy * dy).sqrt() - ring).abs();
+                let alpha = if from_ring < thickness {
+                    0.35 * (1.0 - from_ring / thickness)
+                }
+                else if dx * dx + dy * dy < ring * ring {
+                    0.06
+                }
+                else {
+                    0.0
+                };
+                if alpha > 0.0 {
+                    blend(img.get_pixel_mut(x, y), STAIN_COLOR, alpha);
+                }
+            }
+        }
+    }
+
+    // A fold right across the page: a dark line along the fold, with the paper on one side of it a little shadowed.
+    fn add_crease(img: &mut RgbImage, rng: &mut DamageRng) {
+        let (w, h) = img.dimensions();
+        // Folds run across the page from one edge to the opposite one.
+        let (start, end) = if rng.next_u64() % 2 == 0 {
+            ((0.0, rng.range(0.0, h as f32)), (w as f32, rng.range(0.0, h as f32)))
+        }
+        else {
+            ((rng.range(0.0, w as f32), 0.0), (rng.range(0.0, w as f32), h as f32))
+        };
+        let (dx, dy) = (end.0 - start.0, end.1 - start.1);
+        let length = dx.hypot(dy);
+        let width = w.min(h) as f32 * 0.002 + 1.0;
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            // Distance from the fold, positive on one side and negative on the other.
+            let distance = ((x as f32 - start.0) * dy - (y as f32 - start.1) * dx) / length;
+            if distance.abs() < width {
+                blend(pixel, Rgb([0, 0, 0]), 0.35 * (1.0 - distance.abs() / width));
+            }
+            else if distance > 0.0 {
+                blend(pixel, Rgb([0, 0, 0]), 0.04);
+            }
+        }
+    }
+
+    // A pair of holes left by a staple, somewhere near the top left corner where staples go.
+    fn add_staple_hole(img: &mut RgbImage, rng: &mut DamageRng) {
+        let size = img.width().min(img.height()) as f32;
+        let (x, y) = (rng.range(0.01, 0.08) * size, rng.range(0.01, 0.08) * size);
+        let angle = rng.range(0.0, std::f32::consts::PI);
+        let half_length = size * 0.02;
+        let radius = (size * 0.002).max(1.0) as i32;
+        for side in [-1.0, 1.0] {
+            let center = ((x + side * half_length * angle.cos()) as i32, (y + side * half_length * angle.sin()) as i32);
+            draw_filled_circle_mut(img, center, radius, Rgb([40, 40, 40]));
+        }
+    }
+
+    // Rips off a corner of the page along a ragged edge, leaving the scanner lid showing through.
+    // Corners are numbered clockwise from the top left.
+    fn tear_corner(img: &mut RgbImage, rng: &mut DamageRng, corner: u32) {
+        let (w, h) = img.dimensions();
+        let size = rng.range(0.05, 0.15) * w.min(h) as f32;
+        // Work it out for the top left corner, then flip it into place.
+        let mut points: Vec<(f32, f32)> = vec![(0.0, 0.0), (size, 0.0)];
+        let steps = 12;
+        for i in 1..steps {
+            let along = i as f32 / steps as f32;
+            let jitter = rng.range(-0.08, 0.08) * size;
+            points.push((size * (1.0 - along) + jitter, size * along + jitter));
+        }
+        points.push((0.0, size));
+        let poly: Vec<Point<i32>> = points.iter().map(|(x, y)| {
+            let x = if corner == 1 || corner == 2 { w as f32 - 1.0 - x } else { *x };
+            let y = if corner == 2 || corner == 3 { h as f32 - 1.0 - y } else { *y };
+            Point::new(x.round() as i32, y.round() as i32)
+        }).collect();
+        draw_polygon_mut(img, &poly, Rgb([255, 255, 255]));
+    }
+
+    // Turns the page a little and pulls its corners about, like a page which wasn't lying flat or straight on the glass.
+    fn misalign(&self, img: &RgbImage, rng: &mut DamageRng) -> RgbImage {
+        let (w, h) = (img.width() as f32, img.height() as f32);
+        let (cx, cy) = (w / 2.0, h / 2.0);
+        let theta = rng.range(-self.rotation, self.rotation).to_radians();
+        let jitter = self.skew * w.max(h);
+        let corners = [(0.0, 0.0), (w, 0.0), (w, h), (0.0, h)];
+        let mut moved = corners;
+        for m in moved.iter_mut() {
+            let (dx, dy) = (m.0 - cx, m.1 - cy);
+            *m = (
+                cx + dx * theta.cos() - dy * theta.sin() + rng.range(-jitter, jitter),
+                cy + dx * theta.sin() + dy * theta.cos() + rng.range(-jitter, jitter)
+            );
+        }
+        match Projection::from_control_points(corners, moved) {
+            Some(projection) => warp(img, &projection, Interpolation::Bilinear, Rgb([255, 255, 255])),
+            None => img.clone()
+        }
+    }
+
+    // Darkens the page further from wherever the light was strongest.
+    fn light_unevenly(&self, img: &mut RgbImage, rng: &mut DamageRng) {
+        let (w, h) = (img.width() as f32, img.height() as f32);
+        let (lx, ly) = (rng.range(0.0, w), rng.range(0.0, h));
+        let furthest = [(0.0, 0.0), (w, 0.0), (w, h), (0.0, h)].iter().map(|(x, y)| (x - lx).hypot(y - ly)).fold(0.0, f32::max);
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            let brightness = 1.0 - self.illumination * (x as f32 - lx).hypot(y as f32 - ly) / furthest;
+            for i in 0..3 {
+                pixel[i] = (pixel[i] as f32 * brightness).round() as u8;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn damage_is_repeatable_and_varies_by_page() {
+        let mut page = RgbImage::from_pixel(200, 260, Rgb([255, 255, 255]));
+        for y in (20..240).step_by(8) {
+            for x in 20..180 {
+                page.put_pixel(x, y, Rgb([0, 0, 0]));
+            }
+        }
+        let page = DynamicImage::ImageRgb8(page);
+        let simulator = ScanSimulator::new()
+            .seed(42)
+            .damage_spec("scale=2,blur=0.7,rotate=1,skew=0.01,light=0.2,cast=5:0:-5,noise=4,jpeg=80,stains=1,tears=2,creases=1,staples=1")
+            .unwrap()
+            .finalize();
+        let first = simulator.damage(&page, 1);
+        assert_eq!(first.dimensions(), (400, 520));
+        assert_eq!(first, simulator.damage(&page, 1));
+        assert_ne!(first, simulator.damage(&page, 2));
+        let noisy = |seed: u64| ScanSimulator::new().seed(seed).noise(4.0).finalize().damage(&page, 1);
+        assert_ne!(noisy(0), noisy(1));
+
+        assert!(ScanSimulator::new().damage_spec("smudges=3").is_err());
+        assert!(ScanSimulator::new().damage_spec("cast=1:2").is_err());
+        assert!(ScanSimulator::new().damage_spec("scale=0").is_err());
+        assert_eq!(ScanSimulator::new().damage(&page, 1), page.to_rgb8());
+    }
+}
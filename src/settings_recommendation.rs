@@ -0,0 +1,162 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0+ OR Zlib
+
+use crate::archive_human_output_file::{ArchiveHumanOutputFile, OutputFormat};
+use crate::color_multiplexer::{ColorMultiplexer, MultiplexMode};
+use crate::document_metadata::DocumentMetadata;
+use crate::page_barcode_packer::{BarcodeFormat, PageBarcodePacker, make_radial_damage_map};
+use crate::stress_test_page::{StressTestMeasurement, column_kind_name};
+
+// Share of codewords each QR error correction level can recover, from L to H, and the --ecmin percentage which picks that level.
+const EC_LEVELS: [(f32, u8); 4] = [(0.07, 0), (0.15, 25), (0.25, 50), (0.30, 75)];
+
+// Encode settings worked out from a scanned stress test.
+pub struct SettingsRecommendation {
+    pub multiplex_mode: MultiplexMode,
+    pub num_colors: u8,
+    pub dpi: u16,
+    pub ec_min: u8,
+    pub ec_max: u8,
+    // Worst module error rate of any plane at these settings, as measured.
+    pub module_error_rate: f32,
+    // Width of each barcode, in modules.
+    pub barcode_width: u32,
+    pub bytes_per_page: u32
+}
+
+impl SettingsRecommendation {
+    // Arguments to give the encoder for these settings.
+    pub fn arguments(&self, width: f32, height: f32) -> String {
+        let mode = match self.multiplex_mode {
+            MultiplexMode::Grayscale => " --multiplex grayscale".to_string(),
+            _ => format!(" -c {}", self.num_colors)
+        };
+        format!("-W {} -H {} -D {}{} --ecfunction radial --ecmin {} --ecmax {}", width, height, self.dpi, mode, self.ec_min, self.ec_max)
+    }
+
+    // Printer DPI is what the stress test was printed at, for saying how many of the printer's dots make up each module.
+    pub fn print_report(&self, width: f32, height: f32, printer_dpi: u16) {
+        println!("Recommended encode settings:");
+        match self.multiplex_mode {
+            MultiplexMode::Grayscale => println!("- Multiplexing: {} gray levels", self.num_colors),
+            _ => println!("- Colors: {}", self.num_colors)
+        }
+        println!("- DPI: {}", self.dpi);
+        println!("- Module size: {:.4} in ({:.1} dots at {} DPI)", 1.0 / self.dpi as f32, printer_dpi as f32 / self.dpi as f32, printer_dpi);
+        println!("- Error correction: {}% to {}%, for a worst module error rate of {:.2}%", self.ec_min, self.ec_max, self.module_error_rate * 100.0);
+        println!("- Barcode size: {} modules ({:.2} in)", self.barcode_width, self.barcode_width as f32 / self.dpi as f32);
+        println!("- Data per page: {} bytes", self.bytes_per_page);
+        println!();
+        println!("Encode with: {}", self.arguments(width, height));
+    }
+}
+
+// Picks the settings which fit the most data on a page of the given size, out of those the stress test read every plane of.
+// The safety margin is how many times the measured error rate the error correction has to be able to cover.
+pub fn recommend_settings(measurements: &[StressTestMeasurement], width: f32, height: f32, margin: f32) -> Option<SettingsRecommendation> {
+    let mut tested: Vec<(MultiplexMode, u16, u16)> = measurements.iter().map(|m| (m.multiplex_mode, m.colors, m.dpi)).collect();
+    tested.sort();
+    tested.dedup();
+
+    let mut best: Option<SettingsRecommendation> = None;
+    for (multiplex_mode, colors, dpi) in tested {
+        let planes: Vec<&StressTestMeasurement> = measurements.iter().filter(|m| m.multiplex_mode == multiplex_mode && m.colors == colors && m.dpi == dpi).collect();
+        if planes.len() < colors.ilog2() as usize {
+            // Some planes couldn't be read at all.
+            continue;
+        }
+
+        // A pattern without errors only tells us the rate is somewhere below one in that many modules, so don't count on it being zero.
+        let module_error_rate = planes.iter().map(|m| (m.module_errors + 1) as f32 / m.modules.max(1) as f32).fold(0.0, f32::max);
+        let codeword_error_rate = 1.0 - (1.0 - module_error_rate).powi(8);
+        let level = match EC_LEVELS.iter().position(|(recoverable, _)| *recoverable >= codeword_error_rate * margin) {
+            Some(l) => l,
+            None => continue
+        };
+        // Corners of the page get one level more than the center.
+        let ec_min = EC_LEVELS[level].1;
+        let ec_max = EC_LEVELS.get(level + 1).map(|l| l.1).unwrap_or(100);
+
+        let color_multiplexer = ColorMultiplexer::new(colors as u8).mode(multiplex_mode).finalize();
+        let (w, h) = ArchiveHumanOutputFile::new("", OutputFormat::PNG)
+            .size(width, height)
+            .dpi(dpi)
+            .get_barcode_image_size();
+        let packer = PageBarcodePacker::new(w, h, BarcodeFormat::QR)
+            .color_multiplexer(color_multiplexer)
+            .document_metadata(Some(DocumentMetadata::new("").finalize()))
+            .damage_likelihood_map(make_radial_damage_map(ec_min as f32 / 100.0, ec_max as f32 / 100.0))
+            .finalize();
+        let bytes_per_page = packer.data_bytes_per_page();
+        if bytes_per_page == 0 {
+            continue;
+        }
+        println!("- {} {} at {} DPI: {:.2}% module errors, {} bytes per page at {}% to {}% error correction", colors, column_kind_name(multiplex_mode).to_lowercase(), dpi, module_error_rate * 100.0, bytes_per_page, ec_min, ec_max);
+
+        if best.as_ref().map(|b| bytes_per_page > b.bytes_per_page).unwrap_or(true) {
+            best = Some(SettingsRecommendation {
+                multiplex_mode,
+                num_colors: colors as u8,
+                dpi,
+                ec_min,
+                ec_max,
+                module_error_rate,
+                barcode_width: packer.barcode_width(),
+                bytes_per_page
+            });
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Measurements of the first few planes of one column of the stress test, each with the same number of module errors out of 10000.
+    fn column(multiplex_mode: MultiplexMode, colors: u16, dpi: u16, planes: u16, module_errors: u32) -> Vec<StressTestMeasurement> {
+        (1..=planes).map(|plane| StressTestMeasurement { multiplex_mode, dpi, colors, plane, version: 10, modules: 10000, module_errors }).collect()
+    }
+
+    #[test]
+    fn error_correction_covers_the_margin() {
+        // 1 module in 100 spoils about 7.7% of codewords.
+        let measurements = column(MultiplexMode::Palette, 2, 300, 1, 99);
+        let ec_range = |margin: f32| recommend_settings(&measurements, 8.5, 11.0, margin).map(|r| (r.ec_min, r.ec_max));
+        assert_eq!(ec_range(0.5), Some((0, 25)));
+        assert_eq!(ec_range(1.0), Some((25, 50)));
+        assert_eq!(ec_range(2.0), Some((50, 75)));
+        assert_eq!(ec_range(3.5), Some((75, 100)));
+        // Not even level H recovers 4 times that.
+        assert_eq!(ec_range(4.0), None);
+    }
+
+    #[test]
+    fn skips_columns_with_unread_planes() {
+        let mut measurements = column(MultiplexMode::Palette, 2, 300, 1, 0);
+        // Only one of the two planes of 4 colors was read.
+        measurements.extend(column(MultiplexMode::Palette, 4, 300, 1, 0));
+        assert_eq!(recommend_settings(&measurements, 8.5, 11.0, 2.0).unwrap().num_colors, 2);
+
+        measurements.extend(column(MultiplexMode::Palette, 4, 300, 2, 0).into_iter().skip(1));
+        assert_eq!(recommend_settings(&measurements, 8.5, 11.0, 2.0).unwrap().num_colors, 4);
+    }
+
+    #[test]
+    fn picks_the_most_bytes_per_page() {
+        let columns = [
+            column(MultiplexMode::Palette, 2, 300, 1, 0),
+            column(MultiplexMode::Palette, 4, 150, 2, 0),
+            column(MultiplexMode::Palette, 4, 300, 2, 0),
+            // More planes, but enough errors to need the most error correction.
+            column(MultiplexMode::Palette, 8, 300, 3, 200),
+            column(MultiplexMode::Grayscale, 4, 150, 2, 0)
+        ];
+        let measurements: Vec<StressTestMeasurement> = columns.iter().flatten().map(|m| StressTestMeasurement { ..*m }).collect();
+        let best = recommend_settings(&measurements, 8.5, 11.0, 2.0).unwrap();
+        assert_eq!((best.multiplex_mode, best.num_colors, best.dpi, best.ec_min, best.ec_max), (MultiplexMode::Palette, 4, 300, 0, 25));
+        assert_eq!(best.arguments(8.5, 11.0), "-W 8.5 -H 11 -D 300 -c 4 --ecfunction radial --ecmin 0 --ecmax 25");
+        for c in &columns {
+            assert!(recommend_settings(c, 8.5, 11.0, 2.0).unwrap().bytes_per_page <= best.bytes_per_page);
+        }
+    }
+}
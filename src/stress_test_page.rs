@@ -2,8 +2,10 @@
 
 use crate::archive_human_output_file::*;
 use crate::archive_human_input_file::*;
-use crate::grayscale_recognizer::recognize_grayscale_barcodes;
-use crate::color_multiplexer::ColorMultiplexer;
+use crate::grayscale_recognizer::{locate_grayscale_barcodes, LocatedBarcode};
+use crate::palette_swatch::find_labeled_swatches;
+use crate::color_multiplexer::{ColorMultiplexer, MultiplexMode};
+use crate::pseudorandom::Xorshift32;
 extern crate image;
 extern crate regex;
 use image::{RgbImage, Rgb};
@@ -13,7 +15,74 @@ use imageproc::drawing::*;
 use qrencode::QrCode;
 use qrencode::bits::Bits;
 use qrencode::types::{Version, EcLevel, Mode};
+use qrencode::ec::{construct_codewords, max_allowed_errors};
 use regex::Regex;
+use std::fs;
+
+// Gray levels to test alongside the color palettes, in their own column to the right of them.  2 levels would just be monochrome again.
+const GRAY_LEVELS_TO_TEST: [u8; 1] = [4];
+
+// Every test barcode has a block of pseudorandom modules to its right, drawn on its module grid, for measuring how many modules come out wrong.
+// What the barcode says it's testing seeds the pattern, so the decoder can work it out from the barcode.
+const PATTERN_GAP: u32 = 4;
+const PATTERN_WIDTH: u32 = 8;
+
+// Error correction level of every test barcode on the first page.
+const TEST_EC_LEVEL: EcLevel = EcLevel::H;
+
+// Each page after the first is a layout test with one of these quiet zones between its barcodes, in modules.
+// The encoder's quiet zone was found to need to be around 40 to work around https://github.com/piderman314/bardecoder/issues/50, so this covers well either side of that.
+const QUIET_ZONES_TO_TEST: [u32; 5] = [2, 4, 8, 16, 40];
+// Rows of barcodes on the layout pages cycle through these versions, and each row cycles through every error correction level.
+const VERSIONS_TO_TEST: [i16; 5] = [5, 10, 15, 20, 25];
+const EC_LEVELS_TO_TEST: [EcLevel; 4] = [EcLevel::L, EcLevel::M, EcLevel::Q, EcLevel::H];
+
+// A barcode read from a layout test page: the DPI, error correction level, quiet zone, and version it tests, which copy it is, and how many copies were printed.
+type LayoutCopy = (u16, String, u32, i16, u16, u16);
+
+// What each kind of column is called in its test barcodes, which also seeds their patterns, and in what's printed and saved.
+pub fn column_kind_name(mode: MultiplexMode) -> &'static str {
+    match mode {
+        MultiplexMode::Grayscale => "Gray levels",
+        _ => "Colors"
+    }
+}
+
+// How well one plane of one test barcode came out.
+pub struct StressTestMeasurement {
+    // Palette for the color columns, or grayscale for the gray level one.
+    pub multiplex_mode: MultiplexMode,
+    pub dpi: u16,
+    pub colors: u16,
+    // Numbered from 1.
+    pub plane: u16,
+    pub version: i16,
+    pub modules: u32,
+    pub module_errors: u32
+}
+
+impl StressTestMeasurement {
+    pub fn module_error_rate(&self) -> f32 {
+        self.module_errors as f32 / self.modules.max(1) as f32
+    }
+
+    // How much of the barcode's error correction would be left over at this module error rate: 1 is untouched, 0 is right at the limit, and below 0 it would usually fail.
+    // Assumes errors are spread evenly, so any module error spoils its whole codeword.
+    pub fn ec_headroom(&self) -> f32 {
+        let version = Version::Normal(self.version);
+        let data_codewords = Bits::new(version).max_len(TEST_EC_LEVEL).unwrap() / 8;
+        let ec_codewords = construct_codewords(&vec![0; data_codewords], version, TEST_EC_LEVEL).unwrap().1.len();
+        let correctable = max_allowed_errors(version, TEST_EC_LEVEL).unwrap() as f32;
+        let expected_errors = (data_codewords + ec_codewords) as f32 * (1.0 - (1.0 - self.module_error_rate()).powi(8));
+        1.0 - expected_errors / correctable
+    }
+
+    fn to_json(&self) -> String {
+        format!("{{\"kind\": \"{}\", \"dpi\": {}, \"colors\": {}, \"plane\": {}, \"version\": {}, \"modules\": {}, \"module_errors\": {}, \"module_error_rate\": {}, \"ec_headroom\": {}}}",
+            column_kind_name(self.multiplex_mode), self.dpi, self.colors, self.plane, self.version, self.modules, self.module_errors, self.module_error_rate(), self.ec_headroom())
+    }
+}
+
 pub struct StressTestPage {
 }
 
@@ -39,6 +108,17 @@ impl<'a> StressTestPage {
         return 1;
     }
 
+    // One page for the DPI and color test, then one layout test page for each quiet zone.
+    pub fn num_pages() -> u16 {
+        1 + QUIET_ZONES_TO_TEST.len() as u16
+    }
+
+    // Pseudorandom modules for the pattern beside a test barcode, row by row, with true for dark.
+    fn test_pattern(dpi: u16, colors: u16, mode: MultiplexMode, plane: u16, rows: u32) -> Vec<bool> {
+        let mut rng = Xorshift32::new(crc32fast::hash(format!("{} {} {} {}", dpi, colors, column_kind_name(mode), plane).as_bytes()));
+        (0..rows * PATTERN_WIDTH).map(|_| rng.next_u32() & 1 == 1).collect()
+    }
+
     fn generate_barcode_filling_bits(qrcode_version: Version, ec_level: EcLevel, message: &str) -> Bits {
         let mut bits = Bits::new(qrcode_version);
         let max_bits = bits.max_len(ec_level).unwrap();
@@ -58,6 +138,32 @@ impl<'a> StressTestPage {
         bits
     }
 
+    // Draws one plane of a test barcode with its pattern beside it, with modules 2 ^ scale pixels across.
+    fn render_test_plane(version: i16, scale: u32, dpi: u16, colors: u16, mode: MultiplexMode, plane: u16) -> RgbImage {
+        let color_description_long = format!("{} {}, color #{}", colors, column_kind_name(mode), plane);
+        println!("Generating {} barcode at {} DPI", color_description_long, dpi);
+        let space_for_color = String::from("=").repeat(plane as usize);
+        let message = format!("{} Test at {} DPI in {} =====", space_for_color, dpi, color_description_long);
+        let bits = StressTestPage::generate_barcode_filling_bits(Version::Normal(version), TEST_EC_LEVEL, &message);
+
+        // Generate the QR code.
+        let code = QrCode::with_bits(bits, TEST_EC_LEVEL).unwrap();
+        let code_image = code.render::<Rgb<u8>>().module_dimensions(1 << scale, 1 << scale).quiet_zone(false).build();
+
+        // And the pattern beside it.
+        let side = code.width() as u32;
+        let mut plane_image = RgbImage::from_pixel((side + PATTERN_GAP + PATTERN_WIDTH) << scale, side << scale, Rgb([255, 255, 255]));
+        imageops::overlay(&mut plane_image, &code_image, 0, 0);
+        for (i, dark) in StressTestPage::test_pattern(dpi, colors, mode, plane, side).into_iter().enumerate() {
+            if dark {
+                let module_x = side + PATTERN_GAP + i as u32 % PATTERN_WIDTH;
+                let module_y = i as u32 / PATTERN_WIDTH;
+                draw_filled_rect_mut(&mut plane_image, Rect::at((module_x << scale) as i32, (module_y << scale) as i32).of_size(1 << scale, 1 << scale), Rgb([0, 0, 0]));
+            }
+        }
+        plane_image
+    }
+
     pub fn encode(&self, writer: &ArchiveHumanOutputFile, max_color_multiplexer: &ColorMultiplexer) {
         // Maximum DPI will be native resolution.  Each successive decrease in resolution will be by half, resulting in full pixels.
         let barcode_image_size = writer.get_barcode_image_size();
@@ -67,160 +173,449 @@ impl<'a> StressTestPage {
         let mut out_image = RgbImage::new(barcode_image_size.0, barcode_image_size.1);
         draw_filled_rect_mut(&mut out_image, Rect::at(0, 0).of_size(barcode_image_size.0, barcode_image_size.1), Rgb([255, 255, 255]));
 
+        // One column for each palette size, then one for each number of gray levels.
+        let max_color_bits_to_test = max_color_multiplexer.num_planes();
+        let mut columns: Vec<(ColorMultiplexer, MultiplexMode)> = (1..(max_color_bits_to_test + 1)).map(|b| (ColorMultiplexer::new(2_u8.pow(b as u32)), MultiplexMode::Palette)).collect();
+        columns.extend(GRAY_LEVELS_TO_TEST.iter().map(|l| (ColorMultiplexer::new(*l).mode(MultiplexMode::Grayscale), MultiplexMode::Grayscale)));
+
         // Figure out sizes for each barcode.
         // We want to aim for 1/3 of the height (minus a quiet zone) and fill the with a barcode.
         // Barcodes are padded to fill the space so we can get the most out of the error rate information.
         let quiet_zone = 8; // 8 pixels at full resolution, since the lower-DPI barcodes require larger-pixel quiet zones to work.
         let large_barcode_height = (((barcode_image_size.1 - quiet_zone) / 4) - quiet_zone) as i16;
+        // With a lot of columns, the barcodes have to be narrow enough to fit side by side, too.
+        let column_width = (barcode_image_size.0 / columns.len() as u32 - quiet_zone) as i16;
         //println!("Maximum height: {}", large_barcode_height);
-        let largest_barcode_version = StressTestPage::largest_qrcode_version_for_width(large_barcode_height);
+        let largest_barcode_version = StressTestPage::largest_qrcode_version_for_width(large_barcode_height.min(column_width - (PATTERN_GAP + PATTERN_WIDTH) as i16));
         println!("Largest barcode version: {}", largest_barcode_version);
-        let max_color_bits_to_test = max_color_multiplexer.num_planes();
-        let ec_level = EcLevel::H;
-        for num_colors_bits in 1..(max_color_bits_to_test + 1) {
-            let x = (barcode_image_size.0 / max_color_bits_to_test as u32) * (num_colors_bits - 1) as u32;
-            let num_colors = (2 as u8).pow(num_colors_bits as u32);
-            let multiplexer = ColorMultiplexer::new(num_colors);
+
+        for (column, (multiplexer, mode)) in columns.iter().enumerate() {
+            let x = (barcode_image_size.0 / columns.len() as u32) * column as u32;
+            let num_colors = multiplexer.num_colors();
+            let num_colors_bits = multiplexer.num_planes();
             for y in 0..4 {
                 // Fill up the size of QR we're generating.
                 let color_barcodes:Vec<RgbImage> = (0..num_colors_bits).map(|c:u8| {
-                    let qrcode_version = Version::Normal(largest_barcode_version >> y);
-                    let dpi = full_dpi >> y;
-                    let color_description_long = format!("{} Colors, color #{}", num_colors, (c + 1));
-                    println!("Generating {} barcode at {} DPI", color_description_long, dpi);
-                    let space_for_color = String::from("=").repeat((c + 1) as usize);
-                    let message = format!("{} Test at {} DPI in {} =====", space_for_color, dpi, color_description_long);
-                    let bits = StressTestPage::generate_barcode_filling_bits(qrcode_version, ec_level, &message);
-
-                    // Generate the QR code.
-                    let code = QrCode::with_bits(bits, ec_level).unwrap();
-                    code.render::<Rgb<u8>>().module_dimensions(1 << y, 1 << y).quiet_zone(false).build()
+                    StressTestPage::render_test_plane(largest_barcode_version >> y, y as u32, full_dpi >> y, num_colors as u16, *mode, (c + 1) as u16)
                 }).collect::<Vec<RgbImage>>();
-                let code_image = multiplexer.multiplex_planes(color_barcodes);
+                let code_image = multiplexer.multiplex_planes(color_barcodes, 0);
                 imageops::overlay(&mut out_image, &code_image, x as i64, (((y * large_barcode_height) as u32) + quiet_zone) as i64);
             }
         }
 
         // Feed it to the writer.
         writer.write_page(&out_image, 0);
+
+        for (page, quiet_zone) in QUIET_ZONES_TO_TEST.iter().enumerate() {
+            StressTestPage::encode_layout_page(writer, *quiet_zone, page as u16 + 1);
+        }
     }
 
-    pub fn decode(&self, reader: &ArchiveHumanInputFile, max_color_multiplexer: &ColorMultiplexer) {
-        println!("Reading image");
-        let image = reader.read_page().unwrap();
+    // Fills a page with black and white barcodes packed as tightly as the quiet zone allows, the way the encoder packs them.
+    // Each one says what it's testing and which copy it is, so the decoder can tell how many of each it should have found.
+    fn encode_layout_page(writer: &ArchiveHumanOutputFile, quiet_zone: u32, page_num: u16) {
+        let (width, height) = writer.get_barcode_image_size();
+        println!("Generating layout test with a quiet zone of {}", quiet_zone);
+        writer.write_page(&StressTestPage::layout_page_image(width, height, writer.get_dpi(), quiet_zone), page_num);
+    }
 
-        // For each bitplane depth, demultiplex and try decoding.
-        let re = Regex::new(r"= Test at ([0-9]+) DPI in ([0-9]+) Colors, color #([0-9]+)").unwrap();
-        let max_color_bits_to_test = max_color_multiplexer.num_planes();
-        for num_colors_bits in (1..(max_color_bits_to_test + 1)).rev() {
-            let mut located_all = true;
-            let num_colors = (2 as u8).pow(num_colors_bits as u32);
-            println!("Attempting to decode at {} colors...", num_colors);
-            let multiplexer = ColorMultiplexer::new(num_colors);
-            //println!("- Detecting colors...");
-            //multiplexer.palettize_from_image(&image);
-            println!("- Demultiplexing...");
-            let bit_planes = multiplexer.demultiplex_image(&image);
-            let mut found_barcodes = vec![];
-            let mut dpis_found = vec![];
-            let mut colors_found = vec![];
-            for p in bit_planes {
-                println!("- Finding barcodes in bit plane...");
-                let barcodes = recognize_grayscale_barcodes(&p);
-                for b in barcodes {
-                    // Attempt to parse this barcode.
-                    let hay = String::from_utf8(b).unwrap();
-                    //println!("Decoded barcode as {}", hay);
-                    match re.captures(hay.as_str()) {
-                        Some(cap) => {
-                            // We've got a match, which means we have information on what we were able to read.
-                            let dpi = cap.get(1).unwrap().as_str().parse::<u16>().unwrap();
-                            let colors = cap.get(2).unwrap().as_str().parse::<u16>().unwrap();
-                            let color_num = cap.get(3).unwrap().as_str().parse::<u16>().unwrap();
-                            found_barcodes.push((dpi, colors, color_num));
-                            dpis_found.push(dpi);
-                            colors_found.push(colors);
-                        },
-                        None => { }
+    fn layout_page_image(width: u32, height: u32, dpi: u16, quiet_zone: u32) -> RgbImage {
+
+        // Work out where everything goes first, since every copy needs to know how many copies there are.
+        let mut placements: Vec<(u32, u32, i16, EcLevel)> = vec![];
+        let mut next_y = quiet_zone;
+        let mut row = 0;
+        let fits = |v: &i16| Version::Normal(*v).width() as u32 + quiet_zone * 2 <= width.min(height);
+        let versions: Vec<i16> = VERSIONS_TO_TEST.iter().copied().filter(fits).collect();
+        while !versions.is_empty() {
+            let version = versions[row % versions.len()];
+            let side = Version::Normal(version).width() as u32;
+            if next_y + side + quiet_zone > height {
+                break;
+            }
+            let mut next_x = quiet_zone;
+            let mut column = 0;
+            while next_x + side + quiet_zone <= width {
+                placements.push((next_x, next_y, version, EC_LEVELS_TO_TEST[column % EC_LEVELS_TO_TEST.len()]));
+                next_x += side + quiet_zone;
+                column += 1;
+            }
+            next_y += side + quiet_zone;
+            row += 1;
+        }
+
+        let mut out_image = RgbImage::from_pixel(width, height, Rgb([255, 255, 255]));
+        for (i, (x, y, version, ec_level)) in placements.iter().enumerate() {
+            let copies = placements.iter().filter(|p| p.2 == *version && p.3 == *ec_level).count();
+            let copy = placements[..i].iter().filter(|p| p.2 == *version && p.3 == *ec_level).count() + 1;
+            // This has to fit in the smallest version tested at EC level H, which only holds 43 bytes.
+            let message = format!("=LT {}DPI EC:{:?} QZ:{} V:{} #{}/{}=", dpi, ec_level, quiet_zone, version, copy, copies);
+            let bits = StressTestPage::generate_barcode_filling_bits(Version::Normal(*version), *ec_level, &message);
+            let code = QrCode::with_bits(bits, *ec_level).unwrap();
+            let code_image = code.render::<Rgb<u8>>().module_dimensions(1, 1).quiet_zone(false).build();
+            imageops::overlay(&mut out_image, &code_image, *x as i64, *y as i64);
+        }
+        out_image
+    }
+
+    // Every barcode found on a layout test page, once each.
+    fn read_layout_page(image: &image::DynamicImage) -> Vec<LayoutCopy> {
+        let re = Regex::new(r"=LT ([0-9]+)DPI EC:([LMQH]) QZ:([0-9]+) V:([0-9]+) #([0-9]+)/([0-9]+)=").unwrap();
+        let mut found: Vec<LayoutCopy> = vec![];
+        for b in locate_grayscale_barcodes(image) {
+            let hay = String::from_utf8_lossy(&b.data).to_string();
+            if let Some(cap) = re.captures(hay.as_str()) {
+                let copy = (
+                    cap[1].parse::<u16>().unwrap(),
+                    cap[2].to_string(),
+                    cap[3].parse::<u32>().unwrap(),
+                    cap[4].parse::<i16>().unwrap(),
+                    cap[5].parse::<u16>().unwrap(),
+                    cap[6].parse::<u16>().unwrap()
+                );
+                // Blocks we search in overlap, so the same barcode can turn up more than once.
+                if !found.contains(&copy) {
+                    found.push(copy);
+                }
+            }
+        }
+        found
+    }
+
+    // Reads a layout test page and prints how many of each kind of barcode were found, returning whether it was one.
+    fn decode_layout_page(image: &image::DynamicImage) -> bool {
+        let found = StressTestPage::read_layout_page(image);
+        if found.is_empty() {
+            return false;
+        }
+
+        let dpi = found[0].0;
+        let quiet_zone = found[0].2;
+        println!("Layout test at {} DPI with a quiet zone of {}:", dpi, quiet_zone);
+        println!("Barcodes found out of those printed, by version and error correction level:");
+        print!("{:<9}", "Version");
+        for ec_level in EC_LEVELS_TO_TEST {
+            print!("{:^9}", format!("{:?}", ec_level));
+        }
+        println!();
+        for v in VERSIONS_TO_TEST {
+            print!("{:<9}", v);
+            for ec_level in EC_LEVELS_TO_TEST {
+                let ec_name = format!("{:?}", ec_level);
+                let copies: Vec<&LayoutCopy> = found.iter().filter(|f| f.3 == v && f.1 == ec_name).collect();
+                match copies.first() {
+                    Some(c) => print!("{:^9}", format!("{}/{}", copies.len(), c.5)),
+                    // Without finding any, we can't tell how many there were.
+                    None => print!("{:^9}", "0")
+                }
+            }
+            println!();
+        }
+        true
+    }
+
+    // Reads every stress test barcode from the image that the given kind of column was written with, returning the DPI, the number of colors, and the plane number of each one.
+    // Also measures the module error rate of the barcodes' planes which were written with the same number of colors we demultiplexed with, since those are the only ones demultiplexed properly.
+    fn find_barcodes(image: &image::DynamicImage, multiplexer: &ColorMultiplexer, mode: MultiplexMode) -> (Vec<(u16, u16, u16)>, Vec<StressTestMeasurement>) {
+        let re = Regex::new(&format!(r"= Test at ([0-9]+) DPI in ([0-9]+) {}, color #([0-9]+)", column_kind_name(mode))).unwrap();
+        println!("- Demultiplexing...");
+        let bit_planes = multiplexer.demultiplex_image(image);
+        let mut found_barcodes = vec![];
+        let mut measurements: Vec<StressTestMeasurement> = vec![];
+        for p in bit_planes {
+            println!("- Finding barcodes in bit plane...");
+            let barcodes = locate_grayscale_barcodes(&p);
+            for b in barcodes {
+                // Attempt to parse this barcode.
+                // Misreads can come out as anything, so don't insist on valid UTF-8.
+                let hay = String::from_utf8_lossy(&b.data).to_string();
+                //println!("Decoded barcode as {}", hay);
+                if let Some(cap) = re.captures(hay.as_str()) {
+                    // We've got a match, which means we have information on what we were able to read.
+                    let dpi = cap.get(1).unwrap().as_str().parse::<u16>().unwrap();
+                    let colors = cap.get(2).unwrap().as_str().parse::<u16>().unwrap();
+                    let color_num = cap.get(3).unwrap().as_str().parse::<u16>().unwrap();
+                    // Blocks we search in overlap, so the same barcode can turn up more than once.
+                    if found_barcodes.contains(&(dpi, colors, color_num)) {
+                        continue;
+                    }
+                    found_barcodes.push((dpi, colors, color_num));
+                    if colors == multiplexer.num_colors() as u16 {
+                        let modules = b.side * PATTERN_WIDTH;
+                        let module_errors = StressTestPage::count_pattern_errors(&p, &b, StressTestPage::test_pattern(dpi, colors, mode, color_num, b.side));
+                        measurements.push(StressTestMeasurement { multiplex_mode: mode, dpi, colors, plane: color_num, version: ((b.side - 17) / 4) as i16, modules, module_errors });
                     }
                 }
             }
+        }
+        (found_barcodes, measurements)
+    }
 
-            // Check to see if the maximum we found was fully parsed.
-            dpis_found.sort();
-            dpis_found.dedup();
-            colors_found.sort();
-            colors_found.dedup();
-            if dpis_found.len() > 0 && colors_found.len() > 0 {
-                println!("- Highest DPI found: {}", dpis_found[dpis_found.len() - 1]);
-                println!("- Highest colors found: {}", colors_found[colors_found.len() - 1]);
+    // Compares the pattern beside a barcode found in a demultiplexed plane with what it should be.
+    fn count_pattern_errors(plane: &image::DynamicImage, barcode: &LocatedBarcode, pattern: Vec<bool>) -> u32 {
+        let luma = plane.to_luma8();
+        let mut errors = 0;
+        for (i, dark) in pattern.into_iter().enumerate() {
+            let (x, y) = barcode.module_position((barcode.side + PATTERN_GAP + i as u32 % PATTERN_WIDTH) as f32 + 0.5, (i as u32 / PATTERN_WIDTH) as f32 + 0.5);
+            // Modules off the edge of the page count as wrong.
+            let (px, py) = ((x - 0.5).round(), (y - 0.5).round());
+            let read_dark = if px >= 0.0 && py >= 0.0 && (px as u32) < luma.width() && (py as u32) < luma.height() {
+                Some(luma.get_pixel(px as u32, py as u32)[0] < 128)
+            }
+            else {
+                None
+            };
+            if read_dark != Some(dark) {
+                errors += 1;
+            }
+        }
+        errors
+    }
 
-                // Print the header.
-                println!();
-                println!("Found at this level:");
-                print!("     ");
-                for c in colors_found.clone() {
-                    print!("{}", format!("{:^5}", c));
+    // Prints the module error rate of each plane, and how much error correction that leaves, at each DPI.
+    fn print_error_table(measurements: &[StressTestMeasurement]) {
+        if measurements.is_empty() {
+            return;
+        }
+        let mut dpis: Vec<u16> = measurements.iter().map(|m| m.dpi).collect();
+        let mut planes: Vec<u16> = measurements.iter().map(|m| m.plane).collect();
+        dpis.sort();
+        dpis.dedup();
+        planes.sort();
+        planes.dedup();
+
+        println!();
+        println!("Module error rate (error correction left) at {} {}:", measurements[0].colors, column_kind_name(measurements[0].multiplex_mode).to_lowercase());
+        print!("     ");
+        for p in &planes {
+            print!("{:^17}", format!("Plane {}", p));
+        }
+        println!();
+        for d in dpis {
+            print!("{:<5}", d);
+            for p in &planes {
+                match measurements.iter().find(|m| m.dpi == d && m.plane == *p) {
+                    Some(m) => print!("{:^17}", format!("{:.2}% ({:.0}%)", m.module_error_rate() * 100.0, m.ec_headroom() * 100.0)),
+                    None => print!("{:^17}", "-")
                 }
-                println!();
+            }
+            println!();
+        }
+    }
 
-                // Print each row
-                for d in dpis_found {
-                    print!("{}", format!("{:<5}", d));
-                    for c in colors_found.clone() {
-                        // Check if we have barcodes for each bit plane.
-                        let mut found_all_colors = true;
-                        let mut found_some_colors = false;
-                        let num_planes = u16::ilog2(c);
-                        //println!("Number of colors to search for barcodes {}", num_colors);
-                        for e in 1..(num_planes + 1) as u16 {
-                            let mut found_color = false;
-                            let barcodes_to_search = found_barcodes.clone();
-                            for (dpi, colors, color_num) in barcodes_to_search {
-                                if dpi == d && colors == c && color_num == e {
-                                    found_color = true;
-                                    //println!("Found barcode for {}, {}, {}", dpi, colors, color_num);
-                                    break;
-                                }
-                            }
-                            if found_color {
-                                found_some_colors = true;
-                            }
-                            else {
-                                //println!("Didn't find colors for {}, {}, {}", d, c, e);
-                                found_all_colors = false;
+    // Saves every measurement as JSON, for comparing runs or plotting.
+    fn save_measurements(measurements: &[StressTestMeasurement], out_file: &str) -> std::io::Result<()> {
+        let entries: Vec<String> = measurements.iter().map(|m| format!("    {}", m.to_json())).collect();
+        fs::write(out_file, format!("{{\n  \"measurements\": [\n{}\n  ]\n}}\n", entries.join(",\n")))
+    }
+
+    // Prints a table of which DPIs and numbers of colors we found barcodes for, returning whether every plane was found for all of them.
+    fn print_found_table(found_barcodes: &[(u16, u16, u16)]) -> bool {
+        let mut located_all = true;
+        let mut dpis_found: Vec<u16> = found_barcodes.iter().map(|b| b.0).collect();
+        let mut colors_found: Vec<u16> = found_barcodes.iter().map(|b| b.1).collect();
+
+        // Check to see if the maximum we found was fully parsed.
+        dpis_found.sort();
+        dpis_found.dedup();
+        colors_found.sort();
+        colors_found.dedup();
+        if !dpis_found.is_empty() && !colors_found.is_empty() {
+            println!("- Highest DPI found: {}", dpis_found[dpis_found.len() - 1]);
+            println!("- Highest colors found: {}", colors_found[colors_found.len() - 1]);
+
+            // Print the header.
+            println!();
+            println!("Found at this level:");
+            print!("     ");
+            for c in colors_found.clone() {
+                print!("{:^5}", c);
+            }
+            println!();
+
+            // Print each row
+            for d in dpis_found {
+                print!("{:<5}", d);
+                for c in colors_found.clone() {
+                    // Check if we have barcodes for each bit plane.
+                    let mut found_all_colors = true;
+                    let mut found_some_colors = false;
+                    let num_planes = u16::ilog2(c);
+                    //println!("Number of colors to search for barcodes {}", num_colors);
+                    for e in 1..(num_planes + 1) as u16 {
+                        let mut found_color = false;
+                        for (dpi, colors, color_num) in found_barcodes {
+                            if *dpi == d && *colors == c && *color_num == e {
+                                found_color = true;
+                                //println!("Found barcode for {}, {}, {}", dpi, colors, color_num);
+                                break;
                             }
                         }
-                        if found_all_colors {
-                            print!("  *  ");
-                        }
-                        else if found_some_colors {
-                            located_all = false;
-                            print!("  ?  ");
+                        if found_color {
+                            found_some_colors = true;
                         }
                         else {
-                            located_all = false;
-                            print!("     ");
+                            //println!("Didn't find colors for {}, {}, {}", d, c, e);
+                            found_all_colors = false;
                         }
                     }
-                    println!();
+                    if found_all_colors {
+                        print!("  *  ");
+                    }
+                    else if found_some_colors {
+                        located_all = false;
+                        print!("  ?  ");
+                    }
+                    else {
+                        located_all = false;
+                        print!("     ");
+                    }
                 }
-            }
-            else {
-                println!("- Did not find any usable barcodes at this color depth");
-            }
-            if located_all {
                 println!();
-                println!("Success!  All levels successfully found!  Stopping search.");
-                break;
             }
-            else {
-                println!();
-                println!("Did not find complete combinations of DPI and colors.  Trying again at lower color depth...");
+        }
+        else {
+            println!("- Did not find any usable barcodes at this color depth");
+            located_all = false;
+        }
+        located_all
+    }
+
+    // Takes every page of the stress test, in any order.
+    // If given an output file, saves the module error rates measured there as JSON.
+    // Returns every measurement, for recommending settings from.
+    pub fn decode(&self, readers: &[ArchiveHumanInputFile], max_color_multiplexer: &ColorMultiplexer, out_file: Option<&String>) -> Vec<StressTestMeasurement> {
+        let mut all_measurements: Vec<StressTestMeasurement> = vec![];
+        for reader in readers {
+            println!("Reading image");
+            let image = reader.read_page().unwrap();
+            // Layout test barcodes are black and white, so they can be read without demultiplexing.
+            if StressTestPage::decode_layout_page(&image) {
                 println!();
                 println!("=====");
                 println!();
+                continue;
             }
+            all_measurements.extend(StressTestPage::decode_dpi_page(&image, max_color_multiplexer));
+        }
+
+        if let Some(out_file) = out_file {
+            StressTestPage::save_measurements(&all_measurements, out_file).expect("Could not save stress test measurements");
+            println!("Saved module error rates to {}", out_file);
         }
+        all_measurements
     }
-}
\ No newline at end of file
+
+    // Reads the page testing each DPI and number of colors.
+    fn decode_dpi_page(image: &image::DynamicImage, max_color_multiplexer: &ColorMultiplexer) -> Vec<StressTestMeasurement> {
+        let mut all_measurements: Vec<StressTestMeasurement> = vec![];
+
+        // Gray levels have their own column, so they're tested on their own.
+        // Printers rarely hit the shades exactly, so learn them from the page first.
+        let swatches = find_labeled_swatches(image);
+        for levels in GRAY_LEVELS_TO_TEST {
+            println!("Attempting to decode at {} gray levels...", levels);
+            let mut multiplexer = ColorMultiplexer::new(levels).mode(MultiplexMode::Grayscale).finalize();
+            multiplexer.palettize_from_image(image, &swatches);
+            let (found_barcodes, measurements) = StressTestPage::find_barcodes(image, &multiplexer, MultiplexMode::Grayscale);
+            StressTestPage::print_found_table(&found_barcodes);
+            StressTestPage::print_error_table(&measurements);
+            all_measurements.extend(measurements);
+            println!();
+            println!("=====");
+            println!();
+        }
+
+        // For each bitplane depth, demultiplex and try decoding.
+        // Every depth is measured, so settings can be recommended from any of them, but once every barcode has been found there's no need to show where they were found again.
+        let max_color_bits_to_test = max_color_multiplexer.num_planes();
+        let mut located_all = false;
+        for num_colors_bits in (1..(max_color_bits_to_test + 1)).rev() {
+            let num_colors = 2_u8.pow(num_colors_bits as u32);
+            println!("Attempting to decode at {} colors...", num_colors);
+            let multiplexer = ColorMultiplexer::new(num_colors);
+            //println!("- Detecting colors...");
+            //multiplexer.palettize_from_image(image);
+            let (found_barcodes, measurements) = StressTestPage::find_barcodes(image, &multiplexer, MultiplexMode::Palette);
+            let newly_located_all = !located_all && StressTestPage::print_found_table(&found_barcodes);
+            StressTestPage::print_error_table(&measurements);
+            all_measurements.extend(measurements);
+            println!();
+            if newly_located_all {
+                println!("Success!  All levels successfully found!  Still measuring lower color depths to recommend settings from.");
+            }
+            else if !located_all {
+                println!("Did not find complete combinations of DPI and colors.  Trying again at lower color depth...");
+            }
+            located_all |= newly_located_all;
+            println!();
+            println!("=====");
+            println!();
+        }
+
+        all_measurements
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_flipped_pattern_modules() {
+        let (dpi, colors, plane) = (300, 2, 1);
+        let test_plane = StressTestPage::render_test_plane(5, 0, dpi, colors, MultiplexMode::Palette, plane);
+        let margin = 8;
+        let mut page = RgbImage::from_pixel(test_plane.width() + margin * 2, test_plane.height() + margin * 2, Rgb([255, 255, 255]));
+        imageops::overlay(&mut page, &test_plane, margin as i64, margin as i64);
+        // Flip the first, last, and a middle module of the pattern, which at one pixel per module is one pixel each.
+        let side = Version::Normal(5).width() as u32;
+        let flips = [0, side * PATTERN_WIDTH / 2 + 3, side * PATTERN_WIDTH - 1];
+        for i in flips {
+            let pixel = page.get_pixel_mut(margin + side + PATTERN_GAP + i % PATTERN_WIDTH, margin + i / PATTERN_WIDTH);
+            *pixel = Rgb(pixel.0.map(|c| 255 - c));
+        }
+        let page = image::DynamicImage::ImageRgb8(page);
+
+        let barcodes = locate_grayscale_barcodes(&page);
+        assert!(!barcodes.is_empty());
+        let b = &barcodes[0];
+        assert_eq!(b.side, side);
+        let module_errors = StressTestPage::count_pattern_errors(&page, b, StressTestPage::test_pattern(dpi, colors, MultiplexMode::Palette, plane, b.side));
+        assert_eq!(module_errors, flips.len() as u32);
+
+        let measurement = |module_errors: u32| StressTestMeasurement { multiplex_mode: MultiplexMode::Palette, dpi, colors, plane, version: 5, modules: side * PATTERN_WIDTH, module_errors };
+        assert_eq!(measurement(0).module_error_rate(), 0.0);
+        assert_eq!(measurement(0).ec_headroom(), 1.0);
+        let flipped = measurement(module_errors);
+        assert_eq!(flipped.module_error_rate(), 3.0 / 296.0);
+        // Version 5 at level H has 134 codewords, of which 44 can be corrected.
+        let expected_errors = 134.0 * (1.0 - (1.0 - 3.0f32 / 296.0).powi(8));
+        assert!((flipped.ec_headroom() - (1.0 - expected_errors / 44.0)).abs() < 1e-5);
+        // With half the modules wrong, almost every codeword is spoiled, which is far more than can be corrected.
+        assert!(measurement(side * PATTERN_WIDTH / 2).ec_headroom() < -1.0);
+    }
+
+    #[test]
+    fn counts_layout_copies() {
+        // Small enough that bardecoder doesn't miss any of the barcodes after the first on each row, so every copy should be found.
+        let quiet_zone = 8;
+        let mut page = StressTestPage::layout_page_image(400, 400, 150, quiet_zone);
+        let found = StressTestPage::read_layout_page(&image::DynamicImage::ImageRgb8(page.clone()));
+        assert!(!found.is_empty());
+        assert!(found.iter().all(|f| f.0 == 150 && f.2 == quiet_zone));
+        // Every copy of every kind was found.
+        for f in &found {
+            assert_eq!(found.iter().filter(|g| g.1 == f.1 && g.3 == f.3).count(), f.5 as usize);
+        }
+
+        // Paint over the first barcode, which is version 5 at level L, in the top left corner.
+        let side = Version::Normal(5).width() as u32;
+        draw_filled_rect_mut(&mut page, Rect::at(quiet_zone as i32, quiet_zone as i32).of_size(side, side), Rgb([255, 255, 255]));
+        let damaged = StressTestPage::read_layout_page(&image::DynamicImage::ImageRgb8(page));
+        assert_eq!(damaged.len(), found.len() - 1);
+        let copies = |copies: &[LayoutCopy]| copies.iter().filter(|f| f.1 == "L" && f.3 == 5).count();
+        assert_eq!(copies(&damaged), copies(&found) - 1);
+        assert!(!damaged.iter().any(|f| f.1 == "L" && f.3 == 5 && f.4 == 1));
+    }
+}
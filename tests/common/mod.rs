@@ -0,0 +1,59 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0+ OR Zlib
+
+// Helpers for running the command line tool from the integration tests.
+// Not every test uses all of them.
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+pub const PAGE_OPTIONS: [&str; 6] = ["-W", "2", "-H", "2", "-m", "0.1 0.1 0.1 0.1"];
+pub const DEFAULT_DPI: &str = "150";
+
+// A scratch directory of its own for each test, so tests can run in parallel.  Names have to be unique across all of the test files.
+pub fn work_dir(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Pseudorandom, so it doesn't compress into something easier than real data, but the same on every run.
+pub fn test_data(length: usize, seed: u32) -> Vec<u8> {
+    // xorshift32.
+    let mut state = seed | 1;
+    (0..length).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        (state >> 24) as u8
+    }).collect()
+}
+
+pub fn run(args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_realworldarchive")).args(args).output().unwrap();
+    println!("{}", String::from_utf8_lossy(&output.stdout));
+    println!("{}", String::from_utf8_lossy(&output.stderr));
+    output
+}
+
+// The small page above, with whatever else the test asks for.
+pub fn add_page_options<'a>(args: &mut Vec<&'a str>, options: &[&'a str]) {
+    args.extend_from_slice(&PAGE_OPTIONS);
+    if !options.contains(&"-D") {
+        args.extend_from_slice(&["-D", DEFAULT_DPI]);
+    }
+    args.extend_from_slice(options);
+}
+
+// Encodes the data with the page options above plus any given, and returns the pages written in page order.
+pub fn encode(dir: &Path, data: &[u8], options: &[&str]) -> Vec<PathBuf> {
+    let in_file = dir.join("input.bin");
+    fs::write(&in_file, data).unwrap();
+    let out_prefix = dir.join("page");
+    let mut args = vec!["-e", "-i", in_file.to_str().unwrap(), "-o", out_prefix.to_str().unwrap()];
+    add_page_options(&mut args, options);
+    assert!(run(&args).status.success(), "Encoding failed");
+    (1..).map(|p| dir.join(format!("page{}.png", p))).take_while(|p| p.exists()).collect()
+}
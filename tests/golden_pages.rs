@@ -0,0 +1,105 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0+ OR Zlib
+
+// The same input and options have to encode to exactly the same pages every time, so these pin what fixed inputs encode to.
+// Pixels are hashed rather than files, so a new version of the PNG encoder doesn't count as a layout change.
+// The metadata barcode and format description page record the encoder version, so it's pinned here to keep releases from changing these.
+// If a change to the layout is intended, bump the format version if decoders need to know, and update the hashes here with the ones the failing test prints.
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+use common::*;
+
+// The page header shows the input filename unless there's a title, and the test directory is somewhere different on every machine.
+const PINNED_OPTIONS: [&str; 4] = ["--title", "Golden", "--encoderversion", "golden"];
+
+// Hashes of every page in the directory, in page order, including page 0 if there is one.
+fn page_hashes(dir: &Path) -> Vec<u32> {
+    (0..).map(|p| dir.join(format!("page{}.png", p)))
+        .skip_while(|p| p.ends_with("page0.png") && !p.exists())
+        .take_while(|p| p.exists())
+        .map(|p| {
+            let image = image::open(p).unwrap().to_rgb8();
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(&image.width().to_be_bytes());
+            hasher.update(&image.height().to_be_bytes());
+            hasher.update(image.as_raw());
+            hasher.finalize()
+        })
+        .collect()
+}
+
+fn assert_golden(name: &str, data: &[u8], options: &[&str], golden: &[u32]) {
+    let dir = work_dir(name);
+    let mut all_options = PINNED_OPTIONS.to_vec();
+    all_options.extend_from_slice(options);
+    encode(&dir, data, &all_options);
+    let hashes = page_hashes(&dir);
+    assert_eq!(hashes, golden, "Pages for {} changed.  If that was intended, the new hashes are {:#010x?}", name, hashes);
+}
+
+// The archive doesn't need any pages left if a manifest is given in the options.
+fn verify_reproducible(dir: &Path, options: &[&str]) -> bool {
+    let in_file = dir.join("input.bin");
+    let out_prefix = dir.join("page");
+    let mut args = vec!["-e", "--verifyreproducible", "-i", in_file.to_str().unwrap(), "-o", out_prefix.to_str().unwrap()];
+    add_page_options(&mut args, options);
+    let output = run(&args);
+    output.status.success() && String::from_utf8_lossy(&output.stdout).contains("Archive is reproducible")
+}
+
+#[test]
+fn golden_monochrome() {
+    assert_golden("golden_monochrome", &test_data(1000, 11), &[], &[0x5f5eeb5a, 0xc1fc62fc, 0x705df4d1, 0x14a86cdf]);
+}
+
+#[test]
+fn golden_colors_with_parity() {
+    assert_golden("golden_colors_with_parity", &test_data(1500, 12), &["-c", "4", "-p", "1"], &[0x4be06aaa, 0xca2af22b, 0x05b3ad74, 0xaffada0c]);
+}
+
+#[test]
+fn golden_bootstrap_byte_mode() {
+    assert_golden("golden_bootstrap_byte_mode", &test_data(400, 13), &["--bootstrap", "--encoding", "byte", "--ecfunction", "constant"], &[0x6bf2d103, 0x2fc9ca58]);
+}
+
+#[test]
+fn verify_reproducible_archive() {
+    let dir = work_dir("verify_reproducible_archive");
+    let options = ["-p", "1", "--bootstrap"];
+    let pages = encode(&dir, &test_data(900, 14), &options);
+    assert!(verify_reproducible(&dir, &options));
+
+    // The parity pages and page 0 are taken from the archive when they aren't given.
+    assert!(verify_reproducible(&dir, &[]));
+
+    // Different options encode differently.
+    assert!(!verify_reproducible(&dir, &["-p", "2", "--bootstrap"]));
+
+    // So does a page which has been changed since.
+    fs::copy(&pages[0], &pages[1]).unwrap();
+    assert!(!verify_reproducible(&dir, &options));
+}
+
+#[test]
+fn verify_reproducible_from_manifest() {
+    let dir = work_dir("verify_reproducible_from_manifest");
+    let options = ["-c", "4", "-p", "1"];
+    let pages = encode(&dir, &test_data(900, 15), &options);
+    let manifest_file = dir.join("pagemanifest.txt");
+    let manifest = manifest_file.to_str().unwrap();
+    for page in pages {
+        fs::remove_file(page).unwrap();
+    }
+    let mut with_manifest = options.to_vec();
+    with_manifest.extend_from_slice(&["--manifest", manifest]);
+    assert!(verify_reproducible(&dir, &with_manifest));
+
+    // The manifest records how the archive was encoded, so the options don't have to be given again.
+    assert!(verify_reproducible(&dir, &["--manifest", manifest]));
+
+    // Without the manifest, there's nothing left to compare with.
+    assert!(!verify_reproducible(&dir, &options));
+    assert!(!verify_reproducible(&dir, &["-c", "2", "-p", "1", "--manifest", manifest]));
+}
@@ -0,0 +1,298 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0+ OR Zlib
+
+// Encodes files with the command line tool, decodes the pages it writes, and checks the same bytes come back out.
+// Pages are kept small and low resolution so the whole suite runs in a debug build.
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+use image::Rgb;
+use imageproc::drawing::draw_filled_rect_mut;
+use imageproc::rect::Rect;
+use common::*;
+
+// Decodes whatever pages are left in the directory, returning the decoded file if the decoder was happy with it.
+fn decode(dir: &Path) -> Option<Vec<u8>> {
+    decode_with_options(dir, &[])
+}
+
+fn decode_with_options(dir: &Path, options: &[&str]) -> Option<Vec<u8>> {
+    let out_file = dir.join("output.bin");
+    let pages = dir.join("page*.png");
+    let mut args = vec!["-d", "-i", pages.to_str().unwrap(), "-o", out_file.to_str().unwrap()];
+    args.extend_from_slice(options);
+    let output = run(&args);
+    if !output.status.success() {
+        return None;
+    }
+    assert!(String::from_utf8_lossy(&output.stdout).contains("File passed integrity checks!"), "Decoder didn't check the file hash");
+    Some(fs::read(out_file).unwrap())
+}
+
+fn assert_round_trip(name: &str, data: &[u8], options: &[&str]) -> usize {
+    let dir = work_dir(name);
+    let pages = encode(&dir, data, options);
+    assert!(!pages.is_empty(), "No pages were written");
+    assert_eq!(decode(&dir).expect("Decoding failed"), data);
+    pages.len()
+}
+
+// How many bytes a full page holds with these options, as reported for a dry run page laid out the same way.
+fn bytes_per_page(name: &str, options: &[&str]) -> usize {
+    let dir = work_dir(name);
+    let out_prefix = dir.join("dryrun");
+    let mut args = vec!["-e", "--dryrun", "-o", out_prefix.to_str().unwrap()];
+    add_page_options(&mut args, options);
+    let output = run(&args);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    let line = stdout.lines().find(|l| l.starts_with("Generating dry run page with ")).unwrap();
+    line.split_whitespace().nth(5).unwrap().parse().unwrap()
+}
+
+// Paints over part of a page, given in fractions of its width and height, taking out the barcodes under it.
+fn blank_area(page: &Path, left: f32, top: f32, right: f32, bottom: f32) {
+    let mut image = image::open(page).unwrap().to_rgb8();
+    let (w, h) = (image.width() as f32, image.height() as f32);
+    let (x, y) = ((w * left) as u32, (h * top) as u32);
+    let area = Rect::at(x as i32, y as i32).of_size((w * right) as u32 - x, (h * bottom) as u32 - y);
+    draw_filled_rect_mut(&mut image, area, Rgb([255, 255, 255]));
+    image.save(page).unwrap();
+}
+
+// Paints over a band across the middle of a page.
+fn blank_band(page: &Path) {
+    blank_area(page, 0.0, 0.4, 1.0, 0.6);
+}
+
+#[test]
+fn empty_file() {
+    assert_eq!(assert_round_trip("empty_file", &[], &[]), 1);
+}
+
+#[test]
+fn single_byte() {
+    assert_eq!(assert_round_trip("single_byte", &[0x5a], &[]), 1);
+}
+
+#[test]
+fn exact_page_multiples() {
+    let page_length = bytes_per_page("exact_page_multiples_dryrun", &[]);
+    for pages in 1..=3 {
+        let data = test_data(page_length * pages, pages as u32);
+        assert_eq!(assert_round_trip(&format!("exact_page_multiples_{}", pages), &data, &[]), pages);
+    }
+    // One byte more takes another page.
+    let data = test_data(page_length + 1, 4);
+    assert_eq!(assert_round_trip("exact_page_multiples_plus_one", &data, &[]), 2);
+}
+
+#[test]
+fn colors_and_dpis() {
+    let data = test_data(1500, 5);
+    for (colors, dpi) in [("2", "200"), ("4", "150"), ("6", "150"), ("8", "250"), ("12", "250")] {
+        assert_round_trip(&format!("colors_and_dpis_{}_{}", colors, dpi), &data, &["-c", colors, "-D", dpi]);
+    }
+    assert_round_trip("colors_and_dpis_grayscale", &data, &["--multiplex", "grayscale"]);
+}
+
+#[test]
+fn encoding_options() {
+    let data = test_data(900, 6);
+    assert_round_trip("encoding_options_byte", &data, &["--encoding", "byte"]);
+    assert_round_trip("encoding_options_version_1", &data, &["--formatversion", "1"]);
+    assert_round_trip("encoding_options_version_2", &data, &["--formatversion", "2"]);
+    assert_round_trip("encoding_options_interleaved", &data, &["-c", "4", "--interleaveplanes"]);
+    assert_round_trip("encoding_options_no_metadata", &data, &["--nometadata"]);
+}
+
+#[test]
+fn parity_recovers_lost_pages() {
+    let data = test_data(3000, 7);
+    let dir = work_dir("parity_recovers_lost_pages");
+    let pages = encode(&dir, &data, &["-p", "2"]);
+    assert!(pages.len() > 4);
+    fs::remove_file(&pages[1]).unwrap();
+    fs::remove_file(&pages[pages.len() - 3]).unwrap();
+    assert_eq!(decode(&dir).expect("Could not recover the lost pages"), data);
+}
+
+#[test]
+fn parity_recovers_lost_barcodes() {
+    let data = test_data(3000, 8);
+    let dir = work_dir("parity_recovers_lost_barcodes");
+    let pages = encode(&dir, &data, &["-p", "1"]);
+    blank_band(&pages[0]);
+    assert_eq!(decode(&dir).expect("Could not recover the lost barcodes"), data);
+}
+
+#[test]
+fn lost_parity_barcodes_count_as_missing() {
+    // Barcodes lost from the middle of a parity page leave a gap before ones which were read.  That gap has to count as missing parity, not as parity which happens to be zero.
+    // This needs a high enough DPI for several barcodes on each page.
+    let data = test_data(6000, 12);
+    let dir = work_dir("lost_parity_barcodes_count_as_missing");
+    let pages = encode(&dir, &data, &["-p", "2", "-D", "250"]);
+    assert!(pages.len() > 4);
+    fs::remove_file(&pages[1]).unwrap();
+    // The second column of barcodes, which holds parity from somewhere in the middle of the page.
+    blank_area(&pages[pages.len() - 2], 0.31, 0.0, 0.49, 1.0);
+    assert_eq!(decode(&dir).expect("Could not recover around the lost parity barcodes"), data);
+}
+
+#[test]
+fn too_much_lost_fails() {
+    let data = test_data(3000, 9);
+    let dir = work_dir("too_much_lost_fails");
+    let pages = encode(&dir, &data, &["-p", "1"]);
+    fs::remove_file(&pages[1]).unwrap();
+    fs::remove_file(&pages[2]).unwrap();
+    assert_eq!(decode(&dir), None);
+}
+
+#[test]
+fn manifest_checks_full_hash() {
+    let data = test_data(700, 10);
+    let dir = work_dir("manifest_checks_full_hash");
+    encode(&dir, &data, &[]);
+    let manifest_file = dir.join("pagemanifest.txt");
+    let manifest = fs::read_to_string(&manifest_file).unwrap();
+    assert!(manifest.contains("input_length 700\n"));
+    assert_eq!(decode_with_options(&dir, &["--manifest", manifest_file.to_str().unwrap()]).expect("Decoding with the manifest failed"), data);
+
+    // The barcodes only check the low 24 bits of the hash, so a manifest which disagrees on the high bits is the only way to tell.
+    let hash_line = manifest.lines().find(|l| l.starts_with("file_hash ")).unwrap();
+    let hash = u32::from_str_radix(&hash_line[10..], 16).unwrap();
+    fs::write(&manifest_file, manifest.replace(hash_line, &format!("file_hash {:08x}", hash ^ 0x80000000))).unwrap();
+    assert_eq!(decode_with_options(&dir, &["--manifest", manifest_file.to_str().unwrap()]), None);
+}
+
+#[test]
+fn sha256_digest() {
+    let data = test_data(1200, 11);
+    let dir = work_dir("sha256_digest");
+    encode(&dir, &data, &["--sha256", "-p", "1"]);
+    let out_file = dir.join("output.bin");
+    let pages = dir.join("page*.png");
+    let output = run(&["-d", "-i", pages.to_str().unwrap(), "-o", out_file.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("File matches the SHA-256 digest in the metadata barcode"));
+    assert_eq!(fs::read(out_file).unwrap(), data);
+}
+
+// Sets the last 4 bytes of the data so its CRC-32 comes out as the one given.
+// A CRC is linear, so this works out what flipping each of those 32 bits does to it, then solves for the bits to flip.
+fn forge_crc32(data: &mut [u8], target: u32) {
+    let n = data.len();
+    data[(n - 4)..].fill(0);
+    let base = crc32fast::hash(data);
+    // Indexed by the highest bit of the change to the CRC, along with the bits which were flipped to make it.
+    let mut basis: [Option<(u32, u32)>; 32] = [None; 32];
+    for bit in 0..32 {
+        let mut flipped = data.to_vec();
+        flipped[n - 4 + bit / 8] ^= 1 << (bit % 8);
+        let mut change = (crc32fast::hash(&flipped) ^ base, 1u32 << bit);
+        for high in (0..32).rev() {
+            if change.0 >> high & 1 == 0 {
+                continue;
+            }
+            match basis[high] {
+                Some(b) => change = (change.0 ^ b.0, change.1 ^ b.1),
+                None => {
+                    basis[high] = Some(change);
+                    break;
+                }
+            }
+        }
+    }
+    let (mut remaining, mut flips) = (target ^ base, 0u32);
+    for high in (0..32).rev() {
+        if remaining >> high & 1 != 0 {
+            let b = basis[high].unwrap();
+            remaining ^= b.0;
+            flips ^= b.1;
+        }
+    }
+    assert_eq!(remaining, 0);
+    data[(n - 4)..].copy_from_slice(&flips.to_le_bytes());
+    assert_eq!(crc32fast::hash(data), target);
+}
+
+#[test]
+fn sha256_digest_mismatch() {
+    // Two files the same length with the same CRC-32 have the same document hash, so only the SHA-256 digest can tell them apart.
+    let original = test_data(bytes_per_page("sha256_digest_mismatch_layout", &[]) * 3 / 2, 13);
+    let mut altered = original.clone();
+    let n = altered.len();
+    for b in &mut altered[(n - 40)..] {
+        *b ^= 0xff;
+    }
+    forge_crc32(&mut altered, crc32fast::hash(&original));
+
+    let original_dir = work_dir("sha256_digest_mismatch_original");
+    assert_eq!(encode(&original_dir, &original, &["--sha256"]).len(), 2);
+    let dir = work_dir("sha256_digest_mismatch");
+    assert_eq!(encode(&dir, &altered, &["--sha256"]).len(), 2);
+    // The first page holds the same data either way, but its metadata barcode has the original file's digest, which is the one the decoder goes by.
+    fs::copy(original_dir.join("page1.png"), dir.join("page1.png")).unwrap();
+
+    let out_file = dir.join("output.bin");
+    let pages = dir.join("page*.png");
+    let output = run(&["-d", "-i", pages.to_str().unwrap(), "-o", out_file.to_str().unwrap()]);
+    assert!(!output.status.success(), "Decoding succeeded with the wrong SHA-256 digest");
+    assert!(String::from_utf8_lossy(&output.stdout).contains("File passed integrity checks!"));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("File does not match the SHA-256 digest in the metadata barcode"));
+}
+
+// Whether any pixel in part of a page, given in fractions as for blank_area, is colored rather than black, white or gray.
+fn has_color(page: &Path, left: f32, top: f32, right: f32, bottom: f32) -> bool {
+    let image = image::open(page).unwrap().to_rgb8();
+    let (w, h) = (image.width() as f32, image.height() as f32);
+    image.enumerate_pixels()
+        .filter(|(x, y, _)| (*x as f32) >= w * left && (*x as f32) < w * right && (*y as f32) >= h * top && (*y as f32) < h * bottom)
+        .any(|(_, _, p)| p.0.iter().max().unwrap() - p.0.iter().min().unwrap() > 64)
+}
+
+#[test]
+fn sha256_digest_unread() {
+    let data = test_data(1500, 14);
+    let dir = work_dir("sha256_digest_unread");
+    let pages = encode(&dir, &data, &["--sha256", "-c", "4"]);
+    // These pages hold two barcodes side by side, either side of the middle.  Only the data barcode is in color, so the black and white one is the metadata.
+    // The band checked leaves out the color swatches above and below them.
+    for page in &pages {
+        let left_color = has_color(page, 0.0, 0.3, 0.5, 0.6);
+        assert_ne!(left_color, has_color(page, 0.5, 0.3, 1.0, 0.6), "Expected one color and one black and white barcode on {}", page.display());
+        if left_color {
+            blank_area(page, 0.5, 0.0, 1.0, 1.0);
+        }
+        else {
+            blank_area(page, 0.0, 0.0, 0.5, 1.0);
+        }
+    }
+
+    let out_file = dir.join("output.bin");
+    let pages = dir.join("page*.png");
+    let output = run(&["-d", "-i", pages.to_str().unwrap(), "-o", out_file.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Warning: the metadata barcode could not be read, so the SHA-256 digest, if one was stored, could not be checked"));
+    assert_eq!(fs::read(&out_file).unwrap(), data);
+
+    let output = run(&["-d", "--sha256", "-i", pages.to_str().unwrap(), "-o", out_file.to_str().unwrap()]);
+    assert!(!output.status.success(), "Decoding succeeded without checking the SHA-256 digest it was asked to");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("The metadata barcode could not be read, so the file could not be checked against its SHA-256 digest"));
+}
+
+#[test]
+fn survives_simulated_scan() {
+    let data = test_data(500, 12);
+    let dir = work_dir("survives_simulated_scan");
+    encode(&dir, &data, &[]);
+    // Leaving out --damage runs the default trip through a printer and scanner.
+    let pages = dir.join("page*.png");
+    let scanned = dir.join("scanned");
+    assert!(run(&["--simulate", "-i", pages.to_str().unwrap(), "-o", scanned.to_str().unwrap()]).status.success(), "Simulating a scan failed");
+    assert!(scanned.join("page1.png").exists());
+    assert_eq!(decode(&scanned).expect("Decoding the scanned pages failed"), data);
+}